@@ -0,0 +1,212 @@
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use std::fmt;
+use std::str::FromStr;
+
+/// Number of decimal places kept by an [`Amount`].
+pub const DECIMAL_PLACES: u32 = 4;
+const SCALE: i64 = 10i64.pow(DECIMAL_PLACES);
+
+/// An exact fixed-point monetary amount with four decimal places.
+///
+/// Internally the value is stored as an integer number of ten-thousandths,
+/// so sums and differences never drift the way `f64` does.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(i64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    /// The amount made of `units` ten-thousandths.
+    #[inline]
+    pub const fn from_units(units: i64) -> Amount {
+        Amount(units)
+    }
+
+    /// The amount as an integer number of ten-thousandths.
+    #[inline]
+    pub const fn units(self) -> i64 {
+        self.0
+    }
+
+    #[inline]
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    #[inline]
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    #[inline]
+    pub fn checked_neg(self) -> Option<Amount> {
+        self.0.checked_neg().map(Amount)
+    }
+
+    #[inline]
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+
+    /// `percent` percent of this amount, e.g. `1.5` for 1.5%, rounded half away from zero
+    /// to four decimal places.
+    #[inline]
+    pub fn checked_percent(self, percent: Amount) -> Option<Amount> {
+        let divisor = 100 * SCALE as i128;
+        let product = self.0 as i128 * percent.0 as i128;
+        let half = if product < 0 { -divisor / 2 } else { divisor / 2 };
+        i64::try_from((product + half) / divisor).ok().map(Amount)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseAmountError {
+    Empty,
+    InvalidDigit,
+    TooManyDecimals,
+    Overflow,
+}
+
+impl fmt::Display for ParseAmountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseAmountError::Empty => write!(f, "amount is empty"),
+            ParseAmountError::InvalidDigit => write!(f, "amount contains an invalid digit"),
+            ParseAmountError::TooManyDecimals => write!(f, "amount has more than {} decimal places", DECIMAL_PLACES),
+            ParseAmountError::Overflow => write!(f, "amount is too large"),
+        }
+    }
+}
+
+impl std::error::Error for ParseAmountError {}
+
+impl FromStr for Amount {
+    type Err = ParseAmountError;
+
+    /// Parses a decimal string such as `"1.5"`, `"-0.0001"` or `"42"` without going through a float.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (negative, digits) = match s.as_bytes().first() {
+            Some(b'-') => (true, &s[1..]),
+            Some(b'+') => (false, &s[1..]),
+            _ => (false, s),
+        };
+        let (integer, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        if integer.is_empty() && fraction.is_empty() {
+            return Err(ParseAmountError::Empty);
+        }
+
+        let mut value: i64 = 0;
+        for byte in integer.bytes() {
+            value = push_digit(value, byte)?;
+        }
+
+        let mut places = 0;
+        for byte in fraction.bytes() {
+            if places < DECIMAL_PLACES {
+                value = push_digit(value, byte)?;
+                places += 1;
+            } else if byte != b'0' {
+                // Trailing zeros are harmless, anything else would have to be rounded.
+                return Err(if byte.is_ascii_digit() { ParseAmountError::TooManyDecimals } else { ParseAmountError::InvalidDigit });
+            }
+        }
+        for _ in places..DECIMAL_PLACES {
+            value = value.checked_mul(10).ok_or(ParseAmountError::Overflow)?;
+        }
+
+        Ok(Amount(if negative { -value } else { value }))
+    }
+}
+
+#[inline]
+fn push_digit(value: i64, byte: u8) -> Result<i64, ParseAmountError> {
+    if !byte.is_ascii_digit() {
+        return Err(ParseAmountError::InvalidDigit);
+    }
+    value.checked_mul(10)
+        .and_then(|value| value.checked_add((byte - b'0') as i64))
+        .ok_or(ParseAmountError::Overflow)
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let value = self.0.unsigned_abs();
+        let scale = SCALE as u64;
+        write!(f, "{}{}.{:0width$}", sign, value / scale, value % scale, width = DECIMAL_PLACES as usize)
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct AmountVisitor;
+
+        impl Visitor<'_> for AmountVisitor {
+            type Value = Amount;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "a decimal amount with at most {} decimal places", DECIMAL_PLACES)
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Amount, E> {
+                value.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(AmountVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_display() {
+        assert_eq!("1.5".parse::<Amount>().unwrap().to_string(), "1.5000");
+        assert_eq!("42".parse::<Amount>().unwrap().to_string(), "42.0000");
+        assert_eq!(".25".parse::<Amount>().unwrap().to_string(), "0.2500");
+        assert_eq!("-0.0001".parse::<Amount>().unwrap().to_string(), "-0.0001");
+        assert_eq!("2.50000".parse::<Amount>().unwrap().to_string(), "2.5000");
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert_eq!("".parse::<Amount>(), Err(ParseAmountError::Empty));
+        assert_eq!(".".parse::<Amount>(), Err(ParseAmountError::Empty));
+        assert_eq!("1.2.3".parse::<Amount>(), Err(ParseAmountError::InvalidDigit));
+        assert_eq!("1e5".parse::<Amount>(), Err(ParseAmountError::InvalidDigit));
+        assert_eq!("0.00001".parse::<Amount>(), Err(ParseAmountError::TooManyDecimals));
+        assert_eq!("99999999999999999999".parse::<Amount>(), Err(ParseAmountError::Overflow));
+    }
+
+    #[test]
+    fn test_no_drift() {
+        let step: Amount = "0.1".parse().unwrap();
+        let mut total = Amount::ZERO;
+        for _ in 0..1_000_000 {
+            total = total.checked_add(step).unwrap();
+        }
+        assert_eq!(total, "100000".parse().unwrap());
+    }
+
+    #[test]
+    fn test_checked_overflow() {
+        let max = Amount(i64::MAX);
+        assert_eq!(max.checked_add("0.0001".parse().unwrap()), None);
+        assert_eq!(Amount(i64::MIN).checked_sub("0.0001".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn test_checked_percent() {
+        let amount = |value: &str| value.parse::<Amount>().unwrap();
+        assert_eq!(amount("200").checked_percent(amount("1.5")), Some(amount("3")));
+        assert_eq!(amount("0.0150").checked_percent(amount("10")), Some(amount("0.0015")));
+        // 0.0005 rounds up, 0.0004 rounds down
+        assert_eq!(amount("0.0050").checked_percent(amount("10")), Some(amount("0.0005")));
+        assert_eq!(amount("0.0045").checked_percent(amount("10")), Some(amount("0.0005")));
+        assert_eq!(amount("0.0044").checked_percent(amount("10")), Some(amount("0.0004")));
+        assert_eq!(amount("-0.0045").checked_percent(amount("10")), Some(amount("-0.0005")));
+        assert_eq!(Amount(i64::MAX).checked_percent(amount("200")), None);
+    }
+}
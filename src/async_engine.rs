@@ -0,0 +1,231 @@
+use std::fmt;
+use std::thread;
+use tokio::sync::{mpsc, oneshot};
+use crate::csv_handler::TransactionRaw;
+use crate::error::TransactionError;
+use crate::transaction_engine::{LoadReport, Outcome, TransactionEngine};
+
+/// The engine has stopped and no longer accepts transactions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EngineStopped;
+
+impl fmt::Display for EngineStopped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "transaction engine has stopped")
+    }
+}
+
+impl std::error::Error for EngineStopped {}
+
+/// Where the outcome of a queued transaction is sent.
+type OutcomeSender = oneshot::Sender<Result<Outcome, TransactionError>>;
+
+struct Request {
+    transaction: TransactionRaw,
+    outcome: OutcomeSender,
+}
+
+/// Front end running a [`TransactionEngine`] on its own thread, fed by any number of async producers.
+///
+/// Transactions are queued on a bounded channel and applied one at a time in the order they
+/// were queued, so a producer waits whenever the engine falls `capacity` transactions behind.
+/// The engine does not need a particular runtime, any async context can submit transactions.
+///
+/// Whenever the queue runs empty, and at the latest every `capacity` transactions, the engine is
+/// flushed with [`TransactionEngine::flush`] before the outcomes of the transactions applied since
+/// the last flush are handed out, so an outcome is only reported once its write-ahead log record,
+/// events and storage changes are durable.
+pub struct AsyncEngine {
+    handle: EngineHandle,
+    stopped: oneshot::Receiver<(TransactionEngine, LoadReport)>,
+}
+
+impl AsyncEngine {
+    /// Starts applying transactions to `engine`, with room for `capacity` queued transactions.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn start(mut engine: TransactionEngine, capacity: usize) -> Self {
+        assert!(capacity > 0, "the queue of an AsyncEngine needs room for at least one transaction");
+        let (sender, mut receiver) = mpsc::channel::<Request>(capacity);
+        let (stopped_sender, stopped) = oneshot::channel();
+        thread::spawn(move || {
+            let mut report = LoadReport::default();
+            let mut applied = Vec::with_capacity(capacity);
+            while let Some(mut request) = receiver.blocking_recv() {
+                loop {
+                    let result = engine.apply(&request.transaction);
+                    report.record(&request.transaction, &result);
+                    applied.push((request.outcome, result));
+                    match receiver.try_recv() {
+                        Ok(next) if applied.len() < capacity => request = next,
+                        Ok(next) => {
+                            flush(&mut engine, &mut applied);
+                            request = next;
+                        },
+                        Err(_) => break,
+                    }
+                }
+                flush(&mut engine, &mut applied);
+            }
+            let _ = stopped_sender.send((engine, report));
+        });
+        AsyncEngine {
+            handle: EngineHandle { sender },
+            stopped,
+        }
+    }
+
+    /// A handle producers use to submit transactions. Handles can be cloned and sent to other tasks.
+    pub fn handle(&self) -> EngineHandle {
+        self.handle.clone()
+    }
+
+    /// Waits until every handle has been dropped and all queued transactions have been applied,
+    /// then returns the engine and the counts of applied and rejected transactions.
+    pub async fn shutdown(self) -> (TransactionEngine, LoadReport) {
+        drop(self.handle);
+        self.stopped.await.expect("the engine thread hands back the engine when it stops")
+    }
+}
+
+/// Makes the `applied` transactions durable, then hands out their outcomes.
+fn flush(engine: &mut TransactionEngine, applied: &mut Vec<(OutcomeSender, Result<Outcome, TransactionError>)>) {
+    engine.flush();
+    for (outcome, result) in applied.drain(..) {
+        // The producer may have given up on the outcome
+        let _ = outcome.send(result);
+    }
+}
+
+/// Submits transactions to an [`AsyncEngine`].
+#[derive(Clone)]
+pub struct EngineHandle {
+    sender: mpsc::Sender<Request>,
+}
+
+impl EngineHandle {
+    /// Queues a transaction, waiting for room in the queue, and returns its pending outcome
+    /// without waiting for the transaction to be applied.
+    pub async fn enqueue(&self, transaction: TransactionRaw) -> Result<PendingOutcome, EngineStopped> {
+        let (outcome, receiver) = oneshot::channel();
+        self.sender.send(Request { transaction, outcome }).await.map_err(|_| EngineStopped)?;
+        Ok(PendingOutcome { receiver })
+    }
+
+    /// Queues a transaction and waits until it has been applied.
+    pub async fn submit(&self, transaction: TransactionRaw) -> Result<Result<Outcome, TransactionError>, EngineStopped> {
+        self.enqueue(transaction).await?.outcome().await
+    }
+}
+
+/// Outcome of a queued transaction, available once the engine has applied it.
+pub struct PendingOutcome {
+    receiver: oneshot::Receiver<Result<Outcome, TransactionError>>,
+}
+
+impl PendingOutcome {
+    pub async fn outcome(self) -> Result<Result<Outcome, TransactionError>, EngineStopped> {
+        self.receiver.await.map_err(|_| EngineStopped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv_handler::TransactionTypeRaw;
+    use crate::kv_store::KvStore;
+    use crate::storage::StorageError;
+    use crate::test_util::row;
+    use crate::transaction_engine::{EngineConfig, TransactionID};
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_producers() {
+        let engine = AsyncEngine::start(TransactionEngine::default(), 2);
+        let producers: Vec<_> = (1..=8u16).map(|client| {
+            let handle = engine.handle();
+            tokio::spawn(async move {
+                let base = client as TransactionID * 1000;
+                let mut pending = Vec::new();
+                for tx in base..base + 50 {
+                    pending.push(handle.enqueue(row(TransactionTypeRaw::Deposit, client, tx, Some("2.0"))).await.unwrap());
+                }
+                for pending in pending {
+                    assert_eq!(pending.outcome().await, Ok(Ok(Outcome::Deposited("2.0".parse().unwrap()))));
+                }
+                let overdraft = handle.submit(row(TransactionTypeRaw::Withdrawal, client, base + 50, Some("100.5"))).await;
+                assert_eq!(overdraft, Ok(Err(TransactionError::InsufficientFunds)));
+            })
+        }).collect();
+        for producer in producers {
+            producer.await.unwrap();
+        }
+
+        let (engine, report) = engine.shutdown().await;
+        assert_eq!(report.applied, 400);
+        assert_eq!(report.rejected.get(&TransactionError::InsufficientFunds), Some(&8));
+        assert!(engine.clients().all(|client| client.available == "100.0".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_waits_for_handles() {
+        let engine = AsyncEngine::start(TransactionEngine::default(), 1);
+        let handle = engine.handle();
+        assert_eq!(handle.submit(row(TransactionTypeRaw::Deposit, 1, 1, Some("1.0"))).await, Ok(Ok(Outcome::Deposited("1.0".parse().unwrap()))));
+        drop(handle);
+        let (engine, _) = engine.shutdown().await;
+        assert_eq!(engine.clients().count(), 1);
+
+        let engine = AsyncEngine::start(engine, 1);
+        let handle = engine.handle();
+        let (sender, receiver) = oneshot::channel();
+        tokio::spawn(async move {
+            let _ = sender.send(engine.shutdown().await);
+        });
+        // The engine keeps running while a handle is alive
+        assert!(handle.submit(row(TransactionTypeRaw::Deposit, 1, 2, Some("1.0"))).await.unwrap().is_ok());
+        drop(handle);
+        let (engine, report) = receiver.await.unwrap();
+        assert_eq!(report.applied, 1);
+        assert_eq!(engine.clients().next().unwrap().available, "2.0".parse().unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one transaction")]
+    fn test_zero_capacity() {
+        AsyncEngine::start(TransactionEngine::default(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_storage_is_committed() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("engine.kv");
+        let open = || TransactionEngine::open(EngineConfig::default(), Box::new(KvStore::open(&path).unwrap())).unwrap();
+        let engine = AsyncEngine::start(open(), 4);
+        let handle = engine.handle();
+        let mut pending = Vec::new();
+        for tx in 1..=10 {
+            pending.push(handle.enqueue(row(TransactionTypeRaw::Deposit, 1, tx, Some("1.0"))).await.unwrap());
+        }
+        for pending in pending {
+            pending.outcome().await.unwrap().unwrap();
+        }
+        // Outcomes are only handed out once committed, even while the engine keeps running. The
+        // storage cannot be opened twice, so a copy of the file shows what a crash would leave.
+        assert!(matches!(KvStore::open(&path), Err(StorageError::Locked)));
+        let copy = directory.path().join("copy.kv");
+        std::fs::copy(&path, &copy).unwrap();
+        let reopened = TransactionEngine::open(EngineConfig::default(), Box::new(KvStore::open(&copy).unwrap())).unwrap();
+        assert_eq!(reopened.clients().next().unwrap().available, "10.0".parse().unwrap());
+        drop(reopened);
+
+        handle.submit(row(TransactionTypeRaw::Withdrawal, 1, 11, Some("4.0"))).await.unwrap().unwrap();
+        drop(handle);
+        let (engine, _) = engine.shutdown().await;
+        drop(engine);
+        let reopened = open();
+        assert_eq!(reopened.clients().next().unwrap().available, "6.0".parse().unwrap());
+        assert!(reopened.transaction(11).is_some());
+    }
+}
@@ -0,0 +1,372 @@
+//! Generates a synthetic transaction CSV and the client states the engine should end with.
+//!
+//! The expected states come from a small model of the default engine rules written for this
+//! generator, not from [`TransactionEngine`](transaction_engine::transaction_engine::TransactionEngine),
+//! so running the engine on the generated file checks it against an independent implementation.
+//!
+//! The model covers deposits, withdrawals, transfers, full disputes of deposits, and operator
+//! freezes and unlocks, in the default asset. It does not cover fee schedules, other assets,
+//! partial disputes, disputes of withdrawals or transfers, account closing or the dispute
+//! window, which the engine unit tests exercise instead.
+
+use log::info;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use transaction_engine::amount::Amount;
+use transaction_engine::transaction_engine::{ClientID, TransactionID};
+
+/// Kinds of rows the generator writes, in the order of the `--mix` weights.
+const KINDS: [&str; 8] = ["deposit", "withdrawal", "dispute", "resolve", "chargeback", "transfer", "freeze", "unlock"];
+
+/// Transaction ids are handed out in sequence, one per row, below the ids reserved for
+/// references to unknown transactions.
+const MAX_ROWS: u64 = TransactionID::MAX as u64 - UNKNOWN_REFERENCE_IDS;
+
+/// Number of ids at the top of the id space used to refer to transactions that do not exist.
+const UNKNOWN_REFERENCE_IDS: u64 = 1000;
+
+/// Share of disputes that refer to a transaction id that was never used.
+const UNKNOWN_REFERENCE_RATE: f64 = 0.05;
+
+#[derive(Debug, Clone, Copy)]
+enum AmountDistribution {
+    /// Uniform between the smallest amount and twice the mean.
+    Uniform,
+    /// Exponential with the given mean, so most amounts are small and a few are large.
+    Exponential,
+}
+
+struct Options {
+    clients: ClientID,
+    rows: u64,
+    /// Relative weight of each of the [`KINDS`].
+    mix: [u32; 8],
+    invalid_rate: f64,
+    distribution: AmountDistribution,
+    mean_amount: Amount,
+    seed: u64,
+    transactions_path: String,
+    expected_path: String,
+}
+
+/// Parses `[--clients <count>] [--rows <count>]
+/// [--mix <deposit>,<withdrawal>,<dispute>,<resolve>,<chargeback>[,<transfer>,<freeze>,<unlock>]]
+/// [--invalid-rate <fraction>] [--amounts uniform|exponential] [--mean-amount <amount>] [--seed <seed>]
+/// <transactions file> <expected states file>` from the command line.
+fn parse_args() -> Options {
+    let mut options = Options {
+        clients: 100,
+        rows: 10_000,
+        mix: [55, 20, 8, 5, 2, 8, 1, 1],
+        invalid_rate: 0.01,
+        distribution: AmountDistribution::Uniform,
+        mean_amount: "100".parse().expect("a valid amount"),
+        seed: 1,
+        transactions_path: String::new(),
+        expected_path: String::new(),
+    };
+    let mut paths = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--clients" => options.clients = args.next()
+                .and_then(|count| count.parse().ok())
+                .filter(|&count| count > 0)
+                .expect("Please provide a number of clients between 1 and 65535 after --clients"),
+            "--rows" => options.rows = args.next()
+                .and_then(|count| count.parse().ok())
+                .filter(|&count| count <= MAX_ROWS)
+                .unwrap_or_else(|| panic!("Please provide a number of rows up to {} after --rows", MAX_ROWS)),
+            "--mix" => {
+                let mut weights: Vec<u32> = args.next()
+                    .map(|mix| mix.split(',').filter_map(|weight| weight.trim().parse().ok()).collect())
+                    .unwrap_or_default();
+                // Five weights leave out transfers and operator rows
+                if weights.len() == 5 {
+                    weights.resize(KINDS.len(), 0);
+                }
+                options.mix = weights.try_into().ok()
+                    .filter(|weights: &[u32; 8]| weights.iter().any(|&weight| weight > 0))
+                    .expect("Please provide weights for deposit, withdrawal, dispute, resolve and chargeback rows, optionally followed by transfer, freeze and unlock rows, after --mix");
+            },
+            "--invalid-rate" => options.invalid_rate = args.next()
+                .and_then(|rate| rate.parse().ok())
+                .filter(|rate| (0.0..=1.0).contains(rate))
+                .expect("Please provide a fraction between 0 and 1 after --invalid-rate"),
+            "--amounts" => options.distribution = match args.next().as_deref() {
+                Some("uniform") => AmountDistribution::Uniform,
+                Some("exponential") => AmountDistribution::Exponential,
+                _ => panic!("Please provide uniform or exponential after --amounts"),
+            },
+            "--mean-amount" => options.mean_amount = args.next()
+                .and_then(|amount| amount.parse().ok())
+                .filter(|&amount| amount > Amount::ZERO)
+                .expect("Please provide a positive amount after --mean-amount"),
+            "--seed" => options.seed = args.next()
+                .and_then(|seed| seed.parse().ok())
+                .expect("Please provide a number after --seed"),
+            flag if flag.starts_with("--") => panic!("Unknown option {}", flag),
+            _ => paths.push(arg),
+        }
+    }
+    let [transactions_path, expected_path] = <[String; 2]>::try_from(paths)
+        .expect("Please provide the transactions file and the expected states file");
+    options.transactions_path = transactions_path;
+    options.expected_path = expected_path;
+    options
+}
+
+/// SplitMix64, small and good enough to spread synthetic rows.
+struct Random(u64);
+
+impl Random {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..bound`.
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+
+    /// A number in `[0, 1)`.
+    fn fraction(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn pick<T: Copy>(&mut self, items: &[T]) -> Option<T> {
+        (!items.is_empty()).then(|| items[self.below(items.len() as u64) as usize])
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DepositState {
+    Normal,
+    Disputed,
+    ChargedBack,
+}
+
+/// What the engine is expected to know about a client.
+#[derive(Debug, Default)]
+struct ExpectedClient {
+    available: Amount,
+    held: Amount,
+    locked: bool,
+    /// Deposits applied for the client, which its disputes pick from.
+    deposits: Vec<TransactionID>,
+    /// Deposits currently disputed, which its resolves and chargebacks pick from.
+    disputed: Vec<TransactionID>,
+}
+
+/// The default engine rules, for deposits, withdrawals and full disputes of deposits.
+#[derive(Debug, Default)]
+struct Model {
+    clients: HashMap<ClientID, ExpectedClient>,
+    /// Owner, amount and state of every applied deposit.
+    deposits: HashMap<TransactionID, (ClientID, Amount, DepositState)>,
+    applied: u64,
+}
+
+impl Model {
+    fn deposit(&mut self, client_id: ClientID, tx: TransactionID, amount: Amount) {
+        let client = self.clients.entry(client_id).or_default();
+        if client.locked {
+            return;
+        }
+        client.available = client.available.checked_add(amount).expect("generated balances stay small");
+        client.deposits.push(tx);
+        self.deposits.insert(tx, (client_id, amount, DepositState::Normal));
+        self.applied += 1;
+    }
+
+    /// Moves funds between two clients. The destination is registered unless the source is locked.
+    fn transfer(&mut self, source: ClientID, destination: ClientID, amount: Amount) {
+        if self.clients.entry(source).or_default().locked || source == destination {
+            return;
+        }
+        if self.clients.entry(destination).or_default().locked || self.clients[&source].available < amount {
+            return;
+        }
+        let client = self.clients.get_mut(&source).expect("the source was registered");
+        client.available = client.available.checked_sub(amount).expect("generated balances stay small");
+        let client = self.clients.get_mut(&destination).expect("the destination was registered");
+        client.available = client.available.checked_add(amount).expect("generated balances stay small");
+        self.applied += 1;
+    }
+
+    /// Locks an active account, or unlocks a locked one.
+    fn set_locked(&mut self, client_id: ClientID, locked: bool) {
+        let client = self.clients.entry(client_id).or_default();
+        if client.locked != locked {
+            client.locked = locked;
+            self.applied += 1;
+        }
+    }
+
+    fn withdrawal(&mut self, client_id: ClientID, amount: Amount) {
+        let client = self.clients.entry(client_id).or_default();
+        if client.locked || client.available < amount {
+            return;
+        }
+        client.available = client.available.checked_sub(amount).expect("generated balances stay small");
+        self.applied += 1;
+    }
+
+    fn dispute(&mut self, client_id: ClientID, tx: TransactionID) {
+        let client = self.clients.entry(client_id).or_default();
+        let Some((owner, amount, state)) = self.deposits.get_mut(&tx) else {
+            return;
+        };
+        if *owner != client_id || client.locked || *state != DepositState::Normal || client.available < *amount {
+            return;
+        }
+        client.available = client.available.checked_sub(*amount).expect("generated balances stay small");
+        client.held = client.held.checked_add(*amount).expect("generated balances stay small");
+        client.disputed.push(tx);
+        *state = DepositState::Disputed;
+        self.applied += 1;
+    }
+
+    /// Settles a dispute, returning the funds to the client or charging them back.
+    fn settle(&mut self, client_id: ClientID, tx: TransactionID, chargeback: bool) {
+        let client = self.clients.entry(client_id).or_default();
+        let Some((owner, amount, state)) = self.deposits.get_mut(&tx) else {
+            return;
+        };
+        if *owner != client_id || client.locked || *state != DepositState::Disputed {
+            return;
+        }
+        client.held = client.held.checked_sub(*amount).expect("generated balances stay small");
+        if chargeback {
+            client.locked = true;
+            *state = DepositState::ChargedBack;
+        } else {
+            client.available = client.available.checked_add(*amount).expect("generated balances stay small");
+            *state = DepositState::Normal;
+        }
+        client.disputed.retain(|&disputed| disputed != tx);
+        self.applied += 1;
+    }
+
+    /// Writes the client states in the format of the engine output, sorted by client.
+    fn write_expected(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        writeln!(writer, "client, available, held, total, locked")?;
+        let mut client_ids: Vec<_> = self.clients.keys().copied().collect();
+        client_ids.sort_unstable();
+        for client_id in client_ids {
+            let client = &self.clients[&client_id];
+            let total = client.available.checked_add(client.held).expect("generated balances stay small");
+            writeln!(writer, "{}, {}, {}, {}, {}", client_id, client.available, client.held, total, client.locked)?;
+        }
+        Ok(())
+    }
+}
+
+fn random_amount(random: &mut Random, distribution: AmountDistribution, mean: Amount) -> Amount {
+    let mean = mean.units() as f64;
+    let units = match distribution {
+        AmountDistribution::Uniform => random.fraction() * 2.0 * mean,
+        AmountDistribution::Exponential => -mean * (1.0 - random.fraction()).ln(),
+    };
+    Amount::from_units((units as i64).max(1))
+}
+
+/// A row the CSV reader rejects, so it never reaches the engine.
+fn invalid_row(random: &mut Random, client_id: ClientID, tx: TransactionID) -> String {
+    match random.below(5) {
+        0 => format!("refund, {}, {}, 1.0", client_id, tx),
+        1 => format!("deposit, {}, {}, 1.2.3", client_id, tx),
+        2 => format!("deposit, {}, {}, 0.00001", client_id, tx),
+        3 => format!("withdrawal, -{}, {}, 1.0", client_id, tx),
+        _ => format!("deposit, {}", client_id),
+    }
+}
+
+fn main() {
+    env_logger::init();
+    let options = parse_args();
+    let mut random = Random(options.seed);
+    let mut model = Model::default();
+    let total_weight: u64 = options.mix.iter().map(|&weight| weight as u64).sum();
+    let file = File::create(&options.transactions_path).expect("Failed to create the transactions file");
+    let mut writer = BufWriter::new(file);
+    let mut next_tx: TransactionID = 1;
+    let mut invalid = 0;
+
+    writeln!(writer, "type, client, tx, amount, reason, to").expect("Failed to write the transactions file");
+    for _ in 0..options.rows {
+        let client_id = random.below(options.clients as u64) as ClientID + 1;
+        if random.fraction() < options.invalid_rate {
+            writeln!(writer, "{}", invalid_row(&mut random, client_id, next_tx)).expect("Failed to write the transactions file");
+            invalid += 1;
+            continue;
+        }
+
+        let mut draw = random.below(total_weight);
+        let kind = options.mix.iter()
+            .position(|&weight| {
+                let hit = draw < weight as u64;
+                draw = draw.saturating_sub(weight as u64);
+                hit
+            })
+            .expect("the draw is below the total weight");
+        let client = model.clients.entry(client_id).or_default();
+        let row = match KINDS[kind] {
+            "deposit" | "withdrawal" => {
+                let tx = next_tx;
+                next_tx += 1;
+                let amount = random_amount(&mut random, options.distribution, options.mean_amount);
+                if kind == 0 {
+                    model.deposit(client_id, tx, amount);
+                } else {
+                    model.withdrawal(client_id, amount);
+                }
+                format!("{}, {}, {}, {}", KINDS[kind], client_id, tx, amount)
+            },
+            "transfer" => {
+                let tx = next_tx;
+                next_tx += 1;
+                let amount = random_amount(&mut random, options.distribution, options.mean_amount);
+                let destination = random.below(options.clients as u64) as ClientID + 1;
+                model.transfer(client_id, destination, amount);
+                format!("transfer, {}, {}, {}, , {}", client_id, tx, amount, destination)
+            },
+            "freeze" | "unlock" => {
+                // Operator rows only use their tx as an audit reference
+                model.set_locked(client_id, KINDS[kind] == "freeze");
+                format!("{}, {}, {}, , generated review", KINDS[kind], client_id, next_tx)
+            },
+            "dispute" => {
+                let tx = match random.pick(&client.deposits) {
+                    Some(tx) if random.fraction() >= UNKNOWN_REFERENCE_RATE => tx,
+                    _ => (MAX_ROWS + 1 + random.below(UNKNOWN_REFERENCE_IDS)) as TransactionID,
+                };
+                model.dispute(client_id, tx);
+                format!("dispute, {}, {},", client_id, tx)
+            },
+            _ => {
+                // Settle an open dispute when there is one, otherwise refer to a plain deposit
+                let tx = random.pick(&client.disputed)
+                    .or_else(|| random.pick(&client.deposits))
+                    .unwrap_or(TransactionID::MAX);
+                model.settle(client_id, tx, KINDS[kind] == "chargeback");
+                format!("{}, {}, {},", KINDS[kind], client_id, tx)
+            },
+        };
+        writeln!(writer, "{}", row).expect("Failed to write the transactions file");
+    }
+    writer.flush().expect("Failed to write the transactions file");
+
+    let file = File::create(&options.expected_path).expect("Failed to create the expected states file");
+    let mut writer = BufWriter::new(file);
+    model.write_expected(&mut writer)
+        .and_then(|_| writer.flush())
+        .expect("Failed to write the expected states file");
+    info!("Generated {} rows for {} clients: {} applied, {} rejected by the engine and {} invalid.",
+        options.rows, model.clients.len(), model.applied, options.rows - model.applied - invalid, invalid);
+}
@@ -0,0 +1,130 @@
+use std::io;
+use std::path::Path;
+use crate::snapshot::{self, SnapshotError, SnapshotReader, SnapshotWriter};
+use crate::transaction_engine::{EngineConfig, TransactionEngine};
+use crate::transaction_store::TransactionStore;
+
+/// Version of the checkpoint format written by this build.
+pub const CHECKPOINT_VERSION: u32 = 1;
+
+/// First bytes of every checkpoint.
+const MAGIC: [u8; 8] = *b"TXENGCKP";
+
+/// How far a run got through its input file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputPosition {
+    /// Byte offset, line and record number of the next record to read.
+    pub next: csv::Position,
+    /// Length of the input file, to tell a resume on a different file.
+    pub input_len: u64,
+}
+
+/// Saves the state of `engine` with the position it reached in its input to `path`, replacing
+/// the previous checkpoint only once the new one is complete.
+///
+/// A checkpoint is the input position followed by a snapshot of the engine, see
+/// [`TransactionEngine::write_snapshot`].
+pub fn save_checkpoint(path: impl AsRef<Path>, engine: &TransactionEngine, position: &InputPosition) -> io::Result<()> {
+    snapshot::write_file(path.as_ref(), |writer| {
+        let mut header = SnapshotWriter::fields(&mut *writer);
+        header.bytes(&MAGIC)?;
+        header.u32(CHECKPOINT_VERSION)?;
+        header.u64(position.next.byte())?;
+        header.u64(position.next.line())?;
+        header.u64(position.next.record())?;
+        header.u64(position.input_len)?;
+        header.finish()?;
+        engine.write_snapshot(writer)
+    })
+}
+
+/// Restores the engine saved at `path` by [`save_checkpoint`], keeping its transactions in the
+/// empty store `transactions`, with the position it reached in its input.
+pub fn load_checkpoint(path: impl AsRef<Path>, config: EngineConfig, transactions: Box<dyn TransactionStore>) -> Result<(TransactionEngine, InputPosition), SnapshotError> {
+    let mut header = SnapshotReader::fields(snapshot::open_file(path.as_ref())?);
+    let magic: [u8; 8] = header.array().map_err(|_| SnapshotError::NotASnapshot)?;
+    if magic != MAGIC {
+        return Err(SnapshotError::NotASnapshot);
+    }
+    let version = header.u32()?;
+    if version != CHECKPOINT_VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+    let mut next = csv::Position::new();
+    let (byte, line, record) = (header.u64()?, header.u64()?, header.u64()?);
+    if line == 0 {
+        return Err(SnapshotError::Corrupt("line numbers start at 1"));
+    }
+    next.set_byte(byte).set_line(line).set_record(record);
+    let input_len = header.u64()?;
+    let reader = header.verify()?;
+    let engine = TransactionEngine::restore(config, transactions, reader)?;
+    Ok((engine, InputPosition { next, input_len }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+    use crate::csv_handler;
+    use crate::transaction_store::MemoryStore;
+
+    const INPUT: &[u8] = b"type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 2, 2, 5.0
+withdrawal, 1, 3, 2.5
+dispute, 2, 2,
+deposit, 1, 4, x
+chargeback, 2, 2,
+deposit, 3, 5, 1.0
+";
+
+    #[test]
+    fn test_resume_from_checkpoint() {
+        let directory = tempfile::tempdir().unwrap();
+        let input = directory.path().join("transactions.csv");
+        File::create(&input).unwrap().write_all(INPUT).unwrap();
+        let path = directory.path().join("engine.checkpoint");
+        let mut uninterrupted = TransactionEngine::new(EngineConfig::default());
+        uninterrupted.load_transactions(csv_handler::load_csv_file(File::open(&input).unwrap()));
+
+        for rows in 1..6 {
+            let mut engine = TransactionEngine::new(EngineConfig::default());
+            let mut next = None;
+            let applied = csv_handler::resume_csv_file(File::open(&input).unwrap(), None, false).unwrap()
+                .take(rows)
+                .map(|(position, transaction)| {
+                    next = Some(position);
+                    transaction
+                });
+            engine.load_transactions(applied);
+            let position = InputPosition { next: next.unwrap(), input_len: INPUT.len() as u64 };
+            save_checkpoint(&path, &engine, &position).unwrap();
+
+            let (mut resumed, loaded) = load_checkpoint(&path, EngineConfig::default(), Box::new(MemoryStore::default())).unwrap();
+            assert_eq!(loaded, position);
+            let rest = csv_handler::resume_csv_file(File::open(&input).unwrap(), Some(&loaded.next), false).unwrap();
+            resumed.load_transactions(rest.map(|(_, transaction)| transaction));
+            assert_eq!(resumed.clients().collect::<Vec<_>>(), uninterrupted.clients().collect::<Vec<_>>(), "resumed after {} rows", rows);
+            assert_eq!(resumed.position(), uninterrupted.position());
+        }
+    }
+
+    #[test]
+    fn test_invalid_checkpoints() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("engine.checkpoint");
+        let load = |path: &Path| load_checkpoint(path, EngineConfig::default(), Box::new(MemoryStore::default())).map(|_| ());
+        let engine = TransactionEngine::new(EngineConfig::default());
+        // A plain snapshot is not a checkpoint
+        engine.save_snapshot(&path).unwrap();
+        assert!(matches!(load(&path), Err(SnapshotError::NotASnapshot)));
+
+        save_checkpoint(&path, &engine, &InputPosition { next: csv::Position::new(), input_len: 0 }).unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[12] ^= 1;
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(matches!(load(&path), Err(SnapshotError::Corrupt("checksum mismatch"))));
+    }
+}
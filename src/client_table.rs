@@ -0,0 +1,178 @@
+use std::{iter, vec};
+use crate::transaction_engine::ClientID;
+
+/// A table indexed directly by client id, with one slot per possible client (65,536 at most).
+///
+/// Slots are only allocated up to the highest client id seen so far, and an occupancy bitmap
+/// tells the clients that have appeared apart from empty slots, so iteration skips the gaps.
+#[derive(Debug, Clone)]
+pub struct ClientTable<T> {
+    slots: Vec<T>,
+    /// One bit per slot, set when the slot holds a client.
+    occupied: Vec<u64>,
+    len: usize,
+}
+
+impl<T> Default for ClientTable<T> {
+    fn default() -> Self {
+        ClientTable {
+            slots: Vec::new(),
+            occupied: Vec::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<T: Default> ClientTable<T> {
+    /// Number of clients in the table.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    #[inline]
+    pub fn contains(&self, client_id: ClientID) -> bool {
+        let index = client_id as usize;
+        self.occupied.get(index / 64).is_some_and(|word| word & (1 << (index % 64)) != 0)
+    }
+
+    #[inline]
+    pub fn get(&self, client_id: ClientID) -> Option<&T> {
+        self.contains(client_id).then(|| &self.slots[client_id as usize])
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self, client_id: ClientID) -> Option<&mut T> {
+        self.contains(client_id).then(|| &mut self.slots[client_id as usize])
+    }
+
+    /// The entry of `client_id`, adding a default one if the client has not appeared yet.
+    #[inline]
+    pub fn get_or_default(&mut self, client_id: ClientID) -> &mut T {
+        if !self.contains(client_id) {
+            self.occupy(client_id);
+        }
+        &mut self.slots[client_id as usize]
+    }
+
+    /// The entries of two different clients, if both have appeared.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `first` and `second` are the same client.
+    pub fn get_pair_mut(&mut self, first: ClientID, second: ClientID) -> Option<(&mut T, &mut T)> {
+        assert_ne!(first, second, "a pair of entries needs two different clients");
+        if !self.contains(first) || !self.contains(second) {
+            return None;
+        }
+        let [first, second] = self.slots.get_disjoint_mut([first as usize, second as usize]).expect("occupied slots are allocated");
+        Some((first, second))
+    }
+
+    /// Puts `value` in the slot of `client_id`, returning the entry it replaces.
+    pub fn insert(&mut self, client_id: ClientID, value: T) -> Option<T> {
+        let previous = self.remove(client_id);
+        *self.get_or_default(client_id) = value;
+        previous
+    }
+
+    pub fn remove(&mut self, client_id: ClientID) -> Option<T> {
+        if !self.contains(client_id) {
+            return None;
+        }
+        let index = client_id as usize;
+        self.occupied[index / 64] &= !(1 << (index % 64));
+        self.len -= 1;
+        Some(std::mem::take(&mut self.slots[index]))
+    }
+
+    /// The clients that have appeared, in increasing id order.
+    pub fn iter(&self) -> impl Iterator<Item = (ClientID, &T)> + '_ {
+        self.client_ids().map(|client_id| (client_id, &self.slots[client_id as usize]))
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &T> + '_ {
+        self.iter().map(|(_, value)| value)
+    }
+
+    fn client_ids(&self) -> impl Iterator<Item = ClientID> + '_ {
+        self.occupied.iter().enumerate().flat_map(|(word_index, &word)| {
+            let mut bits = word;
+            iter::from_fn(move || {
+                if bits == 0 {
+                    return None;
+                }
+                let bit = bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+                Some((word_index * 64 + bit) as ClientID)
+            })
+        })
+    }
+
+    fn occupy(&mut self, client_id: ClientID) {
+        let index = client_id as usize;
+        if index >= self.slots.len() {
+            self.slots.resize_with(index + 1, T::default);
+            self.occupied.resize((index + 1).div_ceil(64), 0);
+        }
+        self.occupied[index / 64] |= 1 << (index % 64);
+        self.len += 1;
+    }
+}
+
+impl<T: Default> IntoIterator for ClientTable<T> {
+    type Item = (ClientID, T);
+    type IntoIter = IntoIter<T>;
+
+    /// Moves the clients out of the table, in increasing id order.
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter {
+            client_ids: self.client_ids().collect::<Vec<_>>().into_iter(),
+            slots: self.slots,
+        }
+    }
+}
+
+/// Iterator over the clients moved out of a [`ClientTable`].
+pub struct IntoIter<T> {
+    client_ids: vec::IntoIter<ClientID>,
+    slots: Vec<T>,
+}
+
+impl<T: Default> Iterator for IntoIter<T> {
+    type Item = (ClientID, T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let client_id = self.client_ids.next()?;
+        Some((client_id, std::mem::take(&mut self.slots[client_id as usize])))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_occupancy() {
+        let mut table = ClientTable::<u32>::default();
+        assert!(table.get(0).is_none());
+        *table.get_or_default(700) += 7;
+        *table.get_or_default(3) += 3;
+        table.insert(ClientID::MAX, 9);
+        // Slot 0 is allocated but no client 0 has appeared
+        table.get_or_default(0);
+        assert_eq!(table.remove(0), Some(0));
+        assert!(table.get(0).is_none() && table.get(4).is_none());
+
+        assert_eq!(table.len(), 3);
+        assert_eq!(table.iter().collect::<Vec<_>>(), [(3, &3), (700, &7), (ClientID::MAX, &9)]);
+        let (first, second) = table.get_pair_mut(3, 700).unwrap();
+        std::mem::swap(first, second);
+        assert!(table.get_pair_mut(3, 4).is_none());
+        assert_eq!(table.insert(3, 1), Some(7));
+        assert_eq!(table.into_iter().collect::<Vec<_>>(), [(3, 1), (700, 3), (ClientID::MAX, 9)]);
+    }
+}
@@ -1,49 +1,419 @@
 use log::warn;
 use serde::Deserialize;
+use std::fmt;
 use std::fs::File;
+use std::io::{self, Read};
+use crate::amount::Amount;
+use crate::currency::Currency;
 use crate::transaction_engine::TransactionEngine;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum TransactionTypeRaw {
     Deposit,
     Withdrawal,
+    Transfer,
     Dispute,
     Resolve,
     Chargeback,
+    Freeze,
+    Unlock,
+    Close,
 }
 
-#[derive(Debug, Deserialize)]
+impl fmt::Display for TransactionTypeRaw {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            TransactionTypeRaw::Deposit => "deposit",
+            TransactionTypeRaw::Withdrawal => "withdrawal",
+            TransactionTypeRaw::Transfer => "transfer",
+            TransactionTypeRaw::Dispute => "dispute",
+            TransactionTypeRaw::Resolve => "resolve",
+            TransactionTypeRaw::Chargeback => "chargeback",
+            TransactionTypeRaw::Freeze => "freeze",
+            TransactionTypeRaw::Unlock => "unlock",
+            TransactionTypeRaw::Close => "close",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
 pub struct TransactionRaw {
     #[serde(rename = "type")]
     pub transaction_type: TransactionTypeRaw,
     pub client: u16,
     pub tx: u32,
-    pub amount: Option<f64>,
+    pub amount: Option<Amount>,
+    /// Audit reason, required for administrative transactions.
+    #[serde(default)]
+    pub reason: Option<String>,
+    /// Destination client, required for transfers.
+    #[serde(default)]
+    pub to: Option<u16>,
+    /// Asset of the transaction, the default asset when left out.
+    #[serde(default)]
+    pub currency: Option<Currency>,
 }
 
 /// Loads transactions from a CSV file and applies them to the transaction engine.
 pub fn load_csv_file(file: File) -> impl Iterator<Item = TransactionRaw> {
-    let reader: csv::DeserializeRecordsIntoIter<File, TransactionRaw> = csv::ReaderBuilder::new()
+    skip_invalid(deserialize_records(file))
+}
+
+/// Loads transactions from a CSV file like [`load_csv_file`], reusing a single `csv::ByteRecord`
+/// and parsing the fields by hand instead of going through serde.
+///
+/// Rows are accepted, rejected and reported exactly as with [`load_csv_file`]: any row the fast
+/// parser does not understand, such as non-ASCII content or hexadecimal ids, is handed to serde.
+pub fn load_csv_file_fast(file: File) -> impl Iterator<Item = TransactionRaw> {
+    skip_invalid(ByteRecords::new(file))
+}
+
+/// Loads transactions from a CSV file like [`load_csv_file`], or [`load_csv_file_fast`] when `fast`
+/// is set, starting at the record at `start` instead of the first one. Each transaction comes with
+/// the position of the record after it, where reading can resume later on.
+///
+/// `start` must be a position returned along an earlier transaction of the same file. Rows and
+/// errors are reported as if the file had been read from the beginning.
+pub fn resume_csv_file(file: File, start: Option<&csv::Position>, fast: bool) -> io::Result<Box<dyn Iterator<Item = (csv::Position, TransactionRaw)>>> {
+    let mut builder = reader_builder();
+    if !fast {
+        builder.trim(csv::Trim::All);
+    }
+    let mut reader = builder.from_reader(file);
+    // The headers are kept by the reader when it seeks past them
+    let headers = reader.headers().ok().cloned();
+    if let Some(start) = start {
+        reader.seek(start.clone()).map_err(io::Error::other)?;
+    }
+    if fast {
+        let mut records = ByteRecords { reader, record: csv::ByteRecord::new(), parser: RecordParser::new(headers) };
+        Ok(Box::new(std::iter::from_fn(move || loop {
+            let result = records.next()?;
+            if let Some(transaction) = valid(result) {
+                return Some((records.reader.position().clone(), transaction));
+            }
+        })))
+    } else {
+        let mut records = reader.into_deserialize();
+        Ok(Box::new(std::iter::from_fn(move || loop {
+            let result = records.next()?;
+            if let Some(transaction) = valid(result) {
+                return Some((records.reader().position().clone(), transaction));
+            }
+        })))
+    }
+}
+
+/// Drops the records that failed to parse, logging why.
+pub(crate) fn skip_invalid<E: fmt::Display>(records: impl Iterator<Item = Result<TransactionRaw, E>>) -> impl Iterator<Item = TransactionRaw> {
+    records.filter_map(valid)
+}
+
+/// The transaction of a record, `None` after logging why if it failed to parse.
+fn valid<E: fmt::Display>(result: Result<TransactionRaw, E>) -> Option<TransactionRaw> {
+    match result {
+        Ok(transaction) => Some(transaction),
+        Err(e) => {
+            warn!("Failed to parse a transaction from the CSV file: {}. Skipping invalid record.", e);
+            None
+        }
+    }
+}
+
+pub(crate) fn reader_builder() -> csv::ReaderBuilder {
+    let mut builder = csv::ReaderBuilder::new();
+    // Optional trailing columns such as `reason` may be left out
+    builder.flexible(true);
+    builder
+}
+
+pub(crate) fn deserialize_records<R: Read>(reader: R) -> csv::DeserializeRecordsIntoIter<R, TransactionRaw> {
+    reader_builder()
         .trim(csv::Trim::All)
-        .from_reader(file)
-        .into_deserialize();
-    reader.into_iter().filter_map(|result| {
-        match result {
-            Ok(transaction) => Some(transaction),
-            Err(e) => {
-                warn!("Failed to parse a transaction from the CSV file: {}. Skipping invalid record.", e);
-                None
+        .from_reader(reader)
+        .into_deserialize()
+}
+
+/// Why a record could not be read, reported the way the csv crate reports it when deserializing.
+#[derive(Debug)]
+pub(crate) enum RecordError {
+    Csv(csv::Error),
+    Deserialize(Option<csv::Position>, csv::DeserializeError),
+    /// The record is not valid UTF-8.
+    Utf8(Option<csv::Position>, csv::Utf8Error),
+}
+
+impl RecordError {
+    /// Moves the reported position of a record read from a chunk that starts at `start` of the whole input.
+    pub(crate) fn offset_by(mut self, start: &csv::Position) -> Self {
+        if let RecordError::Deserialize(Some(pos), _) | RecordError::Utf8(Some(pos), _) = &mut self {
+            let (byte, line, record) = (start.byte() + pos.byte(), start.line() + pos.line() - 1, start.record() + pos.record());
+            pos.set_byte(byte).set_line(line).set_record(record);
+        }
+        self
+    }
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordError::Csv(error) => error.fmt(f),
+            RecordError::Deserialize(Some(pos), error) => write!(f, "CSV deserialize error: record {} (line: {}, byte: {}): {}",
+                pos.record(), pos.line(), pos.byte(), error),
+            RecordError::Deserialize(None, error) => write!(f, "CSV deserialize error: {}", error),
+            RecordError::Utf8(Some(pos), error) => write!(f, "CSV parse error: record {} (line {}, field: {}, byte: {}): {}",
+                pos.record(), pos.line(), error.field(), pos.byte(), error),
+            RecordError::Utf8(None, error) => write!(f, "CSV parse error: field {}: {}", error.field(), error),
+        }
+    }
+}
+
+/// Column of each [`TransactionRaw`] field.
+#[derive(Debug, Default)]
+struct Columns {
+    transaction_type: Option<usize>,
+    client: Option<usize>,
+    tx: Option<usize>,
+    amount: Option<usize>,
+    reason: Option<usize>,
+    to: Option<usize>,
+    currency: Option<usize>,
+    /// Number of columns up to the last one that is not a field, serde rejects shorter records.
+    required_len: usize,
+}
+
+impl Columns {
+    /// The columns named by `headers`, unless a field is named twice, which serde rejects on every row.
+    fn new(headers: &csv::StringRecord) -> Option<Columns> {
+        let mut columns = Columns::default();
+        for (index, header) in headers.iter().enumerate() {
+            let column = match header {
+                "type" => &mut columns.transaction_type,
+                "client" => &mut columns.client,
+                "tx" => &mut columns.tx,
+                "amount" => &mut columns.amount,
+                "reason" => &mut columns.reason,
+                "to" => &mut columns.to,
+                "currency" => &mut columns.currency,
+                _ => {
+                    columns.required_len = index + 1;
+                    continue;
+                },
+            };
+            if column.replace(index).is_some() {
+                return None;
             }
         }
-    })
+        Some(columns)
+    }
+
+    /// Parses a record made of ASCII characters only, or returns `None` if it needs serde.
+    fn parse(&self, record: &csv::ByteRecord) -> Option<TransactionRaw> {
+        if record.len() < self.required_len {
+            return None;
+        }
+        let field = |column: Option<usize>| column.and_then(|index| record.get(index)).map(<[u8]>::trim_ascii);
+        let text = |column: Option<usize>| field(column).map(|bytes| std::str::from_utf8(bytes).expect("the record is ASCII"));
+        let transaction_type = match field(self.transaction_type)? {
+            b"deposit" => TransactionTypeRaw::Deposit,
+            b"withdrawal" => TransactionTypeRaw::Withdrawal,
+            b"transfer" => TransactionTypeRaw::Transfer,
+            b"dispute" => TransactionTypeRaw::Dispute,
+            b"resolve" => TransactionTypeRaw::Resolve,
+            b"chargeback" => TransactionTypeRaw::Chargeback,
+            b"freeze" => TransactionTypeRaw::Freeze,
+            b"unlock" => TransactionTypeRaw::Unlock,
+            b"close" => TransactionTypeRaw::Close,
+            _ => return None,
+        };
+        // Empty or missing optional fields are `None`, like serde does for `Option`
+        let optional = |column: Option<usize>| text(column).filter(|value| !value.is_empty());
+        Some(TransactionRaw {
+            transaction_type,
+            client: text(self.client)?.parse().ok()?,
+            tx: text(self.tx)?.parse().ok()?,
+            amount: match optional(self.amount) {
+                Some(amount) => Some(amount.parse().ok()?),
+                None => None,
+            },
+            reason: optional(self.reason).map(str::to_string),
+            to: match optional(self.to) {
+                Some(to) => Some(to.parse().ok()?),
+                None => None,
+            },
+            currency: match optional(self.currency) {
+                Some(currency) => Some(currency.parse().ok()?),
+                None => None,
+            },
+        })
+    }
+}
+
+/// Parses byte records into transactions, with the same results as [`deserialize_records`].
+///
+/// Fields are trimmed as they are parsed, which spares copying the record to trim it. Records
+/// with non-ASCII bytes or a vertical tab, which ASCII and Unicode trimming disagree on, go
+/// through serde.
+#[derive(Debug)]
+pub(crate) struct RecordParser {
+    /// Trimmed headers, as serde sees them.
+    headers: Option<csv::StringRecord>,
+    columns: Option<Columns>,
+}
+
+impl RecordParser {
+    pub(crate) fn new(headers: Option<csv::StringRecord>) -> Self {
+        let headers = headers.map(|mut headers| {
+            headers.trim();
+            headers
+        });
+        RecordParser {
+            columns: headers.as_ref().and_then(Columns::new),
+            headers,
+        }
+    }
+
+    pub(crate) fn parse(&self, record: &csv::ByteRecord) -> Result<TransactionRaw, RecordError> {
+        let plain = record.as_slice().iter().all(|&byte| byte.is_ascii() && byte != 0x0b);
+        let parsed = self.columns.as_ref()
+            .filter(|_| plain)
+            .and_then(|columns| columns.parse(record));
+        parsed.map_or_else(|| self.deserialize(record), Ok)
+    }
+
+    /// Parses a record with serde, as [`deserialize_records`] would.
+    fn deserialize(&self, record: &csv::ByteRecord) -> Result<TransactionRaw, RecordError> {
+        let mut record = record.clone();
+        record.trim();
+        let position = record.position().cloned();
+        let mut record = csv::StringRecord::from_byte_record(record)
+            .map_err(|error| RecordError::Utf8(position.clone(), error.utf8_error().clone()))?;
+        record.trim();
+        record.deserialize(self.headers.as_ref()).map_err(|error| match error.kind() {
+            csv::ErrorKind::Deserialize { err, .. } => RecordError::Deserialize(position, err.clone()),
+            _ => RecordError::Csv(error),
+        })
+    }
+}
+
+/// Reads transactions into a reused `csv::ByteRecord`.
+struct ByteRecords<R> {
+    reader: csv::Reader<R>,
+    record: csv::ByteRecord,
+    parser: RecordParser,
+}
+
+impl<R: Read> ByteRecords<R> {
+    fn new(reader: R) -> Self {
+        let mut reader = reader_builder().from_reader(reader);
+        let headers = reader.headers().ok().cloned();
+        ByteRecords {
+            reader,
+            record: csv::ByteRecord::new(),
+            parser: RecordParser::new(headers),
+        }
+    }
+}
+
+impl<R: Read> Iterator for ByteRecords<R> {
+    type Item = Result<TransactionRaw, RecordError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.reader.read_byte_record(&mut self.record) {
+            Err(error) => Some(Err(RecordError::Csv(error))),
+            Ok(false) => None,
+            Ok(true) => Some(self.parser.parse(&self.record)),
+        }
+    }
 }
 
 /// Writes the current state of all clients to standard output in CSV format.
+/// A `currency` column with one row per client and asset is added once any asset other than the default one is used,
+/// and a `fees` column with the fees paid by each client when the engine charges fees.
+/// Rows are sorted by client and asset so the output does not depend on how the engine stores clients.
 pub fn write_clients_csv(engine: &TransactionEngine) {
-    println!("client, available, held, total, locked");
-    for client_info in engine.clients() {
-        let client_id = client_info.client_id;
-        println!("{}, {:.4}, {:.4}, {:.4}, {}", client_id, client_info.available, client_info.held, client_info.total, client_info.locked);
+    let multi_asset = engine.is_multi_asset();
+    let fees = engine.charges_fees();
+    let currency_header = if multi_asset { "currency, " } else { "" };
+    let fees_header = if fees { ", fees" } else { "" };
+    println!("client, {}available, held, total, locked{}", currency_header, fees_header);
+    let mut clients: Vec<_> = engine.clients().collect();
+    clients.sort_by_key(|client_info| (client_info.client_id, client_info.currency));
+    for client_info in clients {
+        let currency = if multi_asset { format!("{}, ", client_info.currency) } else { String::new() };
+        let fees = if fees { format!(", {}", client_info.fees) } else { String::new() };
+        println!("{}, {}{}, {}, {}, {}{}", client_info.client_id, currency, client_info.available, client_info.held, client_info.total, client_info.locked, fees);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_same_records(input: &[u8]) {
+        let expected: Vec<_> = deserialize_records(input).map(|result| result.map_err(|e| e.to_string())).collect();
+        let records: Vec<_> = ByteRecords::new(input).map(|result| result.map_err(|e| e.to_string())).collect();
+        assert_eq!(records, expected);
+    }
+
+    #[test]
+    fn test_fast_parser_matches_serde() {
+        let mut input = b"type , client,tx, amount, reason, to, currency, note
+deposit, 1, 1, 1.5
+withdrawal,2,2,0.25,,,eur
+  transfer , 3 , 3 , 10 , , 4 ,
+dispute, 1, 1,
+resolve, 1, 1, 0.5, , , , ignored
+chargeback, 1, 1
+freeze, 1, 4,,\"fraud, confirmed\"
+deposit, 0x10, 0xff, 2.0
+deposit, +5, 6, 1.0
+Deposit, 1, 7, 1.0
+refund, 1, 8, 1.0
+deposit, 70000, 9, 1.0
+deposit, 1, -10, 1.0
+deposit, 1, 11, 1.00001
+deposit, 1, 12, abc
+transfer, 1, 13, 1.0, , x
+deposit, 1, 14, 1.0, , , TOOLONGCODE
+deposit, 1, 15
+deposit, 1
+deposit, \xc2\xa01, 16, 1.0
+deposit, 1, 17, 1.0\x0b
+unlock, 1, 18, , caf\xc3\xa9
+".to_vec();
+        input.extend_from_slice(b"deposit, 1, 19, 1.0, \xff\xfe\n\n\ndeposit, 1, 20, 3.0, , , , , extra\n");
+        assert_same_records(&input);
+    }
+
+    #[test]
+    fn test_fast_parser_matches_serde_on_unusual_headers() {
+        assert_same_records(b"type, client, tx, amount, amount\ndeposit, 1, 1, 1.0, 2.0\n");
+        assert_same_records(b"client, tx, amount\n1, 1, 1.0\n");
+        assert_same_records(b"type, client, tx, amount\ndeposit, 1, 1\ndeposit, 1, 2, 1.0, extra\n");
+        assert_same_records(b"\xff, client\ndeposit, 1\n");
+        assert_same_records(b"");
+    }
+
+    #[test]
+    fn test_resume_from_any_record() {
+        use std::io::{Seek, Write};
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"type, client, tx, amount\r\ndeposit, 1, 1, 1.5\r\n\r\nwithdrawal, 1, 2, x\ndeposit, 2, 3, \"2.0\"\ndispute, 1, 1,\n\ndeposit, 3, 4, 1.0").unwrap();
+        let mut reopen = || {
+            file.rewind().unwrap();
+            file.try_clone().unwrap()
+        };
+        for fast in [false, true] {
+            let expected: Vec<_> = if fast { load_csv_file_fast(reopen()).collect() } else { load_csv_file(reopen()).collect() };
+            let rows: Vec<_> = resume_csv_file(reopen(), None, fast).unwrap().collect();
+            assert_eq!(rows.iter().map(|(_, transaction)| transaction.clone()).collect::<Vec<_>>(), expected);
+            for (index, (position, _)) in rows.iter().enumerate() {
+                let resumed: Vec<_> = resume_csv_file(reopen(), Some(position), fast).unwrap().collect();
+                assert_eq!(resumed, rows[index + 1..], "resuming after row {}", index);
+            }
+        }
     }
 }
@@ -0,0 +1,142 @@
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use std::fmt;
+use std::str::FromStr;
+
+/// Maximum length of a currency or asset code.
+pub const MAX_CODE_LEN: usize = 8;
+
+/// Code of a currency or asset such as `USD` or `BTC`, stored inline so it stays `Copy`.
+///
+/// Codes are upper-cased on parsing. The default, empty code is the asset of rows
+/// without a `currency` column.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Currency([u8; MAX_CODE_LEN]);
+
+impl Currency {
+    pub const DEFAULT: Currency = Currency([0; MAX_CODE_LEN]);
+
+    pub fn as_str(&self) -> &str {
+        let len = self.0.iter().position(|&byte| byte == 0).unwrap_or(MAX_CODE_LEN);
+        std::str::from_utf8(&self.0[..len]).expect("currency codes are ASCII")
+    }
+
+    /// The code padded with zero bytes, as stored.
+    pub fn to_bytes(self) -> [u8; MAX_CODE_LEN] {
+        self.0
+    }
+
+    /// Reads a code written by [`Currency::to_bytes`].
+    pub fn from_bytes(bytes: [u8; MAX_CODE_LEN]) -> Result<Currency, ParseCurrencyError> {
+        let len = bytes.iter().position(|&byte| byte == 0).unwrap_or(MAX_CODE_LEN);
+        if len == 0 {
+            return if bytes.iter().all(|&byte| byte == 0) { Ok(Currency::DEFAULT) } else { Err(ParseCurrencyError::InvalidCharacter) };
+        }
+        let code = std::str::from_utf8(&bytes[..len]).map_err(|_| ParseCurrencyError::InvalidCharacter)?;
+        let currency: Currency = code.parse()?;
+        if currency.0 != bytes {
+            return Err(ParseCurrencyError::InvalidCharacter);
+        }
+        Ok(currency)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseCurrencyError {
+    Empty,
+    TooLong,
+    InvalidCharacter,
+}
+
+impl fmt::Display for ParseCurrencyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseCurrencyError::Empty => write!(f, "currency code is empty"),
+            ParseCurrencyError::TooLong => write!(f, "currency code is longer than {} characters", MAX_CODE_LEN),
+            ParseCurrencyError::InvalidCharacter => write!(f, "currency code must be alphanumeric"),
+        }
+    }
+}
+
+impl std::error::Error for ParseCurrencyError {}
+
+impl FromStr for Currency {
+    type Err = ParseCurrencyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err(ParseCurrencyError::Empty);
+        }
+        if s.len() > MAX_CODE_LEN {
+            return Err(ParseCurrencyError::TooLong);
+        }
+        let mut code = [0; MAX_CODE_LEN];
+        for (slot, byte) in code.iter_mut().zip(s.bytes()) {
+            if !byte.is_ascii_alphanumeric() {
+                return Err(ParseCurrencyError::InvalidCharacter);
+            }
+            *slot = byte.to_ascii_uppercase();
+        }
+        Ok(Currency(code))
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Currency({:?})", self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct CurrencyVisitor;
+
+        impl Visitor<'_> for CurrencyVisitor {
+            type Value = Currency;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "an alphanumeric currency code of at most {} characters", MAX_CODE_LEN)
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Currency, E> {
+                value.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(CurrencyVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_display() {
+        assert_eq!("usd".parse::<Currency>().unwrap().to_string(), "USD");
+        assert_eq!("USDT1234".parse::<Currency>().unwrap().as_str(), "USDT1234");
+        assert_eq!("usd".parse::<Currency>(), "USD".parse::<Currency>());
+        assert_eq!(Currency::DEFAULT.as_str(), "");
+    }
+
+    #[test]
+    fn test_bytes_round_trip() {
+        let usd: Currency = "usd".parse().unwrap();
+        assert_eq!(Currency::from_bytes(usd.to_bytes()), Ok(usd));
+        assert_eq!(Currency::from_bytes(Currency::DEFAULT.to_bytes()), Ok(Currency::DEFAULT));
+        assert_eq!(Currency::from_bytes(*b"usd\0\0\0\0\0"), Err(ParseCurrencyError::InvalidCharacter));
+        assert_eq!(Currency::from_bytes(*b"US\0D\0\0\0\0"), Err(ParseCurrencyError::InvalidCharacter));
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert_eq!("".parse::<Currency>(), Err(ParseCurrencyError::Empty));
+        assert_eq!("TOOLONGXX".parse::<Currency>(), Err(ParseCurrencyError::TooLong));
+        assert_eq!("US-D".parse::<Currency>(), Err(ParseCurrencyError::InvalidCharacter));
+    }
+}
@@ -0,0 +1,397 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Read, Write};
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+use crate::transaction_engine::{ClientID, TransactionID};
+
+/// Number of ranges of expired ids a [`DisputeWindow`] keeps before it coalesces the closest ones.
+pub const MAX_EXPIRED_RANGES: usize = 1 << 16;
+
+/// A set of transaction ids stored as ranges, so runs of consecutive ids take a single entry.
+///
+/// Each range carries the tag its ids were inserted with. Ranges that overlap, or that were
+/// merged by [`IdRanges::coalesce`], with different tags lose their tag: the set still holds
+/// their ids, but no longer knows what they were tagged with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdRanges<T = ()> {
+    /// Inclusive end and tag of each range, by start.
+    ranges: BTreeMap<TransactionID, (TransactionID, Option<T>)>,
+}
+
+impl<T> Default for IdRanges<T> {
+    fn default() -> Self {
+        IdRanges { ranges: BTreeMap::new() }
+    }
+}
+
+/// The tag of a range merged from ranges tagged `a` and `b`.
+fn merge_tags<T: PartialEq>(a: Option<T>, b: Option<T>) -> Option<T> {
+    if a == b { a } else { None }
+}
+
+impl<T: Copy + Eq> IdRanges<T> {
+    pub fn contains(&self, id: TransactionID) -> bool {
+        self.ranges.range(..=id).next_back().is_some_and(|(_, &(end, _))| id <= end)
+    }
+
+    /// The tag of the range holding `id`, `None` if the set does not hold `id` or its range lost its tag.
+    pub fn tag(&self, id: TransactionID) -> Option<T> {
+        self.ranges.range(..=id).next_back().filter(|(_, (end, _))| id <= *end).and_then(|(_, (_, tag))| *tag)
+    }
+
+    pub fn insert(&mut self, id: TransactionID, tag: T) {
+        self.insert_range(id, id, Some(tag));
+    }
+
+    pub fn remove(&mut self, id: TransactionID) {
+        let Some((&start, &(end, tag))) = self.ranges.range(..=id).next_back() else {
+            return;
+        };
+        if end < id {
+            return;
+        }
+        self.ranges.remove(&start);
+        if start < id {
+            self.ranges.insert(start, (id - 1, tag));
+        }
+        if id < end {
+            self.ranges.insert(id + 1, (end, tag));
+        }
+    }
+
+    /// Number of ranges, which is what the set costs in memory.
+    pub fn range_count(&self) -> usize {
+        self.ranges.len()
+    }
+
+    /// The ranges, as inclusive start and end, with their tag.
+    pub fn ranges(&self) -> impl Iterator<Item = (TransactionID, TransactionID, Option<T>)> + '_ {
+        self.ranges.iter().map(|(&start, &(end, tag))| (start, end, tag))
+    }
+
+    /// Adds every id of the inclusive range `start..=end` with `tag`, merging it with the ranges
+    /// it overlaps, and with those it touches that have the same tag.
+    pub fn insert_range(&mut self, mut start: TransactionID, mut end: TransactionID, mut tag: Option<T>) {
+        let mergeable = |end: TransactionID, tag: Option<T>, next_start: TransactionID, next_tag: Option<T>| {
+            next_start <= end || (end.checked_add(1) == Some(next_start) && next_tag == tag)
+        };
+        if let Some((&previous_start, &(previous_end, previous_tag))) = self.ranges.range(..=start).next_back()
+            && mergeable(previous_end, previous_tag, start, tag) {
+            start = previous_start;
+            end = end.max(previous_end);
+            tag = merge_tags(tag, previous_tag);
+        }
+        while let Some((&next_start, &(next_end, next_tag))) = self.ranges.range(start..).next()
+            && mergeable(end, tag, next_start, next_tag) {
+            self.ranges.remove(&next_start);
+            end = end.max(next_end);
+            tag = merge_tags(tag, next_tag);
+        }
+        self.ranges.insert(start, (end, tag));
+    }
+
+    /// Merges the ranges separated by the smallest gaps until at most `max_ranges / 2` are left,
+    /// or one, so the set stays bounded however sparse its ids are. The ids in the merged gaps
+    /// become part of the set.
+    pub fn coalesce(&mut self, max_ranges: usize) {
+        let target = (max_ranges / 2).max(1);
+        if self.ranges.len() <= target {
+            return;
+        }
+        let ranges: Vec<_> = self.ranges().collect();
+        let mut gaps: Vec<_> = ranges.windows(2).map(|pair| pair[1].0 - pair[0].1).collect();
+        let merges = ranges.len() - target;
+        let threshold = *gaps.select_nth_unstable(merges - 1).1;
+        // Every gap below the threshold is merged, and as many as needed of those equal to it
+        let mut ties = merges - gaps.iter().filter(|&&gap| gap < threshold).count();
+        self.ranges.clear();
+        let (mut start, mut end, mut tag) = ranges[0];
+        for &(next_start, next_end, next_tag) in &ranges[1..] {
+            let gap = next_start - end;
+            let merge = gap < threshold || (gap == threshold && ties > 0);
+            if merge {
+                if gap == threshold {
+                    ties -= 1;
+                }
+                end = next_end;
+                tag = merge_tags(tag, next_tag);
+            } else {
+                self.ranges.insert(start, (end, tag));
+                (start, end, tag) = (next_start, next_end, next_tag);
+            }
+        }
+        self.ranges.insert(start, (end, tag));
+    }
+}
+
+/// Clients a transaction involves: its owner, then the source of a transfer or the owner again.
+pub type Parties = [ClientID; 2];
+
+/// Tracks which stored transactions are still within the dispute window.
+///
+/// Positions count the rows applied by the engine, the first row being 1. Expired ids are kept as
+/// ranges tagged with the parties of their transactions, so a reference to an expired transaction
+/// is only reported as such to a client that was a party to it.
+///
+/// The ranges are coalesced once there are more than [`MAX_EXPIRED_RANGES`] of them. With sparse
+/// or out of order ids, the ids between the closest expired ones, used or not, are then treated
+/// as expired too: new transactions with those ids are rejected as duplicates, and the parties of
+/// the merged ranges are only kept where they were all the same, which can make a party see a
+/// never used id between its own expired ones as expired. A rollback does not undo a coalescing.
+#[derive(Debug, Default)]
+pub(crate) struct DisputeWindow {
+    /// Position of the last applied row.
+    pub(crate) position: u64,
+    /// Transactions still within the window, in the order they were accepted, with the position of their row.
+    recent: VecDeque<(u64, TransactionID, Parties)>,
+    /// Transactions that fell out of the window.
+    expired: IdRanges<Parties>,
+    /// Changes since [`DisputeWindow::begin`], undone by [`DisputeWindow::rollback`].
+    undo: Option<WindowUndo>,
+}
+
+/// What a [`DisputeWindow`] needs to return to an earlier state.
+#[derive(Debug, Default)]
+struct WindowUndo {
+    position: u64,
+    /// Entries expired since, in order.
+    expired: Vec<(u64, TransactionID, Parties)>,
+}
+
+impl DisputeWindow {
+    /// Remembers a transaction between `parties` accepted at the current position.
+    pub(crate) fn record(&mut self, transaction_id: TransactionID, parties: Parties) {
+        self.recent.push_back((self.position, transaction_id, parties));
+    }
+
+    /// Marks and returns the next transaction that is `window` or more rows behind the current position.
+    pub(crate) fn pop_expired(&mut self, window: u64) -> Option<TransactionID> {
+        let &(accepted, transaction_id, parties) = self.recent.front()?;
+        if accepted.saturating_add(window) > self.position {
+            return None;
+        }
+        self.recent.pop_front();
+        self.expired.insert(transaction_id, parties);
+        if self.expired.range_count() > MAX_EXPIRED_RANGES {
+            self.expired.coalesce(MAX_EXPIRED_RANGES);
+        }
+        if let Some(undo) = &mut self.undo {
+            undo.expired.push((accepted, transaction_id, parties));
+        }
+        Some(transaction_id)
+    }
+
+    /// Starts remembering changes so they can be rolled back.
+    pub(crate) fn begin(&mut self) {
+        self.undo = Some(WindowUndo { position: self.position, ..Default::default() });
+    }
+
+    /// Keeps the changes made since [`DisputeWindow::begin`].
+    pub(crate) fn commit(&mut self) {
+        self.undo = None;
+    }
+
+    /// Undoes the changes made since [`DisputeWindow::begin`].
+    pub(crate) fn rollback(&mut self) {
+        let undo = self.undo.take().expect("a rollback follows a begin");
+        // Entries after the saved position were recorded since, some of them may have expired already
+        while self.recent.back().is_some_and(|&(accepted, _, _)| accepted > undo.position) {
+            self.recent.pop_back();
+        }
+        for (accepted, transaction_id, parties) in undo.expired.into_iter().rev() {
+            self.expired.remove(transaction_id);
+            if accepted <= undo.position {
+                self.recent.push_front((accepted, transaction_id, parties));
+            }
+        }
+        self.position = undo.position;
+    }
+
+    pub(crate) fn is_expired(&self, transaction_id: TransactionID) -> bool {
+        self.expired.contains(transaction_id)
+    }
+
+    /// True if `transaction_id` expired and is known to have involved `client_id`.
+    pub(crate) fn is_expired_for(&self, transaction_id: TransactionID, client_id: ClientID) -> bool {
+        self.expired.tag(transaction_id).is_some_and(|parties| parties.contains(&client_id))
+    }
+
+    /// Combines the windows of engines that processed disjoint sets of transactions of the same input.
+    pub(crate) fn merge(windows: impl Iterator<Item = DisputeWindow>) -> DisputeWindow {
+        let mut merged = DisputeWindow::default();
+        let mut recent = Vec::new();
+        for window in windows {
+            merged.position = merged.position.max(window.position);
+            recent.extend(window.recent);
+            for (start, end, parties) in window.expired.ranges() {
+                merged.expired.insert_range(start, end, parties);
+            }
+        }
+        recent.sort_unstable();
+        merged.recent = recent.into();
+        merged.expired.coalesce(MAX_EXPIRED_RANGES);
+        merged
+    }
+
+    pub(crate) fn write_snapshot(&self, snapshot: &mut SnapshotWriter<impl Write>) -> io::Result<()> {
+        snapshot.u64(self.position)?;
+        snapshot.count(self.recent.len())?;
+        for &(accepted, transaction_id, parties) in &self.recent {
+            snapshot.u64(accepted)?;
+            snapshot.u32(transaction_id)?;
+            write_parties(snapshot, parties)?;
+        }
+        snapshot.count(self.expired.range_count())?;
+        for (start, end, parties) in self.expired.ranges() {
+            snapshot.u32(start)?;
+            snapshot.u32(end)?;
+            match parties {
+                Some(parties) => {
+                    snapshot.u8(1)?;
+                    write_parties(snapshot, parties)?;
+                },
+                None => snapshot.u8(0)?,
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read_snapshot(snapshot: &mut SnapshotReader<impl Read>) -> Result<DisputeWindow, SnapshotError> {
+        let mut window = DisputeWindow { position: snapshot.u64()?, ..Default::default() };
+        for _ in 0..snapshot.count()? {
+            let entry = (snapshot.u64()?, snapshot.u32()?, read_parties(snapshot)?);
+            if entry.0 > window.position || window.recent.back().is_some_and(|last| last.0 > entry.0) {
+                return Err(SnapshotError::Corrupt("dispute window out of order"));
+            }
+            window.recent.push_back(entry);
+        }
+        for _ in 0..snapshot.count()? {
+            let (start, end) = (snapshot.u32()?, snapshot.u32()?);
+            let parties = match snapshot.u8()? {
+                0 => None,
+                1 => Some(read_parties(snapshot)?),
+                _ => return Err(SnapshotError::Corrupt("invalid expired range")),
+            };
+            if start > end {
+                return Err(SnapshotError::Corrupt("invalid expired range"));
+            }
+            window.expired.insert_range(start, end, parties);
+        }
+        Ok(window)
+    }
+}
+
+fn write_parties(snapshot: &mut SnapshotWriter<impl Write>, parties: Parties) -> io::Result<()> {
+    parties.into_iter().try_for_each(|client_id| snapshot.u16(client_id))
+}
+
+fn read_parties(snapshot: &mut SnapshotReader<impl Read>) -> Result<Parties, SnapshotError> {
+    Ok([snapshot.u16()?, snapshot.u16()?])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spans<T: Copy + Eq>(ids: &IdRanges<T>) -> Vec<(TransactionID, TransactionID)> {
+        ids.ranges().map(|(start, end, _)| (start, end)).collect()
+    }
+
+    #[test]
+    fn test_id_ranges() {
+        let mut ids = IdRanges::default();
+        for id in [1, 2, 3, 7, 5, 6, 10, TransactionID::MAX] {
+            ids.insert(id, ());
+        }
+        assert_eq!(spans(&ids), [(1, 3), (5, 7), (10, 10), (TransactionID::MAX, TransactionID::MAX)]);
+        ids.insert(4, ());
+        assert_eq!(ids.range_count(), 3);
+        ids.insert_range(9, 12, Some(()));
+        assert_eq!(spans(&ids), [(1, 7), (9, 12), (TransactionID::MAX, TransactionID::MAX)]);
+        assert!(ids.contains(4) && ids.contains(7) && ids.contains(TransactionID::MAX));
+        assert!(!ids.contains(0) && !ids.contains(8) && !ids.contains(13));
+        ids.remove(10);
+        ids.remove(1);
+        ids.remove(8);
+        assert_eq!(spans(&ids), [(2, 7), (9, 9), (11, 12), (TransactionID::MAX, TransactionID::MAX)]);
+    }
+
+    #[test]
+    fn test_tags() {
+        let mut ids = IdRanges::default();
+        for (id, client_id) in [(1, 1), (2, 1), (3, 2), (5, 2), (6, 2)] {
+            ids.insert(id, client_id);
+        }
+        // Touching ranges only merge with the same tag
+        assert_eq!(ids.ranges().collect::<Vec<_>>(), [(1, 2, Some(1)), (3, 3, Some(2)), (5, 6, Some(2))]);
+        assert_eq!((ids.tag(2), ids.tag(3), ids.tag(4)), (Some(1), Some(2), None));
+        ids.remove(2);
+        assert_eq!(ids.tag(1), Some(1));
+        // Ranges with different tags lose theirs once merged
+        ids.coalesce(4);
+        assert_eq!(ids.ranges().collect::<Vec<_>>(), [(1, 3, None), (5, 6, Some(2))]);
+        assert!(ids.contains(2) && ids.tag(2).is_none());
+        ids.insert_range(4, 4, Some(2));
+        assert_eq!(ids.ranges().collect::<Vec<_>>(), [(1, 3, None), (4, 6, Some(2))]);
+        ids.insert_range(6, 8, Some(3));
+        assert_eq!(ids.ranges().collect::<Vec<_>>(), [(1, 3, None), (4, 8, None)]);
+    }
+
+    #[test]
+    fn test_pop_expired() {
+        let mut window = DisputeWindow::default();
+        for (position, transaction_id) in [(1, 10), (2, 20), (4, 40)] {
+            window.position = position;
+            window.record(transaction_id, [1, 1]);
+        }
+        window.position = 5;
+        assert_eq!(window.pop_expired(3), Some(10));
+        assert_eq!(window.pop_expired(3), Some(20));
+        assert_eq!(window.pop_expired(3), None);
+        assert!(window.is_expired(10) && !window.is_expired(40));
+        assert!(window.is_expired_for(10, 1) && !window.is_expired_for(10, 2));
+
+        window.begin();
+        window.position = 6;
+        window.record(60, [1, 2]);
+        window.position = 9;
+        window.record(90, [1, 1]);
+        assert_eq!(window.pop_expired(3), Some(40));
+        assert_eq!(window.pop_expired(3), Some(60));
+        window.rollback();
+        assert_eq!(window.position, 5);
+        assert!(!window.is_expired(40));
+        assert_eq!(window.recent, [(4, 40, [1, 1])]);
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let mut ids = IdRanges::default();
+        for id in [1, 3, 10, 11, 20, 22, 40] {
+            ids.insert(id, ());
+        }
+        // Gaps of 2, 7, 9, 2 and 18, the two smallest are merged
+        ids.coalesce(8);
+        assert_eq!(spans(&ids), [(1, 3), (10, 11), (20, 22), (40, 40)]);
+        ids.coalesce(2);
+        assert_eq!(spans(&ids), [(1, 40)]);
+        ids.coalesce(0);
+        assert_eq!(ids.range_count(), 1);
+    }
+
+    #[test]
+    fn test_sparse_ids_stay_bounded() {
+        let mut window = DisputeWindow::default();
+        let ids = (0..3 * MAX_EXPIRED_RANGES as TransactionID).map(|index| index * 10);
+        for transaction_id in ids.clone() {
+            window.position += 1;
+            window.record(transaction_id, [1, 1]);
+            while window.pop_expired(100).is_some() {}
+        }
+        assert!(window.expired.range_count() <= MAX_EXPIRED_RANGES);
+        // Every expired id is still known, the recent ones are not expired
+        let expired = ids.len() - 100;
+        assert!(ids.clone().take(expired).all(|transaction_id| window.is_expired(transaction_id)));
+        assert!(ids.skip(expired).all(|transaction_id| !window.is_expired(transaction_id)));
+        assert!(!window.is_expired(u32::MAX));
+    }
+}
@@ -0,0 +1,88 @@
+use std::fmt;
+
+/// Reasons why the engine can refuse to apply a transaction.
+///
+/// A rejected transaction never changes any client balance or transaction state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TransactionError {
+    /// The client does not have enough available funds.
+    InsufficientFunds,
+    /// The transaction id has already been used, by this or another client.
+    DuplicateTransaction,
+    /// The referenced transaction does not exist for this client.
+    UnknownTransaction,
+    /// The referenced transaction is older than the dispute window and no longer accepts disputes.
+    ExpiredTransaction,
+    /// The referenced transaction is not in a state that allows this operation.
+    InvalidState,
+    /// A partial dispute asked for more than the undisputed value of the transaction.
+    ExceedsUndisputedAmount,
+    /// A partial resolve or chargeback asked for more than the disputed value of the transaction.
+    ExceedsDisputedAmount,
+    /// The client account is locked and only accepts administrative transactions.
+    AccountLocked,
+    /// The client account is closed and accepts no further transactions.
+    AccountClosed,
+    /// The client account is not in a status that allows this administrative action.
+    InvalidAccountState,
+    /// An administrative transaction did not carry an audit reason.
+    MissingReason,
+    /// A transfer did not carry a destination client.
+    MissingDestination,
+    /// A transfer has the same source and destination client.
+    InvalidDestination,
+    /// A deposit or withdrawal did not carry an amount.
+    MissingAmount,
+    /// A transaction carried a zero or negative amount.
+    NonPositiveAmount,
+    /// Withdrawals can only be disputed when enabled in the [`EngineConfig`](crate::transaction_engine::EngineConfig).
+    WithdrawalNotDisputable,
+    /// The transaction is addressed to the house account, which only receives fees.
+    ReservedAccount,
+    /// Applying the transaction would overflow a balance.
+    Overflow,
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            TransactionError::InsufficientFunds => "insufficient available funds",
+            TransactionError::DuplicateTransaction => "transaction id has already been used",
+            TransactionError::UnknownTransaction => "referenced transaction not found",
+            TransactionError::ExpiredTransaction => "referenced transaction is outside the dispute window",
+            TransactionError::InvalidState => "referenced transaction is in an invalid state for this operation",
+            TransactionError::ExceedsUndisputedAmount => "amount exceeds the undisputed value of the transaction",
+            TransactionError::ExceedsDisputedAmount => "amount exceeds the disputed value of the transaction",
+            TransactionError::AccountLocked => "client account is locked",
+            TransactionError::AccountClosed => "client account is closed",
+            TransactionError::InvalidAccountState => "client account is in an invalid state for this action",
+            TransactionError::MissingReason => "administrative transaction is missing a reason",
+            TransactionError::MissingDestination => "transfer is missing a destination client",
+            TransactionError::InvalidDestination => "transfer destination is the source client",
+            TransactionError::MissingAmount => "transaction is missing an amount",
+            TransactionError::NonPositiveAmount => "transaction amount must be positive",
+            TransactionError::WithdrawalNotDisputable => "withdrawals cannot be disputed",
+            TransactionError::ReservedAccount => "house account only receives fees",
+            TransactionError::Overflow => "balance overflow",
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
+/// A batch was rejected as a whole because one of its transactions was.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchError {
+    /// Position of the first rejected transaction in the batch.
+    pub index: usize,
+    pub error: TransactionError,
+}
+
+impl fmt::Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "transaction {} of the batch was rejected: {}", self.index, self.error)
+    }
+}
+
+impl std::error::Error for BatchError {}
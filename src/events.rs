@@ -0,0 +1,395 @@
+use std::fmt::{self, Write as _};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::mpsc;
+use crate::amount::Amount;
+use crate::csv_handler::TransactionTypeRaw;
+use crate::currency::Currency;
+use crate::error::TransactionError;
+use crate::transaction_engine::{ClientID, TransactionID};
+
+/// A change made by the engine while applying a row, or the rejection of the row.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// The position of the row in the input, the first row being 1.
+    pub position: u64,
+    /// The client whose account changed: the owner of the referenced transaction for disputes,
+    /// resolves and chargebacks, each side of a transfer, the payer of a fee and the house account
+    /// collecting it, or the client of a rejected row.
+    pub client_id: ClientID,
+    pub currency: Currency,
+    /// The `tx` of the row.
+    pub tx: TransactionID,
+    /// The type of the row.
+    pub transaction_type: TransactionTypeRaw,
+    pub kind: EventKind,
+}
+
+/// What happened to the account of an [`Event`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EventKind {
+    /// The amount was credited to the available funds, by a deposit, the receiving side of a transfer,
+    /// or the chargeback of a transfer, which credits the source back.
+    FundsDeposited(Amount),
+    /// The amount was debited from the available funds, by a withdrawal or the sending side of a transfer.
+    FundsWithdrawn(Amount),
+    /// A dispute placed the amount on hold.
+    FundsHeld(Amount),
+    /// A resolve released the amount from hold.
+    FundsReleased(Amount),
+    /// A chargeback reversed the amount. It is followed by the credit to the source of a charged
+    /// back transfer and by the [`EventKind::AccountLocked`] it causes.
+    ChargedBack(Amount),
+    /// The account was locked by a chargeback, or frozen by an operator with the given reason.
+    AccountLocked { reason: Option<String> },
+    /// An operator unlocked the account, with the given reason.
+    AccountUnlocked { reason: Option<String> },
+    /// An operator closed the account for good, with the given reason.
+    AccountClosed { reason: Option<String> },
+    /// A fee on the row was debited from the available funds of the payer. It is followed by the
+    /// [`EventKind::FeeCollected`] of the house account, after the events of the row itself.
+    FeeCharged(Amount),
+    /// A fee on the row was credited to the house account.
+    FeeCollected(Amount),
+    /// The row was rejected and changed no balance.
+    TransactionRejected(TransactionError),
+}
+
+impl EventKind {
+    /// The name of the event in event logs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::FundsDeposited(_) => "funds_deposited",
+            EventKind::FundsWithdrawn(_) => "funds_withdrawn",
+            EventKind::FundsHeld(_) => "funds_held",
+            EventKind::FundsReleased(_) => "funds_released",
+            EventKind::ChargedBack(_) => "charged_back",
+            EventKind::AccountLocked { .. } => "account_locked",
+            EventKind::AccountUnlocked { .. } => "account_unlocked",
+            EventKind::AccountClosed { .. } => "account_closed",
+            EventKind::FeeCharged(_) => "fee_charged",
+            EventKind::FeeCollected(_) => "fee_collected",
+            EventKind::TransactionRejected(_) => "transaction_rejected",
+        }
+    }
+
+    /// The amount that moved, if any.
+    pub fn amount(&self) -> Option<Amount> {
+        match *self {
+            EventKind::FundsDeposited(amount) | EventKind::FundsWithdrawn(amount) | EventKind::FundsHeld(amount)
+            | EventKind::FundsReleased(amount) | EventKind::ChargedBack(amount)
+            | EventKind::FeeCharged(amount) | EventKind::FeeCollected(amount) => Some(amount),
+            EventKind::AccountLocked { .. } | EventKind::AccountUnlocked { .. } | EventKind::AccountClosed { .. }
+            | EventKind::TransactionRejected(_) => None,
+        }
+    }
+
+    /// The operator reason of a freeze, unlock or close, or why a row was rejected.
+    pub fn reason(&self) -> Option<String> {
+        match self {
+            EventKind::AccountLocked { reason } | EventKind::AccountUnlocked { reason } | EventKind::AccountClosed { reason } => reason.clone(),
+            EventKind::TransactionRejected(error) => Some(error.to_string()),
+            _ => None,
+        }
+    }
+}
+
+/// Receives the events of a [`TransactionEngine`](crate::transaction_engine::TransactionEngine),
+/// in the order the changes were made.
+pub trait EventSink: fmt::Debug + Send {
+    fn publish(&mut self, event: Event);
+
+    /// Writes out buffered events, called once the engine has loaded a set of rows.
+    fn flush(&mut self) {}
+}
+
+/// Hands the events to a subscriber on another thread. Events published after the receiver
+/// is dropped are discarded.
+impl EventSink for mpsc::Sender<Event> {
+    fn publish(&mut self, event: Event) {
+        let _ = self.send(event);
+    }
+}
+
+/// Format of an [`EventLogWriter`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventFormat {
+    /// A CSV file with the columns `event, row, client, currency, tx, type, amount, reason`.
+    Csv,
+    /// One JSON object per line, with the same fields as the CSV columns, leaving out empty ones.
+    /// Amounts are strings, to keep them exact.
+    JsonLines,
+}
+
+impl EventFormat {
+    /// JSON Lines for `.jsonl` and `.ndjson` files, CSV otherwise.
+    pub fn from_path(path: &Path) -> EventFormat {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("jsonl" | "ndjson") => EventFormat::JsonLines,
+            _ => EventFormat::Csv,
+        }
+    }
+}
+
+/// Writes every event it receives to an event log.
+///
+/// An interrupted run carries on with the log it left behind through [`EventLogWriter::resume`],
+/// which cuts off the events of the rows the engine applies again, so every row is reported once.
+///
+/// Like the other logs of the engine, failing to write the event log is fatal.
+#[derive(Debug)]
+pub struct EventLogWriter<W: Write> {
+    output: EventOutput<W>,
+}
+
+#[derive(Debug)]
+enum EventOutput<W: Write> {
+    Csv(Box<csv::Writer<W>>),
+    JsonLines(W, String),
+}
+
+impl EventLogWriter<BufWriter<File>> {
+    /// Creates the event log at `path`, replacing any previous one.
+    pub fn create(path: impl AsRef<Path>, format: EventFormat) -> io::Result<Self> {
+        EventLogWriter::new(BufWriter::new(File::create(path)?), format)
+    }
+
+    /// Opens the event log at `path` to carry on after the row at `position`, where the engine was
+    /// restored from: the events of later rows, which an interrupted run may have written only in
+    /// part, are cut off, and the events published next are appended. Creates the log if needed.
+    pub fn resume(path: impl AsRef<Path>, format: EventFormat, position: u64) -> io::Result<Self> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let end = resume_offset(&mut file, format, position)?;
+        file.set_len(end)?;
+        file.seek(SeekFrom::Start(end))?;
+        let writer = BufWriter::new(file);
+        let output = match format {
+            EventFormat::Csv => EventOutput::Csv(Box::new(csv::WriterBuilder::new().has_headers(end == 0).from_writer(writer))),
+            EventFormat::JsonLines => EventOutput::JsonLines(writer, String::new()),
+        };
+        let mut log = EventLogWriter { output };
+        if end == 0 {
+            log.write_header()?;
+        }
+        Ok(log)
+    }
+}
+
+/// Offset of the first event in `file` of a row after `position`, or of an event cut short.
+fn resume_offset(file: &mut File, format: EventFormat, position: u64) -> io::Result<u64> {
+    let reader = BufReader::new(&mut *file);
+    // Start of the next event, and of the last one read
+    let (mut start, mut last) = (0, 0);
+    match format {
+        EventFormat::Csv => {
+            let mut reader = csv::ReaderBuilder::new().has_headers(false).flexible(true).from_reader(reader);
+            let mut record = csv::StringRecord::new();
+            while reader.read_record(&mut record).map_err(io::Error::other)? {
+                // An event cut short may have lost its row
+                let row = record.get(1).and_then(|row| row.parse::<u64>().ok());
+                if start > 0 && row.is_none_or(|row| row > position) {
+                    return Ok(start);
+                }
+                (start, last) = (reader.position().byte(), start);
+            }
+        },
+        EventFormat::JsonLines => {
+            let mut reader = reader;
+            let mut line = String::new();
+            while reader.read_line(&mut line)? > 0 {
+                let row = line.split_once(r#","row":"#)
+                    .and_then(|(_, rest)| rest.split(|c: char| !c.is_ascii_digit()).next())
+                    .and_then(|row| row.parse::<u64>().ok());
+                if row.is_none_or(|row| row > position) {
+                    return Ok(start);
+                }
+                (start, last) = (start + line.len() as u64, start);
+                line.clear();
+            }
+        },
+    }
+    // The last event was cut short unless it ends its line
+    let mut end = [0];
+    if start > 0 {
+        file.seek(SeekFrom::Start(start - 1))?;
+        io::Read::read_exact(file, &mut end)?;
+    }
+    Ok(if start == 0 || end[0] == b'\n' { start } else { last })
+}
+
+impl<W: Write> EventLogWriter<W> {
+    /// An event log written to `writer`, starting with the header line for CSV.
+    pub fn new(writer: W, format: EventFormat) -> io::Result<Self> {
+        let output = match format {
+            EventFormat::Csv => EventOutput::Csv(Box::new(csv::Writer::from_writer(writer))),
+            EventFormat::JsonLines => EventOutput::JsonLines(writer, String::new()),
+        };
+        let mut log = EventLogWriter { output };
+        log.write_header()?;
+        Ok(log)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        if let EventOutput::Csv(writer) = &mut self.output {
+            writer.write_record(["event", "row", "client", "currency", "tx", "type", "amount", "reason"])?;
+        }
+        Ok(())
+    }
+
+    /// Appends `event` to the log.
+    pub fn write(&mut self, event: &Event) -> io::Result<()> {
+        let amount = event.kind.amount().map(|amount| amount.to_string());
+        let reason = event.kind.reason();
+        match &mut self.output {
+            EventOutput::Csv(writer) => {
+                writer.write_record([
+                    event.kind.name(),
+                    &event.position.to_string(),
+                    &event.client_id.to_string(),
+                    event.currency.as_str(),
+                    &event.tx.to_string(),
+                    &event.transaction_type.to_string(),
+                    amount.as_deref().unwrap_or_default(),
+                    reason.as_deref().unwrap_or_default(),
+                ])?;
+            },
+            EventOutput::JsonLines(writer, line) => {
+                line.clear();
+                let _ = write!(line, r#"{{"event":"{}","row":{},"client":{},"#, event.kind.name(), event.position, event.client_id);
+                if event.currency != Currency::DEFAULT {
+                    let _ = write!(line, r#""currency":"{}","#, event.currency);
+                }
+                let _ = write!(line, r#""tx":{},"type":"{}""#, event.tx, event.transaction_type);
+                if let Some(amount) = amount {
+                    let _ = write!(line, r#","amount":"{}""#, amount);
+                }
+                if let Some(reason) = reason {
+                    line.push_str(r#","reason":"#);
+                    push_json_string(line, &reason);
+                }
+                line.push_str("}\n");
+                writer.write_all(line.as_bytes())?;
+            },
+        }
+        Ok(())
+    }
+
+    /// Flushes the log and returns the underlying writer.
+    pub fn into_inner(self) -> io::Result<W> {
+        match self.output {
+            EventOutput::Csv(writer) => writer.into_inner().map_err(|error| error.into_error()),
+            EventOutput::JsonLines(mut writer, _) => {
+                writer.flush()?;
+                Ok(writer)
+            },
+        }
+    }
+}
+
+impl<W: Write + fmt::Debug + Send> EventSink for EventLogWriter<W> {
+    fn publish(&mut self, event: Event) {
+        self.write(&event).expect("Failed to write the event log");
+    }
+
+    fn flush(&mut self) {
+        match &mut self.output {
+            EventOutput::Csv(writer) => writer.flush(),
+            EventOutput::JsonLines(writer, _) => writer.flush(),
+        }.expect("Failed to write the event log");
+    }
+}
+
+/// Appends `value` to `line` as a quoted JSON string.
+fn push_json_string(line: &mut String, value: &str) {
+    line.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            '\r' => line.push_str("\\r"),
+            '\t' => line.push_str("\\t"),
+            c if c < ' ' => { let _ = write!(line, "\\u{:04x}", c as u32); },
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn events() -> Vec<Event> {
+        let event = |client_id, tx, transaction_type, kind| Event { position: tx as u64, client_id, currency: Currency::DEFAULT, tx, transaction_type, kind };
+        vec![
+            event(1, 1, TransactionTypeRaw::Deposit, EventKind::FundsDeposited("1.5".parse().unwrap())),
+            Event { currency: "btc".parse().unwrap(), ..event(2, 2, TransactionTypeRaw::Withdrawal, EventKind::FundsWithdrawn("0.25".parse().unwrap())) },
+            event(1, 3, TransactionTypeRaw::Freeze, EventKind::AccountLocked { reason: Some("said \"no\", twice\n".to_string()) }),
+            event(3, 4, TransactionTypeRaw::Dispute, EventKind::TransactionRejected(TransactionError::UnknownTransaction)),
+        ]
+    }
+
+    fn write_log(format: EventFormat) -> String {
+        write_log_until(format, u64::MAX)
+    }
+
+    /// The log of the events of the rows up to `position`.
+    fn write_log_until(format: EventFormat, position: u64) -> String {
+        let mut writer = EventLogWriter::new(Vec::new(), format).unwrap();
+        for event in events().into_iter().filter(|event| event.position <= position) {
+            writer.publish(event);
+        }
+        String::from_utf8(writer.into_inner().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_csv_log() {
+        assert_eq!(write_log(EventFormat::Csv), "event,row,client,currency,tx,type,amount,reason
+funds_deposited,1,1,,1,deposit,1.5000,
+funds_withdrawn,2,2,BTC,2,withdrawal,0.2500,
+account_locked,3,1,,3,freeze,,\"said \"\"no\"\", twice
+\"
+transaction_rejected,4,3,,4,dispute,,referenced transaction not found
+");
+    }
+
+    #[test]
+    fn test_json_lines_log() {
+        assert_eq!(write_log(EventFormat::JsonLines), r#"{"event":"funds_deposited","row":1,"client":1,"tx":1,"type":"deposit","amount":"1.5000"}
+{"event":"funds_withdrawn","row":2,"client":2,"currency":"BTC","tx":2,"type":"withdrawal","amount":"0.2500"}
+{"event":"account_locked","row":3,"client":1,"tx":3,"type":"freeze","reason":"said \"no\", twice\n"}
+{"event":"transaction_rejected","row":4,"client":3,"tx":4,"type":"dispute","reason":"referenced transaction not found"}
+"#);
+    }
+
+    #[test]
+    fn test_resume() {
+        let directory = tempfile::tempdir().unwrap();
+        for (format, name) in [(EventFormat::Csv, "events.csv"), (EventFormat::JsonLines, "events.jsonl")] {
+            let path = directory.path().join(name);
+            let complete = write_log(format);
+            for position in 0..=4 {
+                for cut in 0..complete.len() {
+                    // A run interrupted after the row at `position`, that wrote part of the next rows
+                    let expected = write_log_until(format, position);
+                    if cut < expected.len() {
+                        continue;
+                    }
+                    std::fs::write(&path, &complete[..cut]).unwrap();
+                    let mut writer = EventLogWriter::resume(&path, format, position).unwrap();
+                    events().into_iter().filter(|event| event.position > position).for_each(|event| writer.publish(event));
+                    writer.into_inner().unwrap();
+                    assert_eq!(std::fs::read_to_string(&path).unwrap(), complete, "{:?} resumed at row {} from {} bytes", format, position, cut);
+                }
+            }
+        }
+        // A missing log is created
+        let path = directory.path().join("new.csv");
+        let mut writer = EventLogWriter::resume(&path, EventFormat::Csv, 0).unwrap();
+        events().into_iter().for_each(|event| writer.publish(event));
+        writer.into_inner().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), write_log(EventFormat::Csv));
+    }
+}
@@ -0,0 +1,161 @@
+use serde::Deserialize;
+use std::fmt;
+use std::io::Read;
+use crate::amount::Amount;
+use crate::currency::Currency;
+use crate::transaction_engine::{ClientID, TransactionID};
+
+/// Kind of transaction a fee is charged on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeeKind {
+    Deposit,
+    Withdrawal,
+    Transfer,
+    /// Penalty charged to the owner of a charged back transaction.
+    Chargeback,
+}
+
+/// Fee charged on one kind of transaction: a flat amount plus a percentage of the transaction value.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FeeRule {
+    pub flat: Amount,
+    /// Percentage of the transaction value, e.g. `1.5` for 1.5%.
+    pub percent: Amount,
+}
+
+impl FeeRule {
+    /// The fee on a transaction of `value`, the percentage being rounded half away from zero.
+    #[inline]
+    pub fn fee(&self, value: Amount) -> Option<Amount> {
+        value.checked_percent(self.percent)?.checked_add(self.flat)
+    }
+}
+
+/// Fees the engine charges per kind of transaction, all credited to the house account.
+///
+/// The house account is reserved: it only receives fees and rows addressed to it are rejected.
+#[derive(Debug, Clone, Default)]
+pub struct FeeSchedule {
+    pub house_account: ClientID,
+    pub deposit: FeeRule,
+    pub withdrawal: FeeRule,
+    /// Paid by the source client of the transfer.
+    pub transfer: FeeRule,
+    /// Charged on the charged back amount. The chargeback has already happened, so unlike the
+    /// other fees it never rejects the transaction: when the available funds cannot cover it,
+    /// only what they can cover is collected, unless negative balances are allowed.
+    pub chargeback: FeeRule,
+}
+
+#[derive(Debug, Deserialize)]
+struct FeeRuleRaw {
+    #[serde(rename = "type")]
+    kind: FeeKind,
+    flat: Option<Amount>,
+    percent: Option<Amount>,
+}
+
+#[derive(Debug)]
+pub enum FeeScheduleError {
+    Csv(csv::Error),
+    NegativeFee(FeeKind),
+}
+
+impl fmt::Display for FeeScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FeeScheduleError::Csv(error) => write!(f, "invalid fee schedule: {}", error),
+            FeeScheduleError::NegativeFee(kind) => write!(f, "{:?} fee must not be negative", kind),
+        }
+    }
+}
+
+impl std::error::Error for FeeScheduleError {}
+
+impl FeeSchedule {
+    pub fn new(house_account: ClientID) -> Self {
+        FeeSchedule {
+            house_account,
+            ..Default::default()
+        }
+    }
+
+    pub fn rule(&self, kind: FeeKind) -> &FeeRule {
+        match kind {
+            FeeKind::Deposit => &self.deposit,
+            FeeKind::Withdrawal => &self.withdrawal,
+            FeeKind::Transfer => &self.transfer,
+            FeeKind::Chargeback => &self.chargeback,
+        }
+    }
+
+    pub fn rule_mut(&mut self, kind: FeeKind) -> &mut FeeRule {
+        match kind {
+            FeeKind::Deposit => &mut self.deposit,
+            FeeKind::Withdrawal => &mut self.withdrawal,
+            FeeKind::Transfer => &mut self.transfer,
+            FeeKind::Chargeback => &mut self.chargeback,
+        }
+    }
+
+    /// Reads the rules from CSV rows of `type, flat, percent`, where `type` is `deposit`,
+    /// `withdrawal`, `transfer` or `chargeback`. Kinds without a row charge no fee.
+    pub fn load_csv(house_account: ClientID, reader: impl Read) -> Result<Self, FeeScheduleError> {
+        let mut schedule = FeeSchedule::new(house_account);
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        for result in reader.deserialize() {
+            let raw: FeeRuleRaw = result.map_err(FeeScheduleError::Csv)?;
+            let rule = FeeRule {
+                flat: raw.flat.unwrap_or_default(),
+                percent: raw.percent.unwrap_or_default(),
+            };
+            if rule.flat.is_negative() || rule.percent.is_negative() {
+                return Err(FeeScheduleError::NegativeFee(raw.kind));
+            }
+            *schedule.rule_mut(raw.kind) = rule;
+        }
+        Ok(schedule)
+    }
+}
+
+/// A fee collected by the engine, recorded as a transaction of its own.
+///
+/// Transaction ids are assigned by the clients, so a fee does not claim one of its own: it is
+/// identified by the transaction it was charged on and its kind, a transaction being charged at
+/// most one fee and one chargeback penalty. Fee records are kept apart from the stored
+/// transactions and cannot be disputed, a disputed transaction keeps the fee it was charged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeeRecord {
+    /// The transaction the fee was charged on.
+    pub tx: TransactionID,
+    /// The client who paid the fee.
+    pub client_id: ClientID,
+    pub currency: Currency,
+    pub kind: FeeKind,
+    pub amount: Amount,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn amount(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn test_load_csv() {
+        let input = "type, flat, percent\nwithdrawal, 0.5, 1\nchargeback, 15,\n";
+        let schedule = FeeSchedule::load_csv(9, input.as_bytes()).unwrap();
+        assert_eq!(schedule.house_account, 9);
+        assert_eq!(schedule.deposit, FeeRule::default());
+        assert_eq!(schedule.withdrawal.fee(amount("200")), Some(amount("2.5")));
+        assert_eq!(schedule.chargeback.fee(amount("200")), Some(amount("15")));
+
+        let negative = "type, flat, percent\ndeposit, -1, 0\n";
+        assert!(matches!(FeeSchedule::load_csv(9, negative.as_bytes()), Err(FeeScheduleError::NegativeFee(FeeKind::Deposit))));
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use crate::transaction_store::{hash_map_bytes, PageCache};
+
+/// Number of pages of sorted runs a [`SortedIndex`] keeps in memory unless told otherwise.
+pub(crate) const DEFAULT_INDEX_CACHE_PAGES: usize = 1024;
+
+/// Changes kept in memory before they are sorted and written out as a run.
+const MAX_PENDING: usize = 1 << 16;
+
+/// Key, offset and length of an entry of a run.
+const ENTRY_LEN: usize = 8 + 8 + 4;
+const ENTRIES_PER_PAGE: usize = 204;
+const PAGE_LEN: usize = ENTRIES_PER_PAGE * ENTRY_LEN;
+
+/// Where a value is: its offset and length.
+pub(crate) type Location = (u64, u32);
+
+/// A run entry, `None` for a key removed since the older runs were written.
+type Entry = (u64, Option<Location>);
+
+/// Index of the keys of a [`KvStore`](crate::kv_store::KvStore): where the latest value of each key is.
+///
+/// The latest changes are kept in memory. Once there are [`MAX_PENDING`] of them, they are sorted
+/// and written to a scratch file as a run, and the two newest runs are merged for as long as the
+/// older one is at most twice as long, so there are a logarithmic number of runs. A lookup goes
+/// through the runs from the newest, each with the first key of its pages in memory and its pages
+/// read through a bounded cache. Memory use is the pending changes, the cache, and a few hundredths
+/// of a byte per key.
+///
+/// Scratch files are deleted as soon as they are created, so they go away with the index, even
+/// in a crash. The index is rebuilt from the storage file when it is opened.
+#[derive(Debug)]
+pub(crate) struct SortedIndex {
+    /// Where scratch files are created.
+    scratch: PathBuf,
+    pending: HashMap<u64, Option<Location>>,
+    /// Number of pending changes written out as a run at once.
+    max_pending: usize,
+    /// Oldest first.
+    runs: Vec<Run>,
+    cache: PageCache,
+    /// Id of the next run, naming its pages in the cache.
+    next_run: u64,
+}
+
+/// Entries sorted by key in a scratch file.
+#[derive(Debug)]
+struct Run {
+    id: u64,
+    file: File,
+    entries: u64,
+    /// First key of each page.
+    fences: Vec<u64>,
+    last: u64,
+}
+
+impl SortedIndex {
+    /// An empty index with its scratch files at `scratch`, caching up to `cache_pages` pages.
+    pub(crate) fn new(scratch: impl AsRef<Path>, cache_pages: usize) -> Self {
+        SortedIndex {
+            scratch: scratch.as_ref().to_path_buf(),
+            pending: HashMap::new(),
+            max_pending: MAX_PENDING,
+            runs: Vec::new(),
+            cache: PageCache::new(cache_pages),
+            next_run: 0,
+        }
+    }
+
+    /// Where the latest value of `key` is, `None` if it has none.
+    pub(crate) fn get(&mut self, key: u64) -> io::Result<Option<Location>> {
+        if let Some(&location) = self.pending.get(&key) {
+            return Ok(location);
+        }
+        for run in self.runs.iter().rev() {
+            if let Some(location) = run.find(key, &mut self.cache)? {
+                return Ok(location);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Points `key` to `location`, or removes it.
+    pub(crate) fn set(&mut self, key: u64, location: Option<Location>) -> io::Result<()> {
+        self.pending.insert(key, location);
+        if self.pending.len() >= self.max_pending {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the pending changes out as a run, merging the runs that became too many.
+    fn flush(&mut self) -> io::Result<()> {
+        let mut entries: Vec<Entry> = self.pending.drain().collect();
+        entries.sort_unstable_by_key(|&(key, _)| key);
+        let oldest = self.runs.is_empty();
+        let run = self.write_run(entries.into_iter().map(Ok), oldest)?;
+        self.runs.push(run);
+        while let [.., older, newer] = &self.runs[..]
+            && older.entries <= 2 * newer.entries {
+            self.merge_newest()?;
+        }
+        Ok(())
+    }
+
+    /// Merges the two newest runs into one.
+    fn merge_newest(&mut self) -> io::Result<()> {
+        let newer = self.runs.pop().expect("two runs to merge");
+        let older = self.runs.pop().expect("two runs to merge");
+        let oldest = self.runs.is_empty();
+        let merged = merge(older.read()?, newer.read()?);
+        let run = self.write_run(merged, oldest)?;
+        self.runs.push(run);
+        Ok(())
+    }
+
+    /// Every key with its location, sorted by key. The runs and the pending changes are merged as
+    /// they are read, without writing anything.
+    pub(crate) fn entries(&self) -> io::Result<impl Iterator<Item = io::Result<(u64, Location)>> + use<>> {
+        let mut merged: Box<dyn Iterator<Item = io::Result<Entry>>> = Box::new(std::iter::empty());
+        for run in &self.runs {
+            merged = Box::new(merge(merged, run.read()?));
+        }
+        let mut pending: Vec<Entry> = self.pending.iter().map(|(&key, &location)| (key, location)).collect();
+        pending.sort_unstable_by_key(|&(key, _)| key);
+        let merged = merge(merged, pending.into_iter().map(Ok));
+        Ok(merged.filter_map(|entry| match entry {
+            Ok((key, location)) => location.map(|location| Ok((key, location))),
+            Err(error) => Some(Err(error)),
+        }))
+    }
+
+    /// Moves every value, in the order of the keys, to the location `relocate` returns for it,
+    /// leaving a single run without removed keys.
+    pub(crate) fn relocate(&mut self, mut relocate: impl FnMut(u64, Location) -> io::Result<Location>) -> io::Result<()> {
+        let entries = self.entries()?;
+        let moved = entries.map(|entry| entry.and_then(|(key, location)| Ok((key, Some(relocate(key, location)?)))));
+        let run = self.write_run(moved, true)?;
+        self.runs = vec![run];
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Writes `entries`, sorted by key, to a new scratch file, leaving out removed keys if the run is the `oldest`.
+    fn write_run(&mut self, entries: impl Iterator<Item = io::Result<Entry>>, oldest: bool) -> io::Result<Run> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&self.scratch)?;
+        // The file lives as long as it is open
+        fs::remove_file(&self.scratch)?;
+        let mut writer = BufWriter::new(file);
+        let mut run = Run { id: self.next_run, file: writer.get_ref().try_clone()?, entries: 0, fences: Vec::new(), last: 0 };
+        self.next_run += 1;
+        for entry in entries {
+            let (key, location) = entry?;
+            if location.is_none() && oldest {
+                continue;
+            }
+            if run.entries.is_multiple_of(ENTRIES_PER_PAGE as u64) {
+                run.fences.push(key);
+            }
+            let (offset, len) = location.unwrap_or_default();
+            writer.write_all(&key.to_le_bytes())?;
+            writer.write_all(&offset.to_le_bytes())?;
+            writer.write_all(&len.to_le_bytes())?;
+            run.entries += 1;
+            run.last = key;
+        }
+        writer.flush()?;
+        Ok(run)
+    }
+
+    /// Approximate number of bytes of memory the index uses.
+    pub(crate) fn heap_bytes(&self) -> usize {
+        let fences: usize = self.runs.iter().map(|run| run.fences.capacity() * 8).sum();
+        hash_map_bytes(&self.pending) + fences + self.cache.len() * PAGE_LEN
+    }
+}
+
+impl Run {
+    /// The entry of `key` in this run, `None` if the run does not hold it.
+    fn find(&self, key: u64, cache: &mut PageCache) -> io::Result<Option<Option<Location>>> {
+        if self.entries == 0 || key < self.fences[0] || key > self.last {
+            return Ok(None);
+        }
+        let page = self.fences.partition_point(|&first| first <= key) - 1;
+        let content = cache.try_get(self.id << 32 | page as u64, || self.read_page(page))?;
+        let entries: Vec<Entry> = content.chunks_exact(ENTRY_LEN).map(decode).collect();
+        Ok(entries.binary_search_by_key(&key, |&(key, _)| key).ok().map(|index| entries[index].1))
+    }
+
+    fn read_page(&self, page: usize) -> io::Result<Box<[u8]>> {
+        let start = (page * PAGE_LEN) as u64;
+        let len = (self.entries * ENTRY_LEN as u64 - start).min(PAGE_LEN as u64);
+        let mut content = vec![0; len as usize].into_boxed_slice();
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut content)?;
+        Ok(content)
+    }
+
+    /// Reads the entries from the start.
+    fn read(&self) -> io::Result<impl Iterator<Item = io::Result<Entry>> + use<>> {
+        let mut file = self.file.try_clone()?;
+        file.rewind()?;
+        let mut reader = BufReader::new(file);
+        Ok((0..self.entries).map(move |_| {
+            let mut entry = [0; ENTRY_LEN];
+            reader.read_exact(&mut entry)?;
+            Ok(decode(&entry))
+        }))
+    }
+}
+
+fn decode(entry: &[u8]) -> Entry {
+    let key = u64::from_le_bytes(entry[..8].try_into().expect("eight bytes"));
+    let offset = u64::from_le_bytes(entry[8..16].try_into().expect("eight bytes"));
+    let len = u32::from_le_bytes(entry[16..20].try_into().expect("four bytes"));
+    // Values never start at offset 0, where the file header is
+    (key, (offset != 0).then_some((offset, len)))
+}
+
+/// Merges two sorted runs, the entries of `newer` replacing those of `older` with the same key.
+fn merge(older: impl Iterator<Item = io::Result<Entry>>, newer: impl Iterator<Item = io::Result<Entry>>) -> impl Iterator<Item = io::Result<Entry>> {
+    let (mut older, mut newer) = (older.peekable(), newer.peekable());
+    std::iter::from_fn(move || {
+        match (older.peek(), newer.peek()) {
+            (Some(Ok((old, _))), Some(Ok((new, _)))) if old < new => older.next(),
+            (Some(Ok((old, _))), Some(Ok((new, _)))) => {
+                if old == new {
+                    older.next();
+                }
+                newer.next()
+            },
+            (Some(Err(_)), _) | (Some(_), None) => older.next(),
+            (_, Some(_)) => newer.next(),
+            (None, None) => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_hash_map() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut index = SortedIndex::new(directory.path().join("engine.kv.index"), 4);
+        index.max_pending = 4096;
+        let mut expected = HashMap::new();
+        // Keys spread over the key space, in no order, replaced and removed across many runs
+        let key = |step: u64| step.wrapping_mul(0x9e37_79b9_7f4a_7c15) % 400_000;
+        for step in 0..300_000u64 {
+            let location = (step % 7 != 0).then_some((step + 12, step as u32 % 100));
+            index.set(key(step), location).unwrap();
+            match location {
+                Some(location) => expected.insert(key(step), location),
+                None => expected.remove(&key(step)),
+            };
+            if step % 1000 == 0 {
+                let probe = key(step / 2);
+                assert_eq!(index.get(probe).unwrap(), expected.get(&probe).copied(), "key {} at step {}", probe, step);
+            }
+        }
+        assert!(index.runs.len() < 12);
+        assert!(index.heap_bytes() < hash_map_bytes(&expected) / 4);
+        for probe in 0..1000 {
+            assert_eq!(index.get(key(probe)).unwrap(), expected.get(&key(probe)).copied());
+        }
+
+        let mut sorted: Vec<_> = expected.into_iter().collect();
+        sorted.sort();
+        let (runs, next_run) = (index.runs.len(), index.next_run);
+        assert_eq!(index.entries().unwrap().collect::<io::Result<Vec<_>>>().unwrap(), sorted);
+        // Listing the entries writes no run
+        assert_eq!((index.runs.len(), index.next_run), (runs, next_run));
+        index.relocate(|key, (_, len)| Ok((key + 1, len))).unwrap();
+        let (key, (_, len)) = sorted[10];
+        assert_eq!(index.get(key).unwrap(), Some((key + 1, len)));
+        // Scratch files are gone as soon as they are created
+        assert_eq!(std::fs::read_dir(directory.path()).unwrap().count(), 0);
+    }
+}
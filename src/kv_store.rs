@@ -0,0 +1,444 @@
+use std::cell::RefCell;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use crate::kv_index::{Location, SortedIndex, DEFAULT_INDEX_CACHE_PAGES};
+use crate::snapshot::{fnv1a, FNV_OFFSET};
+use crate::storage::{StorageBackend, StorageError, Table};
+
+/// Version of the storage file format written by this build.
+pub const KV_VERSION: u32 = 1;
+
+/// First bytes of every storage file.
+const MAGIC: [u8; 8] = *b"TXENGKVS";
+
+/// Magic and version.
+const HEADER_LEN: u64 = 12;
+
+/// Kind, table, key and value length of a record.
+const RECORD_HEADER_LEN: usize = 10;
+
+/// Largest value a record may hold, anything longer is a corrupt length.
+const MAX_VALUE_LEN: usize = 1 << 24;
+
+const PUT: u8 = 0;
+const REMOVE: u8 = 1;
+const COMMIT: u8 = 2;
+
+/// Records kept in memory before they are written to the file.
+const WRITE_BUFFER_LEN: usize = 64 << 10;
+
+/// Files smaller than this are never compacted.
+const COMPACT_MIN_LEN: u64 = 1 << 20;
+
+/// An embedded key-value database in a single file.
+///
+/// Every change is appended to the file as a record ending with a checksum, and a commit record
+/// marks the end of each group of changes. A sorted index points to the latest value of each
+/// key: it keeps the latest changes in memory and the rest sorted in scratch files, so memory use
+/// stays bounded and the values stay on disk. Opening the file replays the records up to the last
+/// commit and cuts off anything after it, such as the changes of a run that crashed or a record
+/// torn by the crash.
+///
+/// Replaced and removed values are garbage in the file. Once they take more than half of it, a
+/// commit rewrites the live values to a new file, renamed over the old one when complete.
+#[derive(Debug)]
+pub struct KvStore {
+    path: PathBuf,
+    file: RefCell<File>,
+    /// Location of the latest value of each key, see [`index_key`].
+    index: RefCell<SortedIndex>,
+    /// Number of keys in each table.
+    lens: [usize; Table::ALL.len()],
+    /// Length of the file, including the records still in `buffer`.
+    end: u64,
+    /// Records not written to the file yet.
+    buffer: Vec<u8>,
+    /// Bytes taken in the file by the records of the current values.
+    live: u64,
+}
+
+/// Bytes a record holding a value of `len` bytes takes in the file.
+fn record_len(len: u32) -> u64 {
+    (RECORD_HEADER_LEN + len as usize + 8) as u64
+}
+
+/// The key of the index for `key` of `table`, sorting the keys by table.
+fn index_key(table: Table, key: u32) -> u64 {
+    (table as u64) << 32 | key as u64
+}
+
+/// A path next to `path`, with `extension` added to its name.
+fn sibling(path: &Path, extension: &str) -> PathBuf {
+    let mut sibling = path.as_os_str().to_owned();
+    sibling.push(extension);
+    PathBuf::from(sibling)
+}
+
+/// Takes an exclusive advisory lock on `file`, held until it is closed.
+fn lock(file: &File) -> Result<(), StorageError> {
+    file.try_lock().map_err(|error| match error {
+        TryLockError::WouldBlock => StorageError::Locked,
+        TryLockError::Error(error) => StorageError::Io(error),
+    })
+}
+
+impl KvStore {
+    /// Opens the storage file at `path`, creating an empty one if there is none. The file stays
+    /// locked while the store is open, and [`StorageError::Locked`] is returned if another
+    /// process or handle has it open already.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StorageError> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+        // Replaying cuts off uncommitted records, which must not be those of another writer
+        lock(&file)?;
+        if file.metadata()?.len() == 0 {
+            file.write_all(&MAGIC)?;
+            file.write_all(&KV_VERSION.to_le_bytes())?;
+            file.sync_all()?;
+        }
+        let mut store = KvStore {
+            index: RefCell::new(SortedIndex::new(sibling(&path, ".index"), DEFAULT_INDEX_CACHE_PAGES)),
+            path,
+            file: RefCell::new(file),
+            lens: [0; Table::ALL.len()],
+            end: HEADER_LEN,
+            buffer: Vec::with_capacity(WRITE_BUFFER_LEN),
+            live: 0,
+        };
+        store.replay()?;
+        Ok(store)
+    }
+
+    /// Rebuilds the index from the committed records and cuts off the rest of the file.
+    fn replay(&mut self) -> Result<(), StorageError> {
+        let mut file = self.file.get_mut().try_clone()?;
+        // A first pass finds the last commit, so the changes of a commit need not be held until it is reached
+        self.end = scan(&file, u64::MAX, |_, _, _, _| Ok(()))?;
+        scan(&file, self.end, |kind, table, key, location| {
+            if kind == PUT {
+                self.insert(table, key, location)?;
+            } else {
+                self.forget(table, key)?;
+            }
+            Ok(())
+        })?;
+        if file.metadata()?.len() > self.end {
+            file.set_len(self.end)?;
+            file.sync_all()?;
+        }
+        file.rewind()?;
+        Ok(())
+    }
+
+    /// Points `key` to the value at `location`.
+    fn insert(&mut self, table: Table, key: u32, location: Location) -> io::Result<()> {
+        let index = self.index.get_mut();
+        match index.get(index_key(table, key))? {
+            Some((_, previous)) => self.live -= record_len(previous),
+            None => self.lens[table as usize] += 1,
+        }
+        index.set(index_key(table, key), Some(location))?;
+        self.live += record_len(location.1);
+        Ok(())
+    }
+
+    fn forget(&mut self, table: Table, key: u32) -> io::Result<bool> {
+        let index = self.index.get_mut();
+        let Some((_, previous)) = index.get(index_key(table, key))? else {
+            return Ok(false);
+        };
+        index.set(index_key(table, key), None)?;
+        self.live -= record_len(previous);
+        self.lens[table as usize] -= 1;
+        Ok(true)
+    }
+
+    /// Appends a record, returning the offset of its value.
+    fn append(&mut self, kind: u8, table: Table, key: u32, value: &[u8]) -> u64 {
+        let mut record = [0; RECORD_HEADER_LEN];
+        record[0] = kind;
+        record[1] = table as u8;
+        record[2..6].copy_from_slice(&key.to_le_bytes());
+        record[6..10].copy_from_slice(&(value.len() as u32).to_le_bytes());
+        let hash = fnv1a(fnv1a(FNV_OFFSET, &record), value);
+        self.buffer.extend_from_slice(&record);
+        self.buffer.extend_from_slice(value);
+        self.buffer.extend_from_slice(&hash.to_le_bytes());
+        let offset = self.end + RECORD_HEADER_LEN as u64;
+        self.end += record_len(value.len() as u32);
+        if self.buffer.len() >= WRITE_BUFFER_LEN {
+            self.write_buffer();
+        }
+        offset
+    }
+
+    fn write_buffer(&mut self) {
+        let start = self.end - self.buffer.len() as u64;
+        let file = self.file.get_mut();
+        file.seek(SeekFrom::Start(start))
+            .and_then(|_| file.write_all(&self.buffer))
+            .expect("failed to write to the storage file");
+        self.buffer.clear();
+    }
+
+    fn read(&self, offset: u64, len: u32) -> Vec<u8> {
+        let mut value = vec![0; len as usize];
+        let written = self.end - self.buffer.len() as u64;
+        if offset >= written {
+            let start = (offset - written) as usize;
+            value.copy_from_slice(&self.buffer[start..start + len as usize]);
+        } else {
+            let mut file = self.file.borrow_mut();
+            file.seek(SeekFrom::Start(offset))
+                .and_then(|_| file.read_exact(&mut value))
+                .expect("failed to read from the storage file");
+        }
+        value
+    }
+
+    /// Size of the storage file, including the records not written yet.
+    pub fn file_len(&self) -> u64 {
+        self.end
+    }
+
+    /// Rewrites the current values to a new file, which replaces the old one once complete.
+    fn compact(&mut self) -> io::Result<()> {
+        let temporary = sibling(&self.path, ".tmp");
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&temporary)?;
+        // Locked before it takes the place of the storage file, so it is never unlocked there
+        file.try_lock().map_err(io::Error::from)?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&KV_VERSION.to_le_bytes())?;
+        let mut end = HEADER_LEN;
+        self.index.borrow_mut().relocate(|key, (offset, len)| {
+            let value = self.read(offset, len);
+            let mut record = [0; RECORD_HEADER_LEN];
+            record[0] = PUT;
+            record[1] = (key >> 32) as u8;
+            record[2..6].copy_from_slice(&(key as u32).to_le_bytes());
+            record[6..10].copy_from_slice(&len.to_le_bytes());
+            writer.write_all(&record)?;
+            writer.write_all(&value)?;
+            writer.write_all(&fnv1a(fnv1a(FNV_OFFSET, &record), &value).to_le_bytes())?;
+            let location = (end + RECORD_HEADER_LEN as u64, len);
+            end += record_len(len);
+            Ok(location)
+        })?;
+        let mut commit = [0; RECORD_HEADER_LEN];
+        commit[0] = COMMIT;
+        writer.write_all(&commit)?;
+        writer.write_all(&fnv1a(FNV_OFFSET, &commit).to_le_bytes())?;
+        end += record_len(0);
+        let file = writer.into_inner().map_err(|error| error.into_error())?;
+        file.sync_all()?;
+        fs::rename(&temporary, &self.path)?;
+        // The rename is only durable once the directory is
+        let directory = self.path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        File::open(directory)?.sync_all()?;
+
+        self.file = RefCell::new(file);
+        self.end = end;
+        Ok(())
+    }
+}
+
+/// Reads the records of `file` that start before `limit`, passing the puts and removes to `visit`
+/// with the location of their value, and returns the end of the last commit record.
+fn scan(file: &File, limit: u64, mut visit: impl FnMut(u8, Table, u32, Location) -> io::Result<()>) -> Result<u64, StorageError> {
+    let mut reader = BufReader::new(file);
+    reader.rewind()?;
+    let mut header = [0; HEADER_LEN as usize];
+    reader.read_exact(&mut header).map_err(|_| StorageError::NotAStorage)?;
+    if header[..8] != MAGIC {
+        return Err(StorageError::NotAStorage);
+    }
+    let version = u32::from_le_bytes(header[8..].try_into().expect("four bytes"));
+    if version != KV_VERSION {
+        return Err(StorageError::UnsupportedVersion(version));
+    }
+
+    let mut offset = HEADER_LEN;
+    let mut committed = HEADER_LEN;
+    let mut value = Vec::new();
+    while offset < limit {
+        let mut record = [0; RECORD_HEADER_LEN];
+        if reader.read_exact(&mut record).is_err() {
+            break;
+        }
+        let kind = record[0];
+        let key = u32::from_le_bytes(record[2..6].try_into().expect("four bytes"));
+        let len = u32::from_le_bytes(record[6..10].try_into().expect("four bytes"));
+        if len as usize > MAX_VALUE_LEN {
+            break;
+        }
+        value.resize(len as usize, 0);
+        let mut hash = [0; 8];
+        if reader.read_exact(&mut value).and_then(|_| reader.read_exact(&mut hash)).is_err()
+            || u64::from_le_bytes(hash) != fnv1a(fnv1a(FNV_OFFSET, &record), &value) {
+            // Torn by a crash
+            break;
+        }
+        let table = Table::from_u8(record[1]).ok_or(StorageError::Corrupt("unknown table"))?;
+        match kind {
+            PUT | REMOVE => visit(kind, table, key, (offset + RECORD_HEADER_LEN as u64, len))?,
+            COMMIT => committed = offset + record_len(0),
+            _ => return Err(StorageError::Corrupt("unknown record kind")),
+        }
+        offset += record_len(len);
+    }
+    Ok(committed)
+}
+
+impl StorageBackend for KvStore {
+    fn get(&self, table: Table, key: u32) -> Option<Vec<u8>> {
+        let location = self.index.borrow_mut().get(index_key(table, key)).expect("failed to read the storage index");
+        location.map(|(offset, len)| self.read(offset, len))
+    }
+
+    fn put(&mut self, table: Table, key: u32, value: &[u8]) {
+        assert!(value.len() <= MAX_VALUE_LEN, "stored values are at most {} bytes", MAX_VALUE_LEN);
+        let offset = self.append(PUT, table, key, value);
+        self.insert(table, key, (offset, value.len() as u32)).expect("failed to update the storage index");
+    }
+
+    fn remove(&mut self, table: Table, key: u32) {
+        if self.forget(table, key).expect("failed to update the storage index") {
+            self.append(REMOVE, table, key, &[]);
+        }
+    }
+
+    fn len(&self, table: Table) -> usize {
+        self.lens[table as usize]
+    }
+
+    fn keys(&self, table: Table) -> Box<dyn Iterator<Item = u32> + '_> {
+        let entries = self.index.borrow().entries().expect("failed to read the storage index");
+        Box::new(entries.map(|entry| entry.expect("failed to read the storage index").0)
+            .filter(move |&key| key >> 32 == table as u64)
+            .map(|key| key as u32))
+    }
+
+    fn commit(&mut self) {
+        self.append(COMMIT, Table::Clients, 0, &[]);
+        self.write_buffer();
+        self.file.get_mut().sync_data().expect("failed to sync the storage file");
+        if self.end > COMPACT_MIN_LEN && self.end > 2 * self.live {
+            self.compact().expect("failed to compact the storage file");
+        }
+    }
+
+    fn heap_bytes(&self) -> usize {
+        self.index.borrow().heap_bytes() + self.buffer.capacity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(store: &KvStore, table: Table) -> Vec<(u32, Vec<u8>)> {
+        let mut values: Vec<_> = store.keys(table).map(|key| (key, store.get(table, key).unwrap())).collect();
+        values.sort();
+        values
+    }
+
+    #[test]
+    fn test_reopen_keeps_committed_changes() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("engine.kv");
+        let mut store = KvStore::open(&path).unwrap();
+        store.put(Table::Clients, 1, b"one");
+        store.put(Table::Transactions, 1, b"first");
+        store.put(Table::Clients, 2, b"two");
+        store.put(Table::Clients, 1, b"uno");
+        store.remove(Table::Clients, 2);
+        store.remove(Table::Clients, 3);
+        assert_eq!(store.get(Table::Clients, 1).as_deref(), Some(&b"uno"[..]));
+        store.commit();
+        // Uncommitted changes are lost with the process
+        store.put(Table::Clients, 4, b"four");
+        store.remove(Table::Transactions, 1);
+        store.write_buffer();
+        drop(store);
+
+        let store = KvStore::open(&path).unwrap();
+        assert_eq!(values(&store, Table::Clients), [(1, b"uno".to_vec())]);
+        assert_eq!(values(&store, Table::Transactions), [(1, b"first".to_vec())]);
+        assert_eq!((store.len(Table::Clients), store.len(Table::Fees)), (1, 0));
+        assert_eq!(store.file_len(), std::fs::metadata(&path).unwrap().len());
+    }
+
+    #[test]
+    fn test_torn_records_are_cut_off() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("engine.kv");
+        let mut store = KvStore::open(&path).unwrap();
+        store.put(Table::Fees, 0, b"fee");
+        store.commit();
+        store.put(Table::Fees, 1, b"another fee");
+        store.commit();
+        drop(store);
+        let committed = std::fs::read(&path).unwrap();
+
+        for cut in [1, 5, 12, 25] {
+            std::fs::write(&path, &committed[..committed.len() - cut]).unwrap();
+            let store = KvStore::open(&path).unwrap();
+            assert_eq!(values(&store, Table::Fees), [(0, b"fee".to_vec())], "{} bytes cut", cut);
+        }
+        let mut corrupt = committed.clone();
+        let last = corrupt.len() - 20;
+        corrupt[last] ^= 1;
+        std::fs::write(&path, &corrupt).unwrap();
+        assert_eq!(KvStore::open(&path).unwrap().len(Table::Fees), 1);
+
+        std::fs::write(&path, b"type, client, tx, amount\n").unwrap();
+        assert!(matches!(KvStore::open(&path), Err(StorageError::NotAStorage)));
+        let mut newer = committed;
+        newer[8] = 2;
+        std::fs::write(&path, &newer).unwrap();
+        assert!(matches!(KvStore::open(&path), Err(StorageError::UnsupportedVersion(2))));
+    }
+
+    #[test]
+    fn test_open_twice() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("engine.kv");
+        let mut store = KvStore::open(&path).unwrap();
+        store.put(Table::Clients, 1, b"one");
+        store.write_buffer();
+        assert!(matches!(KvStore::open(&path), Err(StorageError::Locked)));
+        store.commit();
+        assert_eq!(values(&store, Table::Clients), [(1, b"one".to_vec())]);
+        drop(store);
+        assert_eq!(KvStore::open(&path).unwrap().len(Table::Clients), 1);
+    }
+
+    #[test]
+    fn test_compaction() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("engine.kv");
+        let mut store = KvStore::open(&path).unwrap();
+        let value = |round: u32, key: u32| format!("{:04}-{:04}", round, key).into_bytes();
+        for round in 0..40 {
+            for key in 0..3000 {
+                store.put(Table::Transactions, key, &value(round, key));
+            }
+            store.remove(Table::Transactions, round);
+            store.commit();
+        }
+        // Without compaction the file would hold all 40 rounds
+        assert!(store.file_len() < COMPACT_MIN_LEN + 2 * 3000 * record_len(9));
+        assert_eq!(store.file_len(), std::fs::metadata(&path).unwrap().len());
+        // The compacted file took over the lock
+        assert!(matches!(KvStore::open(&path), Err(StorageError::Locked)));
+        drop(store);
+
+        let store = KvStore::open(&path).unwrap();
+        assert_eq!(store.len(Table::Transactions), 2999);
+        assert_eq!(store.get(Table::Transactions, 39), None);
+        assert_eq!(store.get(Table::Transactions, 2999), Some(value(39, 2999)));
+        assert_eq!(std::fs::read_dir(directory.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,21 @@
+pub mod amount;
+pub mod async_engine;
+pub mod checkpoint;
+pub mod client_table;
+pub mod csv_handler;
+pub mod currency;
+pub mod dispute_window;
+pub mod error;
+pub mod events;
+pub mod fees;
+mod kv_index;
+pub mod kv_store;
+pub mod mmap_csv;
+pub mod sharded_engine;
+pub mod snapshot;
+pub mod storage;
+#[cfg(test)]
+mod test_util;
+pub mod transaction_engine;
+pub mod transaction_store;
+pub mod write_ahead_log;
@@ -1,15 +1,315 @@
-use crate::transaction_engine::TransactionEngine;
+use log::info;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use transaction_engine::checkpoint::{self, InputPosition};
+use transaction_engine::csv_handler::{self, TransactionRaw};
+use transaction_engine::events::{EventFormat, EventLogWriter};
+use transaction_engine::fees::FeeSchedule;
+use transaction_engine::kv_store::KvStore;
+use transaction_engine::mmap_csv::{self, DEFAULT_CHUNK_LEN};
+use transaction_engine::sharded_engine::{ShardedEngine, MAX_SHARDS};
+use transaction_engine::transaction_engine::{ClientID, EngineConfig, LoadReport, TransactionEngine};
+use transaction_engine::transaction_store::{CompactStore, DiskStore, MemoryStore, TransactionStore, DEFAULT_CACHE_PAGES};
 
-mod csv_handler;
-mod transaction_engine;
+/// Where the engine keeps the transactions it may have to look up for disputes.
+enum StoreKind {
+    Memory,
+    Compact,
+    /// One file per shard in the given directory.
+    Disk(PathBuf),
+}
+
+/// Number of rows applied between two checkpoints unless told otherwise.
+const DEFAULT_CHECKPOINT_ROWS: usize = 1_000_000;
+
+/// How the input file is read.
+enum ReaderKind {
+    Serde,
+    Fast,
+    /// Memory-mapped, parsed in chunks on the given number of threads.
+    Parallel(usize),
+}
+
+struct Options {
+    path: String,
+    config: EngineConfig,
+    shards: usize,
+    store: StoreKind,
+    reader: ReaderKind,
+    /// Snapshot to start from instead of an empty engine.
+    restore: Option<PathBuf>,
+    /// Where to save the final state of the engine.
+    snapshot: Option<PathBuf>,
+    /// Log of the rows applied by this run, replayed if an earlier run of the same input crashed.
+    write_ahead_log: Option<PathBuf>,
+    /// Number of rows the log commits at once, instead of syncing every row.
+    group_commit: Option<usize>,
+    /// Where to save the engine state and the input position every `checkpoint_every` rows.
+    checkpoint: Option<PathBuf>,
+    checkpoint_every: usize,
+    /// Start from the saved checkpoint, if there is one.
+    resume: bool,
+    /// Where to write the events of the engine, as JSON Lines for `.jsonl` files and CSV otherwise.
+    event_log: Option<PathBuf>,
+    /// Storage file holding the clients and transactions, carried over from run to run.
+    storage: Option<PathBuf>,
+}
+
+/// Parses `[--idempotent-replays] [--dispute-withdrawals] [--allow-negative-balance]
+/// [--fees <fee schedule file> --house-account <client>] [--dispute-window <rows>] [--shards <count>]
+/// [--compact-transactions | --transaction-store <directory>] [--fast-csv | --parallel-csv <threads>]
+/// [--restore <snapshot>] [--snapshot <snapshot>] [--write-ahead-log <log> [--group-commit <rows>]]
+/// [--checkpoint <checkpoint> [--checkpoint-every <rows>] [--resume]] [--event-log <log>] [--storage <file>] <file>`
+/// from the command line.
+///
+/// `--dispute-window` counts input rows, not days: the input has no timestamps.
+fn parse_args() -> Options {
+    let mut config = EngineConfig::default();
+    let mut path = None;
+    let mut shards = 1;
+    let mut store = StoreKind::Memory;
+    let mut reader = ReaderKind::Serde;
+    let mut restore = None;
+    let mut snapshot = None;
+    let mut write_ahead_log = None;
+    let mut group_commit = None;
+    let mut checkpoint = None;
+    let mut checkpoint_every = DEFAULT_CHECKPOINT_ROWS;
+    let mut resume = false;
+    let mut event_log = None;
+    let mut storage = None;
+    let mut fees_path = None;
+    let mut house_account = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--idempotent-replays" => config.idempotent_replays = true,
+            "--dispute-withdrawals" => config.dispute_withdrawals = true,
+            "--allow-negative-balance" => config.allow_negative_balance = true,
+            "--fees" => fees_path = Some(args.next().expect("Please provide a fee schedule file after --fees")),
+            "--house-account" => house_account = Some(args.next()
+                .and_then(|client| client.parse::<ClientID>().ok())
+                .expect("Please provide a client id after --house-account")),
+            "--dispute-window" => config.dispute_window = Some(args.next()
+                .and_then(|rows| rows.parse().ok())
+                .filter(|&rows| rows > 0)
+                .expect("Please provide a positive number of rows after --dispute-window (the window counts rows, not days)")),
+            "--shards" => shards = args.next()
+                .and_then(|count| count.parse().ok())
+                .filter(|count| (1..=MAX_SHARDS).contains(count))
+                .expect("Please provide a number of shards between 1 and 65536 after --shards"),
+            "--compact-transactions" => store = StoreKind::Compact,
+            "--transaction-store" => store = StoreKind::Disk(PathBuf::from(args.next()
+                .expect("Please provide a directory after --transaction-store"))),
+            "--fast-csv" => reader = ReaderKind::Fast,
+            "--parallel-csv" => reader = ReaderKind::Parallel(args.next()
+                .and_then(|threads| threads.parse().ok())
+                .filter(|&threads| threads > 0)
+                .expect("Please provide a positive number of threads after --parallel-csv")),
+            "--restore" => restore = Some(PathBuf::from(args.next().expect("Please provide a snapshot file after --restore"))),
+            "--snapshot" => snapshot = Some(PathBuf::from(args.next().expect("Please provide a snapshot file after --snapshot"))),
+            "--write-ahead-log" => write_ahead_log = Some(PathBuf::from(args.next().expect("Please provide a log file after --write-ahead-log"))),
+            "--group-commit" => group_commit = Some(args.next()
+                .and_then(|rows| rows.parse().ok())
+                .filter(|&rows| rows > 0)
+                .expect("Please provide a positive number of rows after --group-commit")),
+            "--checkpoint" => checkpoint = Some(PathBuf::from(args.next().expect("Please provide a checkpoint file after --checkpoint"))),
+            "--checkpoint-every" => checkpoint_every = args.next()
+                .and_then(|rows| rows.parse().ok())
+                .filter(|&rows| rows > 0)
+                .expect("Please provide a positive number of rows after --checkpoint-every"),
+            "--resume" => resume = true,
+            "--event-log" => event_log = Some(PathBuf::from(args.next().expect("Please provide a log file after --event-log"))),
+            "--storage" => storage = Some(PathBuf::from(args.next().expect("Please provide a storage file after --storage"))),
+            flag if flag.starts_with("--") => panic!("Unknown option {}", flag),
+            _ => path = Some(arg),
+        }
+    }
+    if let Some(fees_path) = fees_path {
+        let house_account = house_account.expect("Please provide the --house-account receiving the fees");
+        let file = std::fs::File::open(&fees_path).expect("Failed to open fee schedule file");
+        config.fees = Some(FeeSchedule::load_csv(house_account, file).expect("Failed to load fee schedule"));
+    }
+    if (restore.is_some() || write_ahead_log.is_some()) && shards > 1 {
+        panic!("--restore and --write-ahead-log cannot be combined with --shards");
+    }
+    if shards > 1 && event_log.is_some() {
+        panic!("--event-log cannot be combined with --shards");
+    }
+    if group_commit.is_some() && write_ahead_log.is_none() {
+        panic!("Please provide the --write-ahead-log to commit in groups");
+    }
+    if resume && checkpoint.is_none() {
+        panic!("Please provide the --checkpoint to resume from");
+    }
+    if checkpoint.is_some() && (shards > 1 || matches!(reader, ReaderKind::Parallel(_))) {
+        panic!("--checkpoint cannot be combined with --shards or --parallel-csv");
+    }
+    if storage.is_some() && (shards > 1 || restore.is_some() || write_ahead_log.is_some() || checkpoint.is_some() || !matches!(store, StoreKind::Memory)) {
+        panic!("--storage cannot be combined with --shards, --restore, --write-ahead-log, --checkpoint or another transaction store");
+    }
+    Options {
+        path: path.expect("Please provide a file path as the first argument"),
+        config,
+        shards,
+        store,
+        reader,
+        restore,
+        snapshot,
+        write_ahead_log,
+        group_commit,
+        checkpoint,
+        checkpoint_every,
+        resume,
+        event_log,
+        storage,
+    }
+}
+
+/// One transaction store per shard.
+fn transaction_stores(shards: usize, kind: &StoreKind) -> Vec<Box<dyn TransactionStore>> {
+    (0..shards).map(|shard| match kind {
+        StoreKind::Memory => Box::new(MemoryStore::default()) as Box<dyn TransactionStore>,
+        StoreKind::Compact => Box::new(CompactStore::default()),
+        StoreKind::Disk(directory) => {
+            let store = DiskStore::create(directory.join(format!("transactions-{}.dat", shard)), DEFAULT_CACHE_PAGES)
+                .expect("Failed to create the transaction store");
+            Box::new(store)
+        },
+    }).collect()
+}
+
+/// Applies the input in rounds of `--checkpoint-every` rows, saving the engine state and the
+/// position reached in the input to `path` after each round. With `--resume`, carries on from the
+/// saved checkpoint if there is one, skipping the rows it already covers. With a `--write-ahead-log`,
+/// the rows logged after the checkpoint are replayed and skipped too, and the log starts over after
+/// each checkpoint.
+fn run_with_checkpoints(options: &Options, path: &Path, store: Box<dyn TransactionStore>, file: File) -> (TransactionEngine, LoadReport) {
+    let input_len = file.metadata().expect("Failed to read file metadata").len();
+    let (mut transaction_engine, start) = if options.resume && path.exists() {
+        let (transaction_engine, position) = checkpoint::load_checkpoint(path, options.config.clone(), store)
+            .unwrap_or_else(|error| panic!("Failed to resume from {}: {}", path.display(), error));
+        if position.input_len != input_len {
+            panic!("The checkpoint {} was saved for another input file", path.display());
+        }
+        info!("Resuming from record {} (line {}) of the input", position.next.record(), position.next.line());
+        (transaction_engine, Some(position.next))
+    } else {
+        (initial_engine(options, store), None)
+    };
+    let resuming = start.is_some() || options.write_ahead_log.as_ref().is_some_and(|log| log.exists());
+    attach_event_log(&mut transaction_engine, options, resuming);
+    let replayed = attach_write_ahead_log(&mut transaction_engine, options);
+
+    let fast = matches!(options.reader, ReaderKind::Fast);
+    let mut rows = csv_handler::resume_csv_file(file, start.as_ref(), fast).expect("Failed to seek in file")
+        .skip(replayed as usize)
+        .peekable();
+    let mut report = LoadReport::default();
+    let mut next = start;
+    while rows.peek().is_some() {
+        let round = rows.by_ref().take(options.checkpoint_every).map(|(position, transaction)| {
+            next = Some(position);
+            transaction
+        });
+        report.merge(transaction_engine.load_transactions(round));
+        let position = InputPosition { next: next.clone().expect("the round read a row"), input_len };
+        checkpoint::save_checkpoint(path, &transaction_engine, &position).expect("Failed to save checkpoint");
+        transaction_engine.trim_write_ahead_log().expect("Failed to trim the write-ahead log");
+    }
+    (transaction_engine, report)
+}
+
+/// The engine a run starts from: the state committed to the `--storage`, the `--restore` snapshot,
+/// or an empty engine.
+fn initial_engine(options: &Options, store: Box<dyn TransactionStore>) -> TransactionEngine {
+    if let Some(path) = &options.storage {
+        let storage = KvStore::open(path).unwrap_or_else(|error| panic!("Failed to open {}: {}", path.display(), error));
+        return TransactionEngine::open(options.config.clone(), Box::new(storage))
+            .unwrap_or_else(|error| panic!("Failed to load {}: {}", path.display(), error));
+    }
+    match &options.restore {
+        Some(path) => TransactionEngine::restore_file(options.config.clone(), store, path)
+            .unwrap_or_else(|error| panic!("Failed to restore {}: {}", path.display(), error)),
+        None => TransactionEngine::with_store(options.config.clone(), store),
+    }
+}
+
+/// Publishes the events of `transaction_engine` to the `--event-log`, if any. When `resuming` an
+/// interrupted run, the log it left carries on from the row the engine was restored to.
+fn attach_event_log(transaction_engine: &mut TransactionEngine, options: &Options, resuming: bool) {
+    if let Some(path) = &options.event_log {
+        let format = EventFormat::from_path(path);
+        let writer = if resuming {
+            EventLogWriter::resume(path, format, transaction_engine.position())
+        } else {
+            EventLogWriter::create(path, format)
+        }.expect("Failed to open the event log");
+        transaction_engine.set_event_sink(Box::new(writer));
+    }
+}
+
+/// Logs the rows applied by `transaction_engine` to the `--write-ahead-log`, if any, after replaying
+/// the rows an earlier, crashed run logged there. Returns the number of rows replayed.
+fn attach_write_ahead_log(transaction_engine: &mut TransactionEngine, options: &Options) -> u64 {
+    let Some(log) = &options.write_ahead_log else {
+        return 0;
+    };
+    let restored = transaction_engine.position();
+    transaction_engine.attach_write_ahead_log(log)
+        .unwrap_or_else(|error| panic!("Failed to recover from {}: {}", log.display(), error));
+    if let Some(rows) = options.group_commit {
+        transaction_engine.write_ahead_log_mut().expect("the log was attached").set_group_commit(rows);
+    }
+    let replayed = transaction_engine.position() - restored;
+    if replayed > 0 {
+        info!("Recovered {} rows from the write-ahead log", replayed);
+    }
+    replayed
+}
 
 fn main() {
     env_logger::init();
-    let path = std::env::args().nth(1).expect("Please provide a file path as the first argument");
-    let file = std::fs::File::open(&path).expect("Failed to open file");
+    let options = parse_args();
+    let file = File::open(&options.path).expect("Failed to open file");
 
-    let trasactions = csv_handler::load_csv_file(file);
-    let mut transaction_engine = TransactionEngine::default();
-    transaction_engine.load_transactions(trasactions);
+    let mut stores = transaction_stores(options.shards, &options.store);
+    let (mut transaction_engine, report) = if let Some(path) = &options.checkpoint {
+        run_with_checkpoints(&options, path, stores.remove(0), file)
+    } else {
+        let trasactions: Box<dyn Iterator<Item = TransactionRaw>> = match options.reader {
+            ReaderKind::Serde => Box::new(csv_handler::load_csv_file(file)),
+            ReaderKind::Fast => Box::new(csv_handler::load_csv_file_fast(file)),
+            ReaderKind::Parallel(threads) => Box::new(mmap_csv::load_csv_file_mmap(file, threads, DEFAULT_CHUNK_LEN)
+                .expect("Failed to map file")),
+        };
+        if options.shards > 1 {
+            let mut sharded_engine = ShardedEngine::with_stores(options.config.clone(), stores);
+            let report = sharded_engine.load_transactions(trasactions);
+            (sharded_engine.into_engine(), report)
+        } else {
+            let mut transaction_engine = initial_engine(&options, stores.remove(0));
+            let resuming = options.write_ahead_log.as_ref().is_some_and(|log| log.exists());
+            attach_event_log(&mut transaction_engine, &options, resuming);
+            // Rows of the input an earlier, crashed run already logged are replayed from the log
+            let replayed = attach_write_ahead_log(&mut transaction_engine, &options);
+            let report = transaction_engine.load_transactions(trasactions.skip(replayed as usize));
+            (transaction_engine, report)
+        }
+    };
+    info!("Applied {} transactions, rejected {}: {:?}", report.applied, report.rejected_total(), report.rejected);
+    let store = transaction_engine.transaction_store();
+    info!("Stored {} transactions using {:.1} bytes per transaction", store.len(), store.bytes_per_transaction());
+    if let Some(path) = &options.snapshot {
+        transaction_engine.save_snapshot(path).expect("Failed to save snapshot");
+    }
     csv_handler::write_clients_csv(&transaction_engine);
+    // The results are out, a rerun must start over
+    if let Some(log) = transaction_engine.take_write_ahead_log() {
+        log.remove().expect("Failed to remove the write-ahead log");
+    }
+    if let Some(path) = &options.checkpoint
+        && path.exists() {
+        std::fs::remove_file(path).expect("Failed to remove the checkpoint");
+    }
 }
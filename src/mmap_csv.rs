@@ -0,0 +1,380 @@
+use std::fs::File;
+use std::io;
+use std::sync::mpsc;
+use std::thread;
+use memmap2::Mmap;
+use crate::csv_handler::{self, RecordError, RecordParser, TransactionRaw};
+
+/// Size of the chunks the input is split into, unless told otherwise.
+pub const DEFAULT_CHUNK_LEN: usize = 4 << 20;
+
+/// Loads transactions from a memory-mapped CSV file, parsing chunks of about `chunk_len` bytes
+/// on `threads` threads at a time.
+///
+/// Chunks are cut on record boundaries and their rows are handed out in the order of the file,
+/// so the engine sees the same rows, and the log the same errors, as with
+/// [`load_csv_file`](csv_handler::load_csv_file). Parsing runs ahead of the consumer by at most
+/// one round of chunks.
+///
+/// The file must not be changed while it is read: the map reflects changes made by other
+/// processes, and truncating the file would crash the process.
+pub fn load_csv_file_mmap(file: File, threads: usize, chunk_len: usize) -> io::Result<impl Iterator<Item = TransactionRaw> + use<>> {
+    // SAFETY: the file is only read, and the caller keeps it unchanged while it is read, as documented
+    let data = unsafe { Mmap::map(&file)? };
+    Ok(csv_handler::skip_invalid(parallel_records(data, threads, chunk_len)))
+}
+
+/// Parses the records of `data` in chunks on up to `threads` threads, yielding them in order.
+fn parallel_records<D>(data: D, threads: usize, chunk_len: usize) -> impl Iterator<Item = Result<TransactionRaw, RecordError>>
+where D: AsRef<[u8]> + Send + 'static {
+    let threads = threads.max(1);
+    let chunk_len = chunk_len.max(1);
+    let (sender, receiver) = mpsc::sync_channel::<Vec<Result<TransactionRaw, RecordError>>>(threads);
+    thread::spawn(move || {
+        let bytes = data.as_ref();
+        let mut header_reader = csv_handler::reader_builder().from_reader(bytes);
+        let parser = RecordParser::new(header_reader.headers().ok().cloned());
+        // Position of the first record of the next chunk
+        let mut start = header_reader.position().clone();
+        let quoted = bytes.contains(&b'"');
+        loop {
+            let offset = start.byte() as usize;
+            let chunks = if quoted {
+                quoted_chunks(bytes, offset, threads, chunk_len)
+            } else {
+                let mut chunks = Vec::with_capacity(threads);
+                let mut offset = offset;
+                while chunks.len() < threads && offset < bytes.len() {
+                    let end = line_boundary(bytes, offset, chunk_len);
+                    chunks.push(offset..end);
+                    offset = end;
+                }
+                chunks
+            };
+            if chunks.is_empty() {
+                return;
+            }
+
+            let parsed: Vec<_> = thread::scope(|scope| {
+                let workers: Vec<_> = chunks.iter()
+                    .map(|range| scope.spawn(|| parse_chunk(&parser, &bytes[range.clone()])))
+                    .collect();
+                workers.into_iter().map(|worker| worker.join().expect("chunk parsing does not panic")).collect()
+            });
+            for (range, records) in chunks.into_iter().zip(parsed) {
+                let records: Vec<_> = records.into_iter().map(|record| record.map_err(|error| error.offset_by(&start))).collect();
+                let (record, line) = (start.record() + records.len() as u64, start.line() + count_lines(&bytes[range.clone()]));
+                start.set_byte(range.end as u64).set_line(line).set_record(record);
+                if sender.send(records).is_err() {
+                    // The consumer stopped reading
+                    return;
+                }
+            }
+        }
+    });
+    receiver.into_iter().flatten()
+}
+
+/// Number of lines `bytes` moves the position of the csv reader by. It counts line feeds only,
+/// so records ended by a bare carriage return stay on the line of the next line feed.
+fn count_lines(bytes: &[u8]) -> u64 {
+    bytes.iter().filter(|&&byte| byte == b'\n').count() as u64
+}
+
+fn is_terminator(byte: u8) -> bool {
+    BYTE_CLASSES[byte as usize] == TERMINATOR
+}
+
+/// The end of the chunk starting at `start` in an input without quotes: the first line end at
+/// least `chunk_len` bytes further, or the end of the input.
+///
+/// A record ends right after its first line terminator, blank lines after it count as the start of
+/// the next record, as they do for the positions the csv reader reports. Without any quote in the
+/// input every non-blank line is a record.
+fn line_boundary(bytes: &[u8], start: usize, chunk_len: usize) -> usize {
+    let target = start.saturating_add(chunk_len);
+    if target >= bytes.len() {
+        return bytes.len();
+    }
+    match bytes[target - 1..].windows(2).position(|pair| !is_terminator(pair[0]) && is_terminator(pair[1])) {
+        Some(index) => target + index + 1,
+        None => bytes.len(),
+    }
+}
+
+/// Where the csv reader can be within a record, as far as finding record ends goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ScanState {
+    /// Between records, where line terminators are blank lines.
+    StartRecord,
+    StartField,
+    /// In a field that did not start with a quote, where quotes are plain bytes.
+    InField,
+    InQuotedField,
+    /// Right after a quote in a quoted field, which either closes the field or escapes a quote.
+    QuoteInQuotedField,
+}
+
+/// Bytes that move a [`ScanState`]: quotes, commas, line terminators, and all the others.
+const OTHER: u8 = 0;
+const QUOTE: u8 = 1;
+const COMMA: u8 = 2;
+const TERMINATOR: u8 = 3;
+
+const BYTE_CLASSES: [u8; 256] = {
+    let mut classes = [0; 256];
+    classes[b'"' as usize] = QUOTE;
+    classes[b',' as usize] = COMMA;
+    classes[b'\r' as usize] = TERMINATOR;
+    classes[b'\n' as usize] = TERMINATOR;
+    classes
+};
+
+impl ScanState {
+    const ALL: [ScanState; 5] = [ScanState::StartRecord, ScanState::StartField, ScanState::InField, ScanState::InQuotedField, ScanState::QuoteInQuotedField];
+
+    /// The state after a byte of `class`, and whether the byte ended a record. Follows the rules of
+    /// the csv reader: a quote only opens a quoted field at the start of the field, and inside it
+    /// a doubled quote is an escaped quote.
+    const fn next(self, class: u8) -> (ScanState, bool) {
+        match (self, class) {
+            (ScanState::StartRecord, TERMINATOR) => (ScanState::StartRecord, false),
+            (ScanState::StartRecord | ScanState::StartField, QUOTE) => (ScanState::InQuotedField, false),
+            (ScanState::InQuotedField, QUOTE) => (ScanState::QuoteInQuotedField, false),
+            (ScanState::InQuotedField, _) => (ScanState::InQuotedField, false),
+            (ScanState::QuoteInQuotedField, QUOTE) => (ScanState::InQuotedField, false),
+            (_, COMMA) => (ScanState::StartField, false),
+            (_, TERMINATOR) => (ScanState::StartRecord, true),
+            _ => (ScanState::InField, false),
+        }
+    }
+}
+
+/// Number of combinations of one [`ScanState`] for each of the states a region may start in.
+const SPECULATIONS: usize = 5usize.pow(5);
+
+/// Transitions of the five speculative scans of a region at once: the combination of states
+/// reached by each of them, packed in base 5 with the scan started in `ScanState::ALL[i]` as
+/// digit `i`, and the mask of scans the byte ended a record for, by combination and byte class.
+static SPECULATIVE_TRANSITIONS: [[(u16, u8); 4]; SPECULATIONS] = {
+    let mut transitions = [[(0, 0); 4]; SPECULATIONS];
+    let mut combination = 0;
+    while combination < SPECULATIONS {
+        let mut class = 0;
+        while class < 4 {
+            let (mut next, mut ended, mut digits, mut weight, mut scan) = (0, 0, combination, 1, 0);
+            while scan < 5 {
+                let (state, record_end) = ScanState::ALL[digits % 5].next(class as u8);
+                next += state as usize * weight;
+                if record_end {
+                    ended |= 1 << scan;
+                }
+                digits /= 5;
+                weight *= 5;
+                scan += 1;
+            }
+            transitions[combination][class] = (next as u16, ended);
+            class += 1;
+        }
+        combination += 1;
+    }
+    transitions
+};
+
+/// What scanning a region of the input finds, for one state the region may start in.
+#[derive(Debug, Clone, Copy)]
+struct RegionScan {
+    /// The state at the end of the region.
+    end_state: ScanState,
+    /// Offset right after the first record end in the region.
+    first_record_end: Option<usize>,
+}
+
+/// Scans `region` once for each state it may start in, in the order of `ScanState::ALL`, all
+/// five scans advancing together through [`SPECULATIVE_TRANSITIONS`].
+fn scan_region(region: &[u8]) -> [RegionScan; 5] {
+    // Each scan starts in its own state: digit `i` is `i`
+    let mut combination = (0..5).map(|scan| scan * 5usize.pow(scan as u32)).sum::<usize>();
+    let mut first_record_ends = [None; 5];
+    let mut pending = 0b11111u8;
+    let mut index = 0;
+    while index < region.len() {
+        let class = BYTE_CLASSES[region[index] as usize];
+        let (next, ended) = SPECULATIVE_TRANSITIONS[combination][class as usize];
+        combination = next as usize;
+        index += 1;
+        if ended & pending != 0 {
+            for (scan, first_record_end) in first_record_ends.iter_mut().enumerate() {
+                if ended & pending & (1 << scan) != 0 {
+                    *first_record_end = Some(index);
+                }
+            }
+            pending &= !ended;
+        }
+        if class == OTHER {
+            // Further plain bytes leave every state as it is
+            index += region[index..].iter().position(|&byte| BYTE_CLASSES[byte as usize] != OTHER).unwrap_or(region.len() - index);
+        }
+    }
+    std::array::from_fn(|scan| RegionScan {
+        end_state: ScanState::ALL[combination / 5usize.pow(scan as u32) % 5],
+        first_record_end: first_record_ends[scan],
+    })
+}
+
+/// Up to `threads` chunks of an input containing quotes, starting at the record boundary `offset`
+/// and each ending on a record boundary at least `chunk_len` bytes further, or at the end of the input.
+///
+/// Whether a line break ends a record depends on the quotes before it, so the input after `offset`
+/// is split into regions of `chunk_len` bytes scanned in parallel, speculatively from every state
+/// a region may start in. The actual state at the start of each region then follows from the one
+/// before, and with it the first record end of the region, which is where the previous chunk ends.
+/// A record longer than all the regions is scanned to its end afterwards.
+fn quoted_chunks(bytes: &[u8], offset: usize, threads: usize, chunk_len: usize) -> Vec<std::ops::Range<usize>> {
+    if offset >= bytes.len() {
+        return Vec::new();
+    }
+    let regions: Vec<_> = (0..=threads)
+        .map(|index| offset.saturating_add(index.saturating_mul(chunk_len)))
+        .take_while(|&start| start < bytes.len())
+        .map(|start| start..start.saturating_add(chunk_len).min(bytes.len()))
+        .collect();
+    let scans: Vec<[RegionScan; 5]> = thread::scope(|scope| {
+        let workers: Vec<_> = regions.iter()
+            .map(|region| scope.spawn(|| scan_region(&bytes[region.clone()])))
+            .collect();
+        workers.into_iter().map(|worker| worker.join().expect("scanning does not panic")).collect()
+    });
+
+    let mut chunks = Vec::with_capacity(threads);
+    let mut chunk_start = offset;
+    // The first region starts on a record boundary
+    let mut state = scans[0][ScanState::StartRecord as usize].end_state;
+    for (region, scans) in regions.iter().zip(&scans).skip(1) {
+        if chunks.len() == threads {
+            break;
+        }
+        let scan = scans[state as usize];
+        if let Some(record_end) = scan.first_record_end {
+            chunks.push(chunk_start..region.start + record_end);
+            chunk_start = region.start + record_end;
+        }
+        state = scan.end_state;
+    }
+    let scanned = regions.last().expect("the input goes on after offset").end;
+    if chunks.is_empty() {
+        // No record ended in any region after the first, scan on to the end of the record
+        let mut end = bytes.len();
+        for (index, &byte) in bytes[scanned..].iter().enumerate() {
+            let ended;
+            (state, ended) = state.next(BYTE_CLASSES[byte as usize]);
+            if ended {
+                end = scanned + index + 1;
+                break;
+            }
+        }
+        chunks.push(chunk_start..end);
+    } else if scanned == bytes.len() && chunk_start < bytes.len() && chunks.len() < threads {
+        chunks.push(chunk_start..bytes.len());
+    }
+    chunks
+}
+
+/// Parses every record of a chunk, with positions relative to the chunk.
+fn parse_chunk(parser: &RecordParser, chunk: &[u8]) -> Vec<Result<TransactionRaw, RecordError>> {
+    let mut reader = csv_handler::reader_builder().has_headers(false).from_reader(chunk);
+    let mut record = csv::ByteRecord::new();
+    let mut records = Vec::new();
+    loop {
+        match reader.read_byte_record(&mut record) {
+            Err(error) => records.push(Err(RecordError::Csv(error))),
+            Ok(false) => return records,
+            Ok(true) => records.push(parser.parse(&record)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Seek, Write};
+
+    const INPUT: &[u8] = b"type, client, tx, amount, reason\r
+deposit, 1, 1, 1.5\r
+withdrawal, 2, 2, 0.25
+
+freeze, 1, 3, , \"line one
+line two, with \"\"quotes\"\"\"
+refund, 1, 4, 1.0
+deposit, 1, 5, 1.00001
+deposit, 0x10, 6, 2.0
+deposit, 1, 7, \xff
+deposit, 3
+resolve, 1, 1,
+deposit, 1, 8, 3.0";
+
+    fn assert_same_records(input: &'static [u8]) {
+        let expected: Vec<_> = csv_handler::deserialize_records(input).map(|result| result.map_err(|e| e.to_string())).collect();
+        for chunk_len in [1, 7, 40, 1000] {
+            for threads in [1, 3] {
+                let records: Vec<_> = parallel_records(input, threads, chunk_len).map(|result| result.map_err(|e| e.to_string())).collect();
+                assert_eq!(records, expected, "chunks of {} bytes on {} threads", chunk_len, threads);
+            }
+        }
+    }
+
+    #[test]
+    fn test_matches_sequential_reader() {
+        assert_same_records(INPUT);
+        // Without quotes, chunks are cut at line ends
+        assert_same_records(b"type, client, tx, amount\ndeposit, 1, 1, 1.0\n\ndeposit, 2, 2, x\ndeposit, 3, 3, 3.0\n");
+        assert_same_records(b"type, client, tx, amount\r\n\r\ndeposit, 1, 1, 1.0\r\n\r\r\ndeposit, 2, 2, x\r\rdeposit, 3, 3\n");
+        // Only carriage returns, with errors reported at the positions the csv reader gives
+        assert_same_records(b"type, client, tx, amount\rdeposit, 1, 1, 1.0\r\rdeposit, 2, 2, x\rdeposit, 3, 3\rdeposit, 4, 4, 4.0\r");
+        assert_same_records(b"type, client, tx, amount\n");
+        assert_same_records(b"");
+    }
+
+    #[test]
+    fn test_quoted_input() {
+        // Quotes opening fields, escaped, closing fields early, inside unquoted fields and after spaces
+        assert_same_records(b"type, client, tx, amount, reason
+freeze, 1, 1,,\"a,\r\n\"\"b\"\"\"
+unlock, 1, 2,,\"x\"y
+deposit, 1, 3, 1.0
+freeze, 2, 4,, a\"b
+freeze, 2, 5,, \"c
+unlock, 2, 6,,\"\"
+freeze, 3, 7,,\"
+\"
+deposit, 4, 8, 2.0,\"unterminated
+deposit, 4, 9, 3.0
+");
+        // Boundaries are found without reading the records one by one, so every thread gets a chunk
+        let input: String = (1..=1000).map(|tx| format!("freeze, 1, {}, ,\"multi\nline, \"\"quoted\"\"\"\n", tx)).collect();
+        let chunks = quoted_chunks(input.as_bytes(), 0, 4, input.len() / 8);
+        assert_eq!(chunks.len(), 4);
+        assert!(chunks.iter().all(|chunk| input[chunk.start..].starts_with("freeze")));
+        // Random mixes of the bytes that matter to record boundaries
+        let mut seed = 7u64;
+        for _ in 0..50 {
+            let mut input = b"type, client, tx, amount, reason\n".to_vec();
+            for _ in 0..200 {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                let alphabet = b"deposit, 1, 2, 1.0\n\",\r ax";
+                input.push(alphabet[(seed >> 40) as usize % alphabet.len()]);
+            }
+            assert_same_records(input.leak());
+        }
+    }
+
+    #[test]
+    fn test_load_file() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(INPUT).unwrap();
+        file.rewind().unwrap();
+        let expected: Vec<_> = csv_handler::load_csv_file(file.try_clone().unwrap()).collect();
+        assert_eq!(load_csv_file_mmap(file, 4, 16).unwrap().collect::<Vec<_>>(), expected);
+        assert_eq!(load_csv_file_mmap(tempfile::tempfile().unwrap(), 4, 16).unwrap().count(), 0);
+    }
+}
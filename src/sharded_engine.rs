@@ -0,0 +1,384 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+use crate::csv_handler::{TransactionRaw, TransactionTypeRaw};
+use crate::dispute_window::{IdRanges, MAX_EXPIRED_RANGES};
+use crate::error::TransactionError;
+use crate::transaction_engine::{ClientAccounts, ClientID, EngineConfig, LoadReport, Outcome, TransactionEngine, TransactionID};
+use crate::transaction_store::{MemoryStore, TransactionStore};
+
+/// Maximum number of shards, one per possible client id.
+pub const MAX_SHARDS: usize = ClientID::MAX as usize + 1;
+
+/// Number of commands that can be queued for a shard before the router waits for it.
+const SHARD_QUEUE_LEN: usize = 4096;
+
+/// Accounts of clients lent by their own shard to the shard applying a row.
+type Borrowed = Vec<(ClientID, Option<ClientAccounts>)>;
+
+enum Command {
+    /// Applies a row. Clients lent by other shards are inserted for the duration of the row
+    /// and sent back through `reply`, which is only given when something was borrowed.
+    Apply { sequence: u64, transaction: TransactionRaw, borrowed: Borrowed, reply: Option<SyncSender<Applied>> },
+    /// Removes a client so another shard can act on it.
+    Lend { client_id: ClientID, reply: SyncSender<Option<ClientAccounts>> },
+    /// Puts back a client after it was lent.
+    Return { client_id: ClientID, accounts: ClientAccounts },
+    /// Asks whether the shard holds a transaction id.
+    IsClaimed { transaction_id: TransactionID, reply: SyncSender<bool> },
+    /// Asks for the source client of a transfer the shard stores.
+    Source { transaction_id: TransactionID, reply: SyncSender<Option<ClientID>> },
+    /// Hands over the counts of the rows applied since the last report.
+    Report { reply: SyncSender<LoadReport> },
+}
+
+struct Applied {
+    result: Result<Outcome, TransactionError>,
+    borrowed: Borrowed,
+}
+
+/// State owned by one worker thread.
+struct Shard {
+    engine: TransactionEngine,
+    report: LoadReport,
+    /// Sequence number of the row that created each audit entry of the engine.
+    audit_sequence: Vec<u64>,
+    /// Sequence number of the row that charged each fee of the engine.
+    fee_sequence: Vec<u64>,
+}
+
+impl Shard {
+    fn run(mut self, commands: Receiver<Command>) -> Self {
+        for command in commands {
+            match command {
+                Command::Apply { sequence, transaction, borrowed, reply } => {
+                    let client_ids: Vec<ClientID> = borrowed.into_iter().map(|(client_id, accounts)| {
+                        if let Some(accounts) = accounts {
+                            self.engine.put_client(client_id, accounts);
+                        }
+                        client_id
+                    }).collect();
+                    let result = self.apply(sequence, &transaction);
+                    if let Some(reply) = reply {
+                        let borrowed = client_ids.into_iter().map(|client_id| (client_id, self.engine.take_client(client_id))).collect();
+                        let _ = reply.send(Applied { result, borrowed });
+                    }
+                },
+                Command::Lend { client_id, reply } => {
+                    let _ = reply.send(self.engine.take_client(client_id));
+                },
+                Command::Return { client_id, accounts } => self.engine.put_client(client_id, accounts),
+                Command::IsClaimed { transaction_id, reply } => {
+                    let _ = reply.send(self.engine.is_claimed(transaction_id));
+                },
+                Command::Source { transaction_id, reply } => {
+                    let _ = reply.send(self.engine.transaction(transaction_id).and_then(|transaction| transaction.counterparty));
+                },
+                Command::Report { reply } => {
+                    let _ = reply.send(std::mem::take(&mut self.report));
+                },
+            }
+        }
+        self
+    }
+
+    fn apply(&mut self, sequence: u64, transaction: &TransactionRaw) -> Result<Outcome, TransactionError> {
+        let result = self.engine.apply_at(sequence, transaction);
+        self.report.record(transaction, &result);
+        self.audit_sequence.resize(self.engine.audit_log_len(), sequence);
+        self.fee_sequence.resize(self.engine.fees_len(), sequence);
+        result
+    }
+}
+
+/// A [`TransactionEngine`] split into shards, each owning the clients whose id maps to it
+/// and running on its own thread.
+///
+/// Rows are routed to the shard of their client in input order, so every client sees its
+/// transactions in the same order as with a single engine. Rows involving clients of several
+/// shards, such as transfers or disputes on another client's transaction id, are applied by
+/// one shard after the router has borrowed the other clients' accounts from their shards.
+/// Those rows wait for every shard involved, so the final state is the same as the one a
+/// single engine reaches on the same input.
+///
+/// With a dispute window, the router only remembers the ids claimed within the window one by
+/// one. Older ids are kept as ranges tagged with their shard, like the expired ids of a
+/// [`TransactionEngine`], so its memory stays bounded however long the input is. Once ranges of
+/// different shards are coalesced, the shards are asked which one holds an id.
+pub struct ShardedEngine {
+    config: EngineConfig,
+    shards: Vec<(SyncSender<Command>, JoinHandle<Shard>)>,
+    /// Shard holding, or the last one to try to claim, each transaction id, with the sequence
+    /// number of the claim.
+    claims: HashMap<TransactionID, (u16, u64)>,
+    /// Claims in the order they were made, while they are within the dispute window.
+    recent_claims: VecDeque<(u64, TransactionID)>,
+    /// Shard of the ids whose claim fell out of the dispute window.
+    expired_claims: IdRanges<u16>,
+    /// Number of expired ranges past which they are coalesced.
+    max_expired_claims: usize,
+    /// Source client of the transfers between clients of different shards, within the dispute window.
+    transfer_sources: HashMap<TransactionID, ClientID>,
+    sequence: u64,
+}
+
+impl ShardedEngine {
+    /// Starts `shards` worker threads, each with an empty engine using `config` and keeping its transactions in memory.
+    pub fn new(config: EngineConfig, shards: usize) -> Self {
+        let stores = (0..shards).map(|_| Box::new(MemoryStore::default()) as Box<dyn TransactionStore>).collect();
+        ShardedEngine::with_stores(config, stores)
+    }
+
+    /// Starts one worker thread per store, each with an empty engine using `config` and keeping its transactions in its store.
+    pub fn with_stores(config: EngineConfig, stores: Vec<Box<dyn TransactionStore>>) -> Self {
+        assert!((1..=MAX_SHARDS).contains(&stores.len()), "the number of shards must be between 1 and {}", MAX_SHARDS);
+        let shards = stores.into_iter().map(|store| {
+            let (sender, receiver) = mpsc::sync_channel(SHARD_QUEUE_LEN);
+            let shard = Shard {
+                engine: TransactionEngine::with_store(config.clone(), store),
+                report: LoadReport::default(),
+                audit_sequence: Vec::new(),
+                fee_sequence: Vec::new(),
+            };
+            (sender, thread::spawn(move || shard.run(receiver)))
+        }).collect();
+        ShardedEngine {
+            config,
+            shards,
+            claims: HashMap::new(),
+            recent_claims: VecDeque::new(),
+            expired_claims: IdRanges::default(),
+            max_expired_claims: MAX_EXPIRED_RANGES,
+            transfer_sources: HashMap::new(),
+            sequence: 0,
+        }
+    }
+
+    /// Applies every transaction in order, waiting for all shards before reporting the applied and rejected counts.
+    pub fn load_transactions(&mut self, transactions: impl Iterator<Item = TransactionRaw>) -> LoadReport {
+        for transaction in transactions {
+            self.route(transaction);
+        }
+        let mut report = LoadReport::default();
+        for shard in 0..self.shards.len() {
+            report.merge(self.ask(shard, |reply| Command::Report { reply }));
+        }
+        report
+    }
+
+    /// Stops the workers and merges their shards into a single engine.
+    pub fn into_engine(self) -> TransactionEngine {
+        let shards = self.shards.into_iter().map(|(sender, worker)| {
+            drop(sender);
+            let shard = worker.join().expect("shard worker panicked");
+            (shard.engine, shard.audit_sequence, shard.fee_sequence)
+        }).collect();
+        TransactionEngine::merge(self.config, shards)
+    }
+
+    #[inline]
+    fn shard_of(&self, client_id: ClientID) -> usize {
+        client_id as usize % self.shards.len()
+    }
+
+    fn is_house_account(&self, client_id: ClientID) -> bool {
+        self.config.fees.as_ref().is_some_and(|fees| fees.house_account == client_id)
+    }
+
+    fn send(&self, shard: usize, command: Command) {
+        self.shards[shard].0.send(command).expect("shard workers run until the engine is stopped");
+    }
+
+    fn ask<T>(&self, shard: usize, command: impl FnOnce(SyncSender<T>) -> Command) -> T {
+        let (reply, receiver) = mpsc::sync_channel(1);
+        self.send(shard, command(reply));
+        receiver.recv().expect("shard workers run until the engine is stopped")
+    }
+
+    /// Picks the shard applying `transaction` and the clients it involves, borrowing those of other shards.
+    fn route(&mut self, transaction: TransactionRaw) {
+        self.sequence += 1;
+        self.expire_claims();
+        let home = self.shard_of(transaction.client);
+        let (target, parties) = if self.is_house_account(transaction.client) {
+            // Rejected without touching any account
+            (home, [transaction.client, transaction.client])
+        } else {
+            match transaction.transaction_type {
+                TransactionTypeRaw::Deposit | TransactionTypeRaw::Withdrawal => {
+                    (self.claim(transaction.tx, home), [transaction.client, transaction.client])
+                },
+                TransactionTypeRaw::Transfer => {
+                    let destination = transaction.to
+                        .filter(|&destination| destination != transaction.client && !self.is_house_account(destination));
+                    match destination {
+                        Some(destination) => (self.claim(transaction.tx, self.shard_of(destination)), [transaction.client, destination]),
+                        None => (home, [transaction.client, transaction.client]),
+                    }
+                },
+                TransactionTypeRaw::Dispute | TransactionTypeRaw::Resolve | TransactionTypeRaw::Chargeback => {
+                    let transaction_id = transaction.tx;
+                    let target = self.holder(transaction_id).unwrap_or(home);
+                    let source = match self.transfer_sources.get(&transaction_id) {
+                        Some(&source) => source,
+                        // An expired transfer may still be kept for an open dispute, its shard knows the source
+                        None if !self.claims.contains_key(&transaction_id) && self.expired_claims.contains(transaction_id) => {
+                            self.ask(target, |reply| Command::Source { transaction_id, reply }).unwrap_or(transaction.client)
+                        },
+                        None => transaction.client,
+                    };
+                    (target, [transaction.client, source])
+                },
+                TransactionTypeRaw::Freeze | TransactionTypeRaw::Unlock | TransactionTypeRaw::Close => {
+                    (home, [transaction.client, transaction.client])
+                },
+            }
+        };
+
+        let mut borrowed = Borrowed::new();
+        for client_id in parties {
+            let shard = self.shard_of(client_id);
+            if shard != target && borrowed.iter().all(|(borrowed_id, _)| *borrowed_id != client_id) {
+                borrowed.push((client_id, self.ask(shard, |reply| Command::Lend { client_id, reply })));
+            }
+        }
+        let sequence = self.sequence;
+        if borrowed.is_empty() {
+            self.send(target, Command::Apply { sequence, transaction, borrowed, reply: None });
+            return;
+        }
+
+        let (source, transaction_id, is_transfer) = (transaction.client, transaction.tx, matches!(transaction.transaction_type, TransactionTypeRaw::Transfer));
+        let applied = self.ask(target, |reply| Command::Apply { sequence, transaction, borrowed, reply: Some(reply) });
+        if is_transfer && matches!(applied.result, Ok(Outcome::Transferred(_))) {
+            self.transfer_sources.insert(transaction_id, source);
+        }
+        for (client_id, accounts) in applied.borrowed {
+            if let Some(accounts) = accounts {
+                self.send(self.shard_of(client_id), Command::Return { client_id, accounts });
+            }
+        }
+    }
+
+    /// The shard that must apply a new transaction with `transaction_id` for a client of shard `home`:
+    /// the shard already holding the id, so that it rejects the duplicate, or `home` otherwise.
+    fn claim(&mut self, transaction_id: TransactionID, home: usize) -> usize {
+        let target = match self.holder(transaction_id) {
+            Some(previous) if previous != home && self.ask(previous, |reply| Command::IsClaimed { transaction_id, reply }) => previous,
+            _ => home,
+        };
+        self.claims.insert(transaction_id, (target as u16, self.sequence));
+        if self.config.dispute_window.is_some() {
+            self.recent_claims.push_back((self.sequence, transaction_id));
+            self.expired_claims.remove(transaction_id);
+        }
+        target
+    }
+
+    /// The shard holding, or the last one to try to claim, `transaction_id`, if any did.
+    fn holder(&self, transaction_id: TransactionID) -> Option<usize> {
+        if let Some(&(shard, _)) = self.claims.get(&transaction_id) {
+            return Some(shard as usize);
+        }
+        if !self.expired_claims.contains(transaction_id) {
+            return None;
+        }
+        match self.expired_claims.tag(transaction_id) {
+            Some(shard) => Some(shard as usize),
+            // Coalesced with the ids of other shards
+            None => (0..self.shards.len()).find(|&shard| self.ask(shard, |reply| Command::IsClaimed { transaction_id, reply })),
+        }
+    }
+
+    /// Moves the claims that fell out of the dispute window to the expired ranges.
+    fn expire_claims(&mut self) {
+        let Some(window) = self.config.dispute_window else {
+            return;
+        };
+        while let Some(&(sequence, transaction_id)) = self.recent_claims.front()
+            && sequence.saturating_add(window) <= self.sequence {
+            self.recent_claims.pop_front();
+            // Unless the id was claimed again since
+            if let Some(&(shard, claimed)) = self.claims.get(&transaction_id)
+                && claimed == sequence {
+                self.claims.remove(&transaction_id);
+                self.transfer_sources.remove(&transaction_id);
+                self.expired_claims.insert(transaction_id, shard);
+                if self.expired_claims.range_count() > self.max_expired_claims {
+                    self.expired_claims.coalesce(self.max_expired_claims);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fees::FeeSchedule;
+    use crate::test_util::workload;
+
+    fn assert_same_state(config: EngineConfig, shards: usize) {
+        let mut sequential = TransactionEngine::new(config.clone());
+        let expected_report = sequential.load_transactions(workload(5000).into_iter());
+        let mut sharded = ShardedEngine::new(config, shards);
+        let report = sharded.load_transactions(workload(5000).into_iter());
+        let merged = sharded.into_engine();
+
+        assert_eq!(report, expected_report);
+        let sorted = |engine: &TransactionEngine| {
+            let mut clients: Vec<_> = engine.clients().collect();
+            clients.sort_by_key(|client| (client.client_id, client.currency));
+            clients
+        };
+        assert_eq!(sorted(&merged), sorted(&sequential));
+        for tx in 1..=5000 {
+            assert_eq!(merged.transaction(tx), sequential.transaction(tx));
+        }
+        assert_eq!(merged.audit_log().collect::<Vec<_>>(), sequential.audit_log().collect::<Vec<_>>());
+        assert_eq!(merged.fees().collect::<Vec<_>>(), sequential.fees().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_matches_sequential_engine() {
+        assert_same_state(EngineConfig::default(), 1);
+        assert_same_state(EngineConfig::default(), 3);
+        assert_same_state(EngineConfig { idempotent_replays: true, dispute_withdrawals: true, allow_negative_balance: true, ..Default::default() }, 4);
+    }
+
+    #[test]
+    fn test_matches_sequential_engine_with_fees() {
+        let mut fees = FeeSchedule::new(7);
+        fees.withdrawal.flat = "0.5".parse().unwrap();
+        fees.transfer.percent = "1".parse().unwrap();
+        fees.chargeback.flat = "5".parse().unwrap();
+        assert_same_state(EngineConfig { fees: Some(fees), ..Default::default() }, 4);
+    }
+
+    #[test]
+    fn test_matches_sequential_engine_with_dispute_window() {
+        assert_same_state(EngineConfig { dispute_window: Some(200), ..Default::default() }, 4);
+        assert_same_state(EngineConfig { dispute_window: Some(1), idempotent_replays: true, dispute_withdrawals: true, ..Default::default() }, 3);
+    }
+
+    #[test]
+    fn test_router_memory_is_bounded_by_dispute_window() {
+        let config = EngineConfig { dispute_window: Some(50), ..Default::default() };
+        let mut sequential = TransactionEngine::new(config.clone());
+        let expected_report = sequential.load_transactions(workload(20_000).into_iter());
+        let mut sharded = ShardedEngine::new(config, 4);
+        // Coalesces the ranges of different shards, which the router must then ask about
+        sharded.max_expired_claims = 64;
+        let report = sharded.load_transactions(workload(20_000).into_iter());
+
+        assert_eq!(report, expected_report);
+        assert!(sharded.recent_claims.len() <= 50);
+        assert!(sharded.claims.len() <= 50);
+        assert!(sharded.transfer_sources.len() <= 50);
+        assert!(sharded.expired_claims.range_count() <= 64);
+        let merged = sharded.into_engine();
+        for tx in 1..=20_000 {
+            assert_eq!(merged.transaction(tx), sequential.transaction(tx));
+        }
+    }
+}
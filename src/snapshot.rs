@@ -0,0 +1,341 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use crate::amount::Amount;
+use crate::currency::Currency;
+
+/// Version of the snapshot format written by this build.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// First bytes of every snapshot.
+const MAGIC: [u8; 8] = *b"TXENGSNP";
+
+pub(crate) const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// Reasons why a snapshot cannot be restored.
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    /// The file does not start like a snapshot.
+    NotASnapshot,
+    /// The snapshot was written in a format this build does not read.
+    UnsupportedVersion(u32),
+    /// The snapshot is truncated, fails its checksum or holds an invalid value.
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(error) => write!(f, "failed to read snapshot: {}", error),
+            SnapshotError::NotASnapshot => f.write_str("not an engine snapshot"),
+            SnapshotError::UnsupportedVersion(version) => write!(f, "unsupported snapshot version {} (expected {})", version, SNAPSHOT_VERSION),
+            SnapshotError::Corrupt(reason) => write!(f, "corrupt snapshot: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(error: io::Error) -> Self {
+        if error.kind() == io::ErrorKind::UnexpectedEof {
+            SnapshotError::Corrupt("truncated")
+        } else {
+            SnapshotError::Io(error)
+        }
+    }
+}
+
+/// Writes the fixed-size little-endian fields of a snapshot, after its header, and keeps a
+/// checksum of everything written.
+pub(crate) struct SnapshotWriter<W: Write> {
+    writer: W,
+    hash: u64,
+}
+
+impl<W: Write> SnapshotWriter<W> {
+    pub(crate) fn new(writer: W) -> io::Result<Self> {
+        let mut snapshot = SnapshotWriter::fields(writer);
+        snapshot.bytes(&MAGIC)?;
+        snapshot.u32(SNAPSHOT_VERSION)?;
+        Ok(snapshot)
+    }
+
+    /// A writer of bare fields, without the snapshot header, for records of other files.
+    pub(crate) fn fields(writer: W) -> Self {
+        SnapshotWriter { writer, hash: FNV_OFFSET }
+    }
+
+    pub(crate) fn bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.hash = fnv1a(self.hash, bytes);
+        self.writer.write_all(bytes)
+    }
+
+    pub(crate) fn u8(&mut self, value: u8) -> io::Result<()> {
+        self.bytes(&[value])
+    }
+
+    pub(crate) fn u16(&mut self, value: u16) -> io::Result<()> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    pub(crate) fn u32(&mut self, value: u32) -> io::Result<()> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    pub(crate) fn u64(&mut self, value: u64) -> io::Result<()> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    /// A length or number of entries.
+    pub(crate) fn count(&mut self, count: usize) -> io::Result<()> {
+        self.u64(count as u64)
+    }
+
+    pub(crate) fn amount(&mut self, amount: Amount) -> io::Result<()> {
+        self.bytes(&amount.units().to_le_bytes())
+    }
+
+    pub(crate) fn currency(&mut self, currency: Currency) -> io::Result<()> {
+        self.bytes(&currency.to_bytes())
+    }
+
+    pub(crate) fn string(&mut self, text: &str) -> io::Result<()> {
+        self.count(text.len())?;
+        self.bytes(text.as_bytes())
+    }
+
+    /// Appends the checksum and flushes the snapshot.
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        let hash = self.hash;
+        self.writer.write_all(&hash.to_le_bytes())?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Reads the fields written by a [`SnapshotWriter`], checking the header first.
+pub(crate) struct SnapshotReader<R: Read> {
+    reader: R,
+    hash: u64,
+}
+
+impl<R: Read> SnapshotReader<R> {
+    pub(crate) fn new(reader: R) -> Result<Self, SnapshotError> {
+        let mut snapshot = SnapshotReader::fields(reader);
+        let magic: [u8; 8] = snapshot.array().map_err(|_| SnapshotError::NotASnapshot)?;
+        if magic != MAGIC {
+            return Err(SnapshotError::NotASnapshot);
+        }
+        let version = snapshot.u32()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        Ok(snapshot)
+    }
+
+    /// A reader of the bare fields written by [`SnapshotWriter::fields`].
+    pub(crate) fn fields(reader: R) -> Self {
+        SnapshotReader { reader, hash: FNV_OFFSET }
+    }
+
+    pub(crate) fn array<const N: usize>(&mut self) -> Result<[u8; N], SnapshotError> {
+        let mut bytes = [0; N];
+        self.reader.read_exact(&mut bytes)?;
+        self.hash = fnv1a(self.hash, &bytes);
+        Ok(bytes)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, SnapshotError> {
+        self.array().map(u16::from_le_bytes)
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, SnapshotError> {
+        self.array().map(u32::from_le_bytes)
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, SnapshotError> {
+        self.array().map(u64::from_le_bytes)
+    }
+
+    pub(crate) fn count(&mut self) -> Result<usize, SnapshotError> {
+        usize::try_from(self.u64()?).map_err(|_| SnapshotError::Corrupt("count out of range"))
+    }
+
+    pub(crate) fn amount(&mut self) -> Result<Amount, SnapshotError> {
+        self.array().map(|bytes| Amount::from_units(i64::from_le_bytes(bytes)))
+    }
+
+    pub(crate) fn currency(&mut self) -> Result<Currency, SnapshotError> {
+        Currency::from_bytes(self.array()?).map_err(|_| SnapshotError::Corrupt("invalid currency code"))
+    }
+
+    pub(crate) fn string(&mut self) -> Result<String, SnapshotError> {
+        let len = self.count()?;
+        let mut bytes = Vec::new();
+        (&mut self.reader).take(len as u64).read_to_end(&mut bytes)?;
+        if bytes.len() != len {
+            return Err(SnapshotError::Corrupt("truncated"));
+        }
+        self.hash = fnv1a(self.hash, &bytes);
+        String::from_utf8(bytes).map_err(|_| SnapshotError::Corrupt("invalid text"))
+    }
+
+    /// Checks the checksum, which must end the snapshot.
+    pub(crate) fn finish(self) -> Result<(), SnapshotError> {
+        let mut reader = self.verify()?;
+        if reader.read(&mut [0])? != 0 {
+            return Err(SnapshotError::Corrupt("trailing data"));
+        }
+        Ok(())
+    }
+
+    /// Checks the checksum that follows the fields read so far, and hands back the reader.
+    pub(crate) fn verify(mut self) -> Result<R, SnapshotError> {
+        let expected = self.hash;
+        let mut hash = [0; 8];
+        self.reader.read_exact(&mut hash)?;
+        if u64::from_le_bytes(hash) != expected {
+            return Err(SnapshotError::Corrupt("checksum mismatch"));
+        }
+        Ok(self.reader)
+    }
+}
+
+/// 64-bit FNV-1a hash of `bytes`, continuing from `hash`.
+pub(crate) fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| (hash ^ byte as u64).wrapping_mul(FNV_PRIME))
+}
+
+/// Writes a snapshot to `path` with `write`, through a temporary file renamed over `path` once
+/// complete, so a crash never leaves a partial snapshot behind.
+pub(crate) fn write_file(path: &Path, write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let mut writer = BufWriter::new(File::create(&temporary)?);
+    write(&mut writer)?;
+    writer.into_inner().map_err(|error| error.into_error())?.sync_all()?;
+    fs::rename(&temporary, path)
+}
+
+/// Opens the snapshot at `path` for reading.
+pub(crate) fn open_file(path: &Path) -> Result<BufReader<File>, SnapshotError> {
+    Ok(BufReader::new(File::open(path).map_err(SnapshotError::Io)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv_handler::{TransactionRaw, TransactionTypeRaw};
+    use crate::fees::{FeeKind, FeeSchedule};
+    use crate::test_util::row;
+    use crate::transaction_engine::{EngineConfig, TransactionEngine};
+    use crate::transaction_store::{CompactStore, MemoryStore};
+
+    fn config() -> EngineConfig {
+        let mut fees = FeeSchedule::new(99);
+        fees.rule_mut(FeeKind::Withdrawal).flat = "0.5".parse().unwrap();
+        EngineConfig { fees: Some(fees), dispute_window: Some(6), ..Default::default() }
+    }
+
+    /// Deposits, transfers, other assets, open and settled disputes, and an operator freeze.
+    fn rows() -> Vec<TransactionRaw> {
+        use TransactionTypeRaw::*;
+        vec![
+            row(Deposit, 1, 1, Some("100")),
+            row(Deposit, 2, 2, Some("50")),
+            TransactionRaw { currency: Some("BTC".parse().unwrap()), ..row(Deposit, 2, 3, Some("0.1")) },
+            TransactionRaw { to: Some(3), ..row(Transfer, 1, 4, Some("30")) },
+            row(Withdrawal, 2, 5, Some("10")),
+            row(Dispute, 1, 1, Some("20")),
+            row(Dispute, 3, 4, None),
+            row(Chargeback, 3, 4, None),
+            TransactionRaw { reason: Some("kyc review".to_string()), ..row(Freeze, 2, 6, None) },
+            row(Deposit, 4, 7, Some("5")),
+        ]
+    }
+
+    /// Rows applied after the snapshot, including disputes on expired and recent transactions.
+    fn next_rows() -> Vec<TransactionRaw> {
+        use TransactionTypeRaw::*;
+        vec![
+            row(Resolve, 1, 1, None),
+            row(Dispute, 2, 2, None),
+            row(Deposit, 4, 8, Some("1")),
+            row(Deposit, 4, 7, Some("5")),
+            row(Dispute, 4, 7, None),
+            TransactionRaw { reason: Some("cleared".to_string()), ..row(Unlock, 2, 9, None) },
+            row(Withdrawal, 2, 10, Some("1")),
+            row(Withdrawal, 1, 11, Some("1")),
+        ]
+    }
+
+    fn assert_same_state(restored: &TransactionEngine, original: &TransactionEngine) {
+        assert_eq!(restored.clients().collect::<Vec<_>>(), original.clients().collect::<Vec<_>>());
+        for tx in 1..=11 {
+            assert_eq!(restored.transaction(tx), original.transaction(tx));
+        }
+        assert_eq!(restored.audit_log().collect::<Vec<_>>(), original.audit_log().collect::<Vec<_>>());
+        assert_eq!(restored.fees().collect::<Vec<_>>(), original.fees().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_restore_continues_where_snapshot_left_off() {
+        let mut original = TransactionEngine::new(config());
+        original.load_transactions(rows().into_iter());
+        let mut bytes = Vec::new();
+        original.write_snapshot(&mut bytes).unwrap();
+
+        let mut restored = TransactionEngine::restore(config(), Box::new(CompactStore::default()), &bytes[..]).unwrap();
+        assert_same_state(&restored, &original);
+        for transaction in next_rows() {
+            assert_eq!(restored.apply(&transaction), original.apply(&transaction), "{:?}", transaction);
+        }
+        assert_same_state(&restored, &original);
+    }
+
+    #[test]
+    fn test_save_and_restore_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("engine.snapshot");
+        let mut original = TransactionEngine::new(config());
+        original.load_transactions(rows().into_iter());
+        original.save_snapshot(&path).unwrap();
+        // Saving again replaces the snapshot
+        original.load_transactions(next_rows().into_iter());
+        original.save_snapshot(&path).unwrap();
+
+        let restored = TransactionEngine::restore_file(config(), Box::new(MemoryStore::default()), &path).unwrap();
+        assert_same_state(&restored, &original);
+        assert_eq!(std::fs::read_dir(directory.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_invalid_snapshots() {
+        let mut engine = TransactionEngine::new(config());
+        engine.load_transactions(rows().into_iter());
+        let mut bytes = Vec::new();
+        engine.write_snapshot(&mut bytes).unwrap();
+        let restore = |bytes: &[u8]| TransactionEngine::restore(config(), Box::new(MemoryStore::default()), bytes).map(|_| ());
+
+        assert!(matches!(restore(b"type, client, tx, amount\n"), Err(SnapshotError::NotASnapshot)));
+        let mut newer = bytes.clone();
+        newer[8..12].copy_from_slice(&(SNAPSHOT_VERSION + 1).to_le_bytes());
+        assert!(matches!(restore(&newer), Err(SnapshotError::UnsupportedVersion(version)) if version == SNAPSHOT_VERSION + 1));
+        let mut flipped = bytes.clone();
+        flipped[40] ^= 1;
+        assert!(matches!(restore(&flipped), Err(SnapshotError::Corrupt(_))));
+        assert!(matches!(restore(&bytes[..bytes.len() - 3]), Err(SnapshotError::Corrupt("truncated"))));
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert!(matches!(restore(&longer), Err(SnapshotError::Corrupt("trailing data"))));
+        assert!(restore(&bytes).is_ok());
+    }
+}
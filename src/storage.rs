@@ -0,0 +1,260 @@
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+use crate::transaction_engine::{Transaction, TransactionID};
+use crate::transaction_store::TransactionStore;
+
+/// Tables of a [`StorageBackend`], each mapping 32-bit keys to values.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Table {
+    /// Asset accounts by client id.
+    Clients,
+    /// Transactions kept for disputes, by transaction id.
+    Transactions,
+    /// Administrative actions, by position in the audit log.
+    AuditLog,
+    /// Collected fees, by position in the fee records.
+    Fees,
+    /// The dispute window and the number of rows applied, under key 0.
+    Engine,
+}
+
+impl Table {
+    pub const ALL: [Table; 5] = [Table::Clients, Table::Transactions, Table::AuditLog, Table::Fees, Table::Engine];
+
+    /// The table stored as `byte`, `None` if there is no such table.
+    pub fn from_u8(byte: u8) -> Option<Table> {
+        Table::ALL.get(byte as usize).copied()
+    }
+}
+
+/// Persistent storage of the clients and transactions of a
+/// [`TransactionEngine`](crate::transaction_engine::TransactionEngine), see
+/// [`TransactionEngine::open`](crate::transaction_engine::TransactionEngine::open).
+///
+/// Changes are visible to reads as soon as they are made, and become durable together on
+/// [`StorageBackend::commit`]: after a crash, the storage must come back in the state of its
+/// last commit. Like the transaction stores, a backend panics on I/O errors.
+pub trait StorageBackend: fmt::Debug + Send {
+    /// The value stored under `key`.
+    fn get(&self, table: Table, key: u32) -> Option<Vec<u8>>;
+
+    /// Stores `value` under `key`, replacing any previous value.
+    fn put(&mut self, table: Table, key: u32, value: &[u8]);
+
+    fn remove(&mut self, table: Table, key: u32);
+
+    /// Number of keys in `table`.
+    fn len(&self, table: Table) -> usize;
+
+    /// Every key of `table`, in no particular order.
+    fn keys(&self, table: Table) -> Box<dyn Iterator<Item = u32> + '_>;
+
+    /// Makes every change since the last commit durable, all at once.
+    fn commit(&mut self);
+
+    /// Approximate number of bytes of memory the backend uses.
+    fn heap_bytes(&self) -> usize;
+}
+
+/// Reasons why a storage cannot be opened.
+#[derive(Debug)]
+pub enum StorageError {
+    Io(io::Error),
+    /// The file does not start like an engine storage file.
+    NotAStorage,
+    /// The storage was written in a format this build does not read.
+    UnsupportedVersion(u32),
+    /// A stored value is truncated, fails its checksum or is invalid.
+    Corrupt(&'static str),
+    /// The storage is already open in another process or handle.
+    Locked,
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Io(error) => write!(f, "failed to open storage: {}", error),
+            StorageError::NotAStorage => f.write_str("not an engine storage file"),
+            StorageError::UnsupportedVersion(version) => write!(f, "unsupported storage version {}", version),
+            StorageError::Corrupt(reason) => write!(f, "corrupt storage: {}", reason),
+            StorageError::Locked => f.write_str("storage is already open elsewhere"),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<io::Error> for StorageError {
+    fn from(error: io::Error) -> Self {
+        StorageError::Io(error)
+    }
+}
+
+impl From<SnapshotError> for StorageError {
+    fn from(error: SnapshotError) -> Self {
+        match error {
+            SnapshotError::Io(error) => StorageError::Io(error),
+            SnapshotError::NotASnapshot | SnapshotError::UnsupportedVersion(_) => StorageError::Corrupt("invalid value"),
+            SnapshotError::Corrupt(reason) => StorageError::Corrupt(reason),
+        }
+    }
+}
+
+/// A backend shared by an engine and its [`StoredTransactions`].
+#[derive(Debug, Clone)]
+pub(crate) struct SharedStorage(Arc<Mutex<Box<dyn StorageBackend>>>);
+
+impl SharedStorage {
+    pub(crate) fn new(backend: Box<dyn StorageBackend>) -> Self {
+        SharedStorage(Arc::new(Mutex::new(backend)))
+    }
+
+    pub(crate) fn lock(&self) -> MutexGuard<'_, Box<dyn StorageBackend>> {
+        self.0.lock().expect("a panic while using the storage is fatal")
+    }
+}
+
+/// Keeps the transactions in the [`Table::Transactions`] table of a storage backend, encoded
+/// with [`Transaction::encode`].
+#[derive(Debug)]
+pub(crate) struct StoredTransactions {
+    storage: SharedStorage,
+}
+
+impl StoredTransactions {
+    pub(crate) fn new(storage: SharedStorage) -> Self {
+        StoredTransactions { storage }
+    }
+}
+
+fn decode_transaction(bytes: &[u8]) -> Transaction {
+    bytes.try_into().ok()
+        .and_then(Transaction::decode)
+        .expect("the stored transactions are valid, the storage checks its values")
+}
+
+impl TransactionStore for StoredTransactions {
+    fn get(&self, transaction_id: TransactionID) -> Option<Transaction> {
+        self.storage.lock().get(Table::Transactions, transaction_id).map(|bytes| decode_transaction(&bytes))
+    }
+
+    fn put(&mut self, transaction_id: TransactionID, transaction: Transaction) {
+        self.storage.lock().put(Table::Transactions, transaction_id, &transaction.encode());
+    }
+
+    fn remove(&mut self, transaction_id: TransactionID) {
+        self.storage.lock().remove(Table::Transactions, transaction_id);
+    }
+
+    fn len(&self) -> usize {
+        self.storage.lock().len(Table::Transactions)
+    }
+
+    fn heap_bytes(&self) -> usize {
+        self.storage.lock().heap_bytes()
+    }
+
+    fn entries(&self) -> Box<dyn Iterator<Item = (TransactionID, Transaction)> + '_> {
+        let transaction_ids: Vec<_> = self.storage.lock().keys(Table::Transactions).collect();
+        Box::new(transaction_ids.into_iter().filter_map(|transaction_id| self.get(transaction_id).map(|transaction| (transaction_id, transaction))))
+    }
+
+    fn into_entries(self: Box<Self>) -> Box<dyn Iterator<Item = (TransactionID, Transaction)>> {
+        let transaction_ids: Vec<_> = self.storage.lock().keys(Table::Transactions).collect();
+        Box::new(transaction_ids.into_iter().filter_map(move |transaction_id| self.get(transaction_id).map(|transaction| (transaction_id, transaction))))
+    }
+}
+
+/// Encodes a value with the fields written by `write`, followed by their checksum.
+pub(crate) fn encode(write: impl FnOnce(&mut SnapshotWriter<&mut Vec<u8>>) -> io::Result<()>) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut writer = SnapshotWriter::fields(&mut bytes);
+    write(&mut writer).and_then(|_| writer.finish()).expect("writing to memory does not fail");
+    bytes
+}
+
+/// Decodes a value written by [`encode`] with `read`, checking that it holds nothing else.
+pub(crate) fn decode<'a, T>(bytes: &'a [u8], read: impl FnOnce(&mut SnapshotReader<&'a [u8]>) -> Result<T, SnapshotError>) -> Result<T, StorageError> {
+    let mut reader = SnapshotReader::fields(bytes);
+    let value = read(&mut reader)?;
+    reader.finish()?;
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::csv_handler::{TransactionRaw, TransactionTypeRaw};
+    use crate::fees::{FeeKind, FeeSchedule};
+    use crate::kv_store::KvStore;
+    use crate::test_util::{row, workload};
+    use crate::transaction_engine::{EngineConfig, TransactionEngine};
+
+    fn config() -> EngineConfig {
+        let mut fees = FeeSchedule::new(99);
+        fees.rule_mut(FeeKind::Withdrawal).flat = "0.5".parse().unwrap();
+        EngineConfig { fees: Some(fees), dispute_window: Some(6), ..Default::default() }
+    }
+
+    /// The first rows of the [`workload`] and an operator freeze, the rest of the workload is
+    /// applied to the reopened engine.
+    fn rows() -> (Vec<TransactionRaw>, Vec<TransactionRaw>) {
+        let mut rows = workload(600);
+        let next_rows = rows.split_off(400);
+        rows.push(TransactionRaw { reason: Some("kyc review".to_string()), ..row(TransactionTypeRaw::Freeze, 2, 1000, None) });
+        (rows, next_rows)
+    }
+
+    /// A batch that registers a client and is rolled back by its last row.
+    fn rolled_back_batch() -> Vec<TransactionRaw> {
+        use TransactionTypeRaw::*;
+        vec![row(Deposit, 20, 1001, Some("1")), row(Withdrawal, 1, 1002, Some("1000000"))]
+    }
+
+    fn assert_same_state(stored: &TransactionEngine, original: &TransactionEngine) {
+        assert_eq!(stored.clients().collect::<Vec<_>>(), original.clients().collect::<Vec<_>>());
+        for tx in 1..=1002 {
+            assert_eq!(stored.transaction(tx), original.transaction(tx));
+        }
+        assert_eq!(stored.audit_log().collect::<Vec<_>>(), original.audit_log().collect::<Vec<_>>());
+        assert_eq!(stored.fees().collect::<Vec<_>>(), original.fees().collect::<Vec<_>>());
+        assert_eq!(stored.position(), original.position());
+    }
+
+    fn open(path: &std::path::Path) -> TransactionEngine {
+        TransactionEngine::open(config(), Box::new(KvStore::open(path).unwrap())).unwrap()
+    }
+
+    #[test]
+    fn test_reopened_engine_continues_where_it_left_off() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("engine.kv");
+        let (rows, next_rows) = rows();
+        let mut original = TransactionEngine::new(config());
+        let mut stored = open(&path);
+        for engine in [&mut original, &mut stored] {
+            engine.load_transactions(rows.clone().into_iter());
+            assert!(engine.apply_batch(&rolled_back_batch()).is_err());
+            engine.commit();
+        }
+        drop(stored);
+        assert!(original.audit_log_len() > 0 && original.fees_len() > 0);
+
+        let mut stored = open(&path);
+        assert_same_state(&stored, &original);
+        // Changes that are not committed are lost with the engine
+        stored.apply(&row(TransactionTypeRaw::Deposit, 21, 1003, Some("1"))).unwrap();
+        drop(stored);
+
+        let mut stored = open(&path);
+        assert_same_state(&stored, &original);
+        for transaction in next_rows {
+            assert_eq!(stored.apply(&transaction), original.apply(&transaction), "{:?}", transaction);
+        }
+        stored.commit();
+        drop(stored);
+        assert_same_state(&open(&path), &original);
+    }
+}
@@ -0,0 +1,49 @@
+use crate::csv_handler::{TransactionRaw, TransactionTypeRaw};
+use crate::transaction_engine::{ClientID, TransactionID};
+
+/// A row of the unit tests: `value` is parsed as its amount, and it has no reason, destination
+/// or currency, which tests set with struct update syntax when they need them.
+pub(crate) fn row(transaction_type: TransactionTypeRaw, client: ClientID, tx: TransactionID, value: Option<&str>) -> TransactionRaw {
+    TransactionRaw {
+        transaction_type,
+        client,
+        tx,
+        amount: value.map(|value| value.parse().unwrap()),
+        reason: None,
+        to: None,
+        currency: None,
+    }
+}
+
+/// A deterministic mix of every kind of row over a few clients, some of them rejected, with
+/// frequent transfers, disputes by either party and reused transaction ids. The row at index `n`
+/// has transaction id `n + 1`, unless it refers to or reuses an earlier one.
+pub(crate) fn workload(rows: u32) -> Vec<TransactionRaw> {
+    let mut seed: u64 = 42;
+    let mut next = move |bound: u64| {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (seed >> 33) % bound
+    };
+    (1..=rows).map(|tx| {
+        let transaction_type = match next(12) {
+            0..=3 => TransactionTypeRaw::Deposit,
+            4 | 5 => TransactionTypeRaw::Withdrawal,
+            6 | 7 => TransactionTypeRaw::Transfer,
+            8 => TransactionTypeRaw::Dispute,
+            9 => TransactionTypeRaw::Resolve,
+            10 => TransactionTypeRaw::Chargeback,
+            _ => TransactionTypeRaw::Unlock,
+        };
+        let reference = matches!(transaction_type, TransactionTypeRaw::Dispute | TransactionTypeRaw::Resolve | TransactionTypeRaw::Chargeback);
+        TransactionRaw {
+            transaction_type,
+            client: next(10) as ClientID,
+            // Some new transactions reuse an earlier id
+            tx: if reference || next(20) == 0 { next(tx as u64) as TransactionID + 1 } else { tx },
+            amount: (!reference || next(4) == 0).then(|| format!("{}.{}", next(100), next(10)).parse().unwrap()),
+            reason: Some("reviewed".to_string()),
+            to: Some(next(10) as ClientID),
+            currency: (next(5) == 0).then(|| "EUR".parse().unwrap()),
+        }
+    }).collect()
+}
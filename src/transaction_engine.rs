@@ -1,360 +1,2439 @@
-use std::collections::HashMap;
-use std::collections::BTreeMap;
-use log::trace;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use log::{info, trace, warn};
+use crate::amount::Amount;
+use crate::client_table::ClientTable;
+use crate::currency::Currency;
+use crate::dispute_window::DisputeWindow;
 use crate::csv_handler::TransactionRaw;
 use crate::csv_handler::TransactionTypeRaw;
+use crate::error::{BatchError, TransactionError};
+use crate::events::{Event, EventKind, EventSink};
+use crate::fees::{FeeKind, FeeRecord, FeeSchedule};
+use crate::snapshot::{self, SnapshotError, SnapshotReader, SnapshotWriter};
+use crate::storage::{self, SharedStorage, StorageBackend, StorageError, StoredTransactions, Table};
+use crate::transaction_store::{MemoryStore, TransactionStore};
+use crate::write_ahead_log::{LogEntry, LogError, WriteAheadLog};
 
-type ClientID = u16;
-type TransactionID = u32;
+pub type ClientID = u16;
+pub type TransactionID = u32;
+
+/// Range of amounts, in ten-thousandths, that fit in a packed transaction.
+const PACKED_AMOUNT_MIN: i64 = -(1 << 45);
+const PACKED_AMOUNT_MAX: i64 = (1 << 45) - 1;
 
 #[repr(u8)]
-#[derive(Debug, PartialEq, Eq)]
-enum State {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
     Normal,
+    /// At least part of the transaction is held by an open dispute.
     Disputed,
+    /// At least part of the transaction was charged back and no dispute is open.
     ChargedBack
 }
 
-#[derive(Debug)]
-struct Transaction {
-    state: State,
-    amount: f64, // Negative if it's a withdrawal and positive if it's a deposit
+/// A deposit, withdrawal or transfer kept for later disputes, with its dispute state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transaction {
+    client: ClientID,
+    counterparty: Option<ClientID>, // Source client if this is a transfer, `client` being the destination
+    currency: Currency,
+    amount: Amount, // Negative if it's a withdrawal and positive if it's a deposit or transfer
+    disputed: Amount, // Portion currently held by open disputes
+    charged_back: Amount, // Portion already charged back
 }
 
-#[derive(Debug)]
+impl Transaction {
+    /// Size of a transaction encoded with [`Transaction::encode`].
+    pub const ENCODED_LEN: usize = 37;
+
+    fn new(client: ClientID, currency: Currency, amount: Amount) -> Self {
+        Transaction {
+            client,
+            counterparty: None,
+            currency,
+            amount,
+            disputed: Amount::ZERO,
+            charged_back: Amount::ZERO,
+        }
+    }
+
+    fn transfer(source: ClientID, destination: ClientID, currency: Currency, amount: Amount) -> Self {
+        Transaction {
+            counterparty: Some(source),
+            ..Transaction::new(destination, currency, amount)
+        }
+    }
+
+    /// True if `other` describes the same movement of funds, regardless of any later disputes.
+    #[inline]
+    fn is_replay_of(&self, other: &Transaction) -> bool {
+        self.client == other.client && self.counterparty == other.counterparty
+            && self.currency == other.currency && self.amount == other.amount
+    }
+
+    /// True if `client` is a party to this transaction and may dispute it.
+    #[inline]
+    fn involves(&self, client: ClientID) -> bool {
+        self.client == client || self.counterparty == Some(client)
+    }
+
+    /// The absolute value of the transaction.
+    #[inline]
+    fn value(&self) -> Amount {
+        if self.amount.is_negative() {
+            self.amount.checked_neg().expect("withdrawals are stored as negated positive amounts")
+        } else {
+            self.amount
+        }
+    }
+
+    /// The portion of the transaction that can still be disputed.
+    #[inline]
+    fn undisputed(&self) -> Amount {
+        self.value().checked_sub(self.disputed)
+            .and_then(|value| value.checked_sub(self.charged_back))
+            .expect("disputed portions never exceed the transaction value")
+    }
+
+    #[inline]
+    fn state(&self) -> State {
+        if self.disputed > Amount::ZERO {
+            State::Disputed
+        } else if self.charged_back > Amount::ZERO {
+            State::ChargedBack
+        } else {
+            State::Normal
+        }
+    }
+
+    /// Fixed-size little-endian encoding, for stores that keep transactions outside of memory.
+    pub fn encode(&self) -> [u8; Self::ENCODED_LEN] {
+        let mut bytes = [0; Self::ENCODED_LEN];
+        bytes[0..2].copy_from_slice(&self.client.to_le_bytes());
+        bytes[2] = self.counterparty.is_some() as u8;
+        bytes[3..5].copy_from_slice(&self.counterparty.unwrap_or_default().to_le_bytes());
+        bytes[5..13].copy_from_slice(&self.currency.to_bytes());
+        bytes[13..21].copy_from_slice(&self.amount.units().to_le_bytes());
+        bytes[21..29].copy_from_slice(&self.disputed.units().to_le_bytes());
+        bytes[29..37].copy_from_slice(&self.charged_back.units().to_le_bytes());
+        bytes
+    }
+
+    /// Packs a transaction of the default asset that is not a transfer, and is either untouched,
+    /// fully disputed or fully charged back, into a single word: the state in the low 2 bits,
+    /// then the client in 16 bits and the amount in the remaining 46 bits. `None` if it does not fit.
+    pub fn pack(&self) -> Option<u64> {
+        if self.counterparty.is_some() || self.currency != Currency::DEFAULT {
+            return None;
+        }
+        let value = self.value();
+        let state = match (self.disputed, self.charged_back) {
+            (Amount::ZERO, Amount::ZERO) => 0,
+            (disputed, Amount::ZERO) if disputed == value => 1,
+            (Amount::ZERO, charged_back) if charged_back == value => 2,
+            _ => return None,
+        };
+        let units = self.amount.units();
+        if !(PACKED_AMOUNT_MIN..=PACKED_AMOUNT_MAX).contains(&units) {
+            return None;
+        }
+        Some(state | (self.client as u64) << 2 | (units as u64) << 18)
+    }
+
+    /// Reads a transaction packed by [`Transaction::pack`].
+    pub fn unpack(word: u64) -> Transaction {
+        let mut transaction = Transaction::new((word >> 2) as ClientID, Currency::DEFAULT, Amount::from_units(word as i64 >> 18));
+        match word & 0b11 {
+            1 => transaction.disputed = transaction.value(),
+            2 => transaction.charged_back = transaction.value(),
+            _ => {},
+        }
+        transaction
+    }
+
+    /// Reads a transaction written by [`Transaction::encode`], `None` if the bytes are not a valid encoding.
+    pub fn decode(bytes: &[u8; Self::ENCODED_LEN]) -> Option<Transaction> {
+        let amount = |range: std::ops::Range<usize>| Amount::from_units(i64::from_le_bytes(bytes[range].try_into().expect("eight bytes")));
+        let counterparty = match bytes[2] {
+            0 => None,
+            1 => Some(ClientID::from_le_bytes([bytes[3], bytes[4]])),
+            _ => return None,
+        };
+        Some(Transaction {
+            client: ClientID::from_le_bytes([bytes[0], bytes[1]]),
+            counterparty,
+            currency: Currency::from_bytes(bytes[5..13].try_into().expect("eight bytes")).ok()?,
+            amount: amount(13..21),
+            disputed: amount(21..29),
+            charged_back: amount(29..37),
+        })
+    }
+}
+
+/// Picks the portion of a transaction targeted by a dispute, resolve or chargeback:
+/// the `requested` amount if given, otherwise everything up to `limit`.
+#[inline]
+fn portion(requested: Option<Amount>, limit: Amount, exceeded: TransactionError) -> Result<Amount, TransactionError> {
+    match requested {
+        None => Ok(limit),
+        Some(amount) if amount <= Amount::ZERO => Err(TransactionError::NonPositiveAmount),
+        Some(amount) if amount > limit => Err(exceeded),
+        Some(amount) => Ok(amount),
+    }
+}
+
+/// Read-only view of a stored transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransactionInfo {
+    pub client_id: ClientID,
+    /// Source client of a transfer, `client_id` being the destination.
+    pub counterparty: Option<ClientID>,
+    pub currency: Currency,
+    /// Negative for withdrawals.
+    pub amount: Amount,
+    pub state: State,
+    /// Portion currently held by open disputes.
+    pub disputed: Amount,
+    /// Portion already charged back.
+    pub charged_back: Amount,
+    /// Portion that can still be disputed.
+    pub undisputed: Amount,
+}
+
+/// What an accepted transaction did to the client balances.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The amount was credited to the available funds.
+    Deposited(Amount),
+    /// The amount was debited from the available funds.
+    Withdrawn(Amount),
+    /// The amount was moved from the available funds of one client to another.
+    Transferred(Amount),
+    /// The disputed amount was placed on hold.
+    Held(Amount),
+    /// The dispute was resolved and the held amount was released.
+    Released(Amount),
+    /// The disputed transaction was reversed and the account was locked.
+    ChargedBack(Amount),
+    /// The transaction is an exact replay of one already applied and was ignored.
+    Replayed,
+    /// The account was frozen by an operator.
+    Frozen,
+    /// The account was unlocked by an operator.
+    Unlocked,
+    /// The account was closed by an operator.
+    Closed,
+}
+
+/// Status of a client account in one asset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountStatus {
+    Active,
+    /// Frozen by a chargeback or by an operator, only administrative transactions are accepted.
+    Locked,
+    /// Closed by an operator, no further transactions are accepted.
+    Closed,
+}
+
+/// Administrative action performed by an operator on a client account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdminAction {
+    Freeze,
+    Unlock,
+    Close,
+}
+
+/// Audit record of an applied administrative action.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+    pub client_id: ClientID,
+    pub currency: Currency,
+    /// The `tx` of the administrative row, used as its reference.
+    pub tx: TransactionID,
+    pub action: AdminAction,
+    pub reason: String,
+}
+
+/// Tunable behaviour of the [`TransactionEngine`].
+#[derive(Debug, Clone, Default)]
+pub struct EngineConfig {
+    /// Treat a deposit or withdrawal that exactly repeats an already applied one
+    /// (same id, client, type and amount) as a no-op instead of rejecting it as a duplicate.
+    pub idempotent_replays: bool,
+    /// Allow withdrawals to be disputed. The reversed amount is credited to held funds,
+    /// a resolve drops the hold and a chargeback returns the funds to available.
+    pub dispute_withdrawals: bool,
+    /// Allow a deposit to be disputed even when it has already been spent. The available
+    /// balance goes negative and the shortfall is reported as the client's debt.
+    pub allow_negative_balance: bool,
+    /// Fees charged on deposits, withdrawals, transfers and chargebacks, none when unset.
+    pub fees: Option<FeeSchedule>,
+    /// Number of most recent rows whose deposits, withdrawals and transfers can still be disputed,
+    /// unlimited when unset. Older transactions are dropped from the store once their open disputes
+    /// are settled, and new disputes on them are rejected as expired. The window counts input rows,
+    /// not time: the input carries no timestamps, so a window of days must be converted into the
+    /// number of rows those days usually hold.
+    pub dispute_window: Option<u64>,
+}
+
+/// Balances and status of a client in a single asset.
+#[derive(Debug, Clone)]
 struct ClientFunds {
-    available: f64,
-    held: f64,
-    locked: bool,
-    transactions: BTreeMap<TransactionID, Transaction>
+    available: Amount,
+    held: Amount,
+    status: AccountStatus,
+    fees: Amount, // Fees paid so far
 }
 
 impl Default for ClientFunds {
     fn default() -> Self {
         ClientFunds {
-            available: 0.0,
-            held: 0.0,
-            locked: false,
-            transactions: BTreeMap::new()
+            available: Amount::ZERO,
+            held: Amount::ZERO,
+            status: AccountStatus::Active,
+            fees: Amount::ZERO,
         }
     }
 }
 
 impl ClientFunds {
+    /// Fails unless the account accepts client transactions.
     #[inline]
-    pub fn load_deposit(&mut self, amount: f64, transaction_id: u32) {
-        self.available += amount;
+    pub fn ensure_active(&self) -> Result<(), TransactionError> {
+        match self.status {
+            AccountStatus::Active => Ok(()),
+            AccountStatus::Locked => Err(TransactionError::AccountLocked),
+            AccountStatus::Closed => Err(TransactionError::AccountClosed),
+        }
+    }
 
-        self.transactions.insert(transaction_id, Transaction {
-            state: State::Normal,
-            amount
-        });
+    /// The amount the client owes after a dispute or chargeback drove the available balance negative.
+    #[inline]
+    pub fn debt(&self) -> Amount {
+        if self.available.is_negative() {
+            self.available.checked_neg().expect("available balance is bounded")
+        } else {
+            Amount::ZERO
+        }
     }
 
     #[inline]
-    pub fn load_withdrawal(&mut self, client_id: u16, amount: f64, transaction_id: u32) {
-        if self.available < amount {
-            trace!("Client {} has insufficient funds for withdrawal of amount {}. Available: {}", client_id, amount, self.available);
-            return;
+    pub fn load_deposit(&mut self, amount: Amount) -> Result<Outcome, TransactionError> {
+        if amount <= Amount::ZERO {
+            return Err(TransactionError::NonPositiveAmount);
         }
-        self.available -= amount;
+        // Keep `available + held` representable so the reported total can never overflow
+        let available = self.available.checked_add(amount)
+            .filter(|available| available.checked_add(self.held).is_some())
+            .ok_or(TransactionError::Overflow)?;
+        self.available = available;
+        Ok(Outcome::Deposited(amount))
+    }
 
-        self.transactions.insert(transaction_id, Transaction {
-            state: State::Normal,
-            amount: -amount
-        });
+    #[inline]
+    pub fn load_withdrawal(&mut self, amount: Amount) -> Result<Outcome, TransactionError> {
+        if amount <= Amount::ZERO {
+            return Err(TransactionError::NonPositiveAmount);
+        }
+        if self.available < amount {
+            return Err(TransactionError::InsufficientFunds);
+        }
+        self.available = self.available.checked_sub(amount).ok_or(TransactionError::Overflow)?;
+        Ok(Outcome::Withdrawn(amount))
     }
 
+    /// Disputes `amount` of a transaction, or all of its undisputed value when no amount is given.
+    /// A disputed deposit moves from available to held funds. A disputed withdrawal, when enabled
+    /// in `config`, has its reversed amount credited to held funds.
     #[inline]
-    pub fn load_dispute(&mut self, client_id: u16, ref_transaction_id: u32) {
-        if let Some(transaction) = self.transactions.get_mut(&ref_transaction_id) {
-            if transaction.state != State::Normal {
-                trace!("Transaction {} for client {} is not in a normal state and cannot be disputed.", ref_transaction_id, client_id);
-                return;
-            }
+    pub fn load_dispute(&mut self, transaction: &mut Transaction, amount: Option<Amount>, config: &EngineConfig) -> Result<Outcome, TransactionError> {
+        let is_withdrawal = transaction.amount.is_negative();
+        if is_withdrawal && !config.dispute_withdrawals {
+            return Err(TransactionError::WithdrawalNotDisputable);
+        }
+        let undisputed = transaction.undisputed();
+        if undisputed == Amount::ZERO {
+            return Err(TransactionError::InvalidState);
+        }
+        let amount = portion(amount, undisputed, TransactionError::ExceedsUndisputedAmount)?;
+        let disputed = transaction.disputed.checked_add(amount).ok_or(TransactionError::Overflow)?;
 
-            if transaction.amount < 0.0 {
-                trace!("Transaction {} for client {} is a withdrawal and cannot be disputed.", ref_transaction_id, client_id);
-                return;
-            }
-            
-            if transaction.amount > self.available {
-                trace!("Client {} has insufficient available funds to dispute transaction {}. Available: {}, Transaction Amount: {}", client_id, ref_transaction_id, self.available, transaction.amount);
-                return;
-            }
-            transaction.state = State::Disputed;
-            self.available -= transaction.amount;
-            self.held += transaction.amount;
+        if is_withdrawal {
+            let held = self.held.checked_add(amount)
+                .filter(|held| held.checked_add(self.available).is_some())
+                .ok_or(TransactionError::Overflow)?;
+            self.held = held;
         } else {
-            trace!("Transaction {} for client {} not found for dispute.", ref_transaction_id, client_id);
+            if amount > self.available && !config.allow_negative_balance {
+                return Err(TransactionError::InsufficientFunds);
+            }
+            let available = self.available.checked_sub(amount).ok_or(TransactionError::Overflow)?;
+            let held = self.held.checked_add(amount).ok_or(TransactionError::Overflow)?;
+            self.available = available;
+            self.held = held;
         }
+        transaction.disputed = disputed;
+        Ok(Outcome::Held(amount))
     }
 
+    /// Settles `amount` of the disputed portion, or all of it when no amount is given, in favour
+    /// of the client's original transaction: a disputed deposit goes back to available funds,
+    /// while the hold of a disputed withdrawal is dropped.
     #[inline]
-    pub fn load_resolve(&mut self, client_id: u16, ref_transaction_id: u32) {
-        if let Some(transaction) = self.transactions.get_mut(&ref_transaction_id) {
-            if transaction.state != State::Disputed {
-                trace!("Transaction {} for client {} is not in a disputed state and cannot be resolved.", ref_transaction_id, client_id);
-                return;
-            }
-            transaction.state = State::Normal;
-            self.available += transaction.amount;
-            self.held -= transaction.amount;
-        } else {
-            trace!("Transaction {} for client {} not found for resolve.", ref_transaction_id, client_id);
+    pub fn load_resolve(&mut self, transaction: &mut Transaction, amount: Option<Amount>) -> Result<Outcome, TransactionError> {
+        if transaction.disputed == Amount::ZERO {
+            return Err(TransactionError::InvalidState);
+        }
+        let amount = portion(amount, transaction.disputed, TransactionError::ExceedsDisputedAmount)?;
+        let held = self.held.checked_sub(amount).ok_or(TransactionError::Overflow)?;
+        if !transaction.amount.is_negative() {
+            self.available = self.available.checked_add(amount).ok_or(TransactionError::Overflow)?;
+        }
+        self.held = held;
+        transaction.disputed = transaction.disputed.checked_sub(amount).ok_or(TransactionError::Overflow)?;
+        Ok(Outcome::Released(amount))
+    }
+
+    /// Reverses `amount` of the disputed portion, or all of it when no amount is given, and locks
+    /// the account: a disputed deposit is removed from held funds, while a disputed withdrawal
+    /// is returned from held to available funds. A disputed transfer is removed from held funds
+    /// and returned to the available funds of its `source`.
+    #[inline]
+    pub fn load_chargeback(&mut self, transaction: &mut Transaction, amount: Option<Amount>, source: Option<&mut ClientFunds>) -> Result<Outcome, TransactionError> {
+        if transaction.disputed == Amount::ZERO {
+            return Err(TransactionError::InvalidState);
+        }
+        let amount = portion(amount, transaction.disputed, TransactionError::ExceedsDisputedAmount)?;
+        let held = self.held.checked_sub(amount).ok_or(TransactionError::Overflow)?;
+        if let Some(source) = source {
+            source.available = source.available.checked_add(amount)
+                .filter(|available| available.checked_add(source.held).is_some())
+                .ok_or(TransactionError::Overflow)?;
+        } else if transaction.amount.is_negative() {
+            self.available = self.available.checked_add(amount).ok_or(TransactionError::Overflow)?;
+        }
+        self.held = held;
+        self.status = AccountStatus::Locked;
+        transaction.disputed = transaction.disputed.checked_sub(amount).ok_or(TransactionError::Overflow)?;
+        transaction.charged_back = transaction.charged_back.checked_add(amount).ok_or(TransactionError::Overflow)?;
+        Ok(Outcome::ChargedBack(amount))
+    }
+
+    #[inline]
+    pub fn load_freeze(&mut self) -> Result<Outcome, TransactionError> {
+        if self.status != AccountStatus::Active {
+            return Err(TransactionError::InvalidAccountState);
+        }
+        self.status = AccountStatus::Locked;
+        Ok(Outcome::Frozen)
+    }
+
+    #[inline]
+    pub fn load_unlock(&mut self) -> Result<Outcome, TransactionError> {
+        if self.status != AccountStatus::Locked {
+            return Err(TransactionError::InvalidAccountState);
         }
+        self.status = AccountStatus::Active;
+        Ok(Outcome::Unlocked)
     }
 
+    /// Closes an active account for good. The account must be empty: funds still under dispute
+    /// must be settled, remaining funds paid out and debt recovered first, so closing never strands
+    /// funds or writes off debt. A locked account must be unlocked before it is closed.
     #[inline]
-    pub fn load_chargeback(&mut self, client_id: u16, ref_transaction_id: u32) {
-        if let Some(transaction) = self.transactions.get_mut(&ref_transaction_id) {
-            if transaction.state != State::Disputed {
-                trace!("Transaction {} for client {} is not in a disputed state and cannot be chargebacked.", ref_transaction_id, client_id);
-                return;
+    pub fn load_close(&mut self) -> Result<Outcome, TransactionError> {
+        if self.status != AccountStatus::Active || self.held != Amount::ZERO || self.available != Amount::ZERO {
+            return Err(TransactionError::InvalidAccountState);
+        }
+        self.status = AccountStatus::Closed;
+        Ok(Outcome::Closed)
+    }
+}
+
+/// All asset accounts of a client, in the order they were first used.
+///
+/// Clients rarely hold more than a handful of assets, so a vector beats a map here.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct ClientAccounts {
+    assets: Vec<(Currency, ClientFunds)>,
+}
+
+impl ClientAccounts {
+    /// The funds held in `currency`, opening the account on first use.
+    #[inline]
+    fn funds_mut(&mut self, currency: Currency) -> &mut ClientFunds {
+        let index = match self.assets.iter().position(|(asset, _)| *asset == currency) {
+            Some(index) => index,
+            None => {
+                self.assets.push((currency, ClientFunds::default()));
+                self.assets.len() - 1
             }
-            transaction.state = State::ChargedBack;
-            self.held -= transaction.amount;
-            self.locked = true;
-        } else {
-            trace!("Transaction {} for client {} not found for chargeback.", ref_transaction_id, client_id);
+        };
+        &mut self.assets[index].1
+    }
+
+    /// Adds the balances and fees of `other` to these accounts, asset by asset.
+    fn absorb(&mut self, other: ClientAccounts) {
+        for (currency, funds) in other.assets {
+            let merged = self.funds_mut(currency);
+            merged.available = merged.available.checked_add(funds.available).expect("merged balances stay in range");
+            merged.held = merged.held.checked_add(funds.held).expect("merged balances stay in range");
+            merged.fees = merged.fees.checked_add(funds.fees).expect("merged balances stay in range");
         }
     }
 }
 
-#[derive(Debug)]
-pub struct ClientInfo {
-    pub client_id: ClientID,
-    pub total: f64,
-    pub available: f64,
-    pub held: f64,
-    pub locked: bool
+/// The funds of `client_id` in `currency`, registering the client and opening the account on first use.
+#[inline]
+fn account_mut(clients: &mut ClientTable<ClientAccounts>, client_id: ClientID, currency: Currency) -> &mut ClientFunds {
+    clients.get_or_default(client_id).funds_mut(currency)
 }
 
-/// The transaction engine, responsible for processing transactions
-/// and maintaining client states and balances.
-#[derive(Debug, Default)]
-pub struct TransactionEngine {
-    clients: HashMap<ClientID, ClientFunds>,
+/// Moves `fee` from the available funds of `payer` to the `house` account. Either both balances
+/// change or neither does. Unless `allow_debt` is set, the payer must be able to cover the fee.
+fn collect_fee(clients: &mut ClientTable<ClientAccounts>, payer: ClientID, house: ClientID, currency: Currency, fee: Amount, allow_debt: bool) -> Result<(), TransactionError> {
+    clients.get_or_default(house);
+    let Some((payer, house)) = clients.get_pair_mut(payer, house) else {
+        unreachable!("the payer was registered and the house account never pays fees");
+    };
+    let (payer, house) = (payer.funds_mut(currency), house.funds_mut(currency));
+    if payer.available < fee && !allow_debt {
+        return Err(TransactionError::InsufficientFunds);
+    }
+    let available = payer.available.checked_sub(fee).ok_or(TransactionError::Overflow)?;
+    let fees = payer.fees.checked_add(fee).ok_or(TransactionError::Overflow)?;
+    let house_available = house.available.checked_add(fee)
+        .filter(|available| available.checked_add(house.held).is_some())
+        .ok_or(TransactionError::Overflow)?;
+    payer.available = available;
+    payer.fees = fees;
+    house.available = house_available;
+    Ok(())
 }
 
-impl TransactionEngine {
+impl ClientAccounts {
+    fn write_snapshot(&self, snapshot: &mut SnapshotWriter<impl Write>) -> io::Result<()> {
+        snapshot.count(self.assets.len())?;
+        for (currency, funds) in &self.assets {
+            snapshot.currency(*currency)?;
+            snapshot.amount(funds.available)?;
+            snapshot.amount(funds.held)?;
+            snapshot.u8(funds.status as u8)?;
+            snapshot.amount(funds.fees)?;
+        }
+        Ok(())
+    }
 
-    pub fn load_transactions(&mut self, transactions: impl Iterator<Item = TransactionRaw>) {
-        for transaction in transactions {
-            let client_funds = self.clients.entry(transaction.client).or_default();
-            if client_funds.locked {
-                trace!("Client {} is locked. Skipping transaction {}.", transaction.client, transaction.tx);
-                continue;
-            }
-            match transaction.transaction_type {
-                TransactionTypeRaw::Deposit => {
-                    if let Some(amount) = transaction.amount {
-                        client_funds.load_deposit(amount, transaction.tx);
-                    } else {
-                        trace!("Deposit transaction {} for client {} is missing an amount.", transaction.tx, transaction.client);
-                    }
-                },
-                TransactionTypeRaw::Withdrawal => {
-                    if let Some(amount) = transaction.amount {
-                        client_funds.load_withdrawal(transaction.client, amount, transaction.tx);
-                    } else {
-                        trace!("Withdrawal transaction {} for client {} is missing an amount.", transaction.tx, transaction.client);
-                    }
+    fn read_snapshot(snapshot: &mut SnapshotReader<impl Read>) -> Result<ClientAccounts, SnapshotError> {
+        let mut accounts = ClientAccounts::default();
+        for _ in 0..snapshot.count()? {
+            let currency = snapshot.currency()?;
+            let funds = ClientFunds {
+                available: snapshot.amount()?,
+                held: snapshot.amount()?,
+                status: match snapshot.u8()? {
+                    0 => AccountStatus::Active,
+                    1 => AccountStatus::Locked,
+                    2 => AccountStatus::Closed,
+                    _ => return Err(SnapshotError::Corrupt("invalid account status")),
                 },
-                TransactionTypeRaw::Dispute => {
-                    client_funds.load_dispute(transaction.client, transaction.tx);
-                },
-                TransactionTypeRaw::Resolve => {
-                    client_funds.load_resolve(transaction.client, transaction.tx);
-                },
-                TransactionTypeRaw::Chargeback => {
-                    client_funds.load_chargeback(transaction.client, transaction.tx);
-                },
-            }
+                fees: snapshot.amount()?,
+            };
+            accounts.assets.push((currency, funds));
         }
+        Ok(accounts)
     }
+}
 
-    pub fn clients(&self) -> impl Iterator<Item = ClientInfo> + '_ {
-        self.clients.iter().map(|(&client_id, funds)| ClientInfo {
+fn write_audit_entry(snapshot: &mut SnapshotWriter<impl Write>, entry: &AuditEntry) -> io::Result<()> {
+    snapshot.u16(entry.client_id)?;
+    snapshot.currency(entry.currency)?;
+    snapshot.u32(entry.tx)?;
+    snapshot.u8(entry.action as u8)?;
+    snapshot.string(&entry.reason)
+}
+
+fn read_audit_entry(snapshot: &mut SnapshotReader<impl Read>) -> Result<AuditEntry, SnapshotError> {
+    Ok(AuditEntry {
+        client_id: snapshot.u16()?,
+        currency: snapshot.currency()?,
+        tx: snapshot.u32()?,
+        action: match snapshot.u8()? {
+            0 => AdminAction::Freeze,
+            1 => AdminAction::Unlock,
+            2 => AdminAction::Close,
+            _ => return Err(SnapshotError::Corrupt("invalid administrative action")),
+        },
+        reason: snapshot.string()?,
+    })
+}
+
+fn write_fee_record(snapshot: &mut SnapshotWriter<impl Write>, record: &FeeRecord) -> io::Result<()> {
+    snapshot.u32(record.tx)?;
+    snapshot.u16(record.client_id)?;
+    snapshot.currency(record.currency)?;
+    snapshot.u8(record.kind as u8)?;
+    snapshot.amount(record.amount)
+}
+
+fn read_fee_record(snapshot: &mut SnapshotReader<impl Read>) -> Result<FeeRecord, SnapshotError> {
+    Ok(FeeRecord {
+        tx: snapshot.u32()?,
+        client_id: snapshot.u16()?,
+        currency: snapshot.currency()?,
+        kind: match snapshot.u8()? {
+            0 => FeeKind::Deposit,
+            1 => FeeKind::Withdrawal,
+            2 => FeeKind::Transfer,
+            3 => FeeKind::Chargeback,
+            _ => return Err(SnapshotError::Corrupt("invalid fee kind")),
+        },
+        amount: snapshot.amount()?,
+    })
+}
+
+/// Balances of a client in one asset.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub client_id: ClientID,
+    pub currency: Currency,
+    pub total: Amount,
+    pub available: Amount,
+    pub held: Amount,
+    /// Outstanding debt, non-zero only when the available balance is negative.
+    pub debt: Amount,
+    /// True unless the account is active.
+    pub locked: bool,
+    pub status: AccountStatus,
+    /// Fees paid by the client in this asset.
+    pub fees: Amount,
+}
+
+impl ClientInfo {
+    fn new(client_id: ClientID, currency: Currency, funds: &ClientFunds) -> Self {
+        ClientInfo {
             client_id,
+            currency,
             available: funds.available,
             held: funds.held,
-            total: funds.available + funds.held,
-            locked: funds.locked
-        })
+            total: funds.available.checked_add(funds.held).expect("deposits keep the total in range"),
+            debt: funds.debt(),
+            locked: funds.status != AccountStatus::Active,
+            status: funds.status,
+            fees: funds.fees,
+        }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Summary of a [`TransactionEngine::load_transactions`] run.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct LoadReport {
+    pub applied: usize,
+    pub rejected: BTreeMap<TransactionError, usize>,
+}
 
-    #[test]
-    fn test_dispute_valid() {
-        let mut client_funds = ClientFunds::default();
-        client_funds.load_deposit(100.0, 1);
-        client_funds.load_dispute(1, 1);
+impl LoadReport {
+    pub fn rejected_total(&self) -> usize {
+        self.rejected.values().sum()
+    }
 
-        assert_eq!(client_funds.available, 0.0);
-        assert_eq!(client_funds.held, 100.0);
-        assert_eq!(client_funds.locked, false);
+    /// Counts the result of applying `transaction`, logging it if it was rejected.
+    pub(crate) fn record(&mut self, transaction: &TransactionRaw, result: &Result<Outcome, TransactionError>) {
+        match result {
+            Ok(_) => self.applied += 1,
+            Err(error) => {
+                trace!("Rejected {:?} transaction {} for client {}: {}.", transaction.transaction_type, transaction.tx, transaction.client, error);
+                *self.rejected.entry(*error).or_default() += 1;
+            }
+        }
     }
 
-    #[test]
-    fn test_dispute_invalid_after_withdrawal() {
-        let mut client_funds = ClientFunds::default();
-        client_funds.load_deposit(100.0, 1);
-        client_funds.load_withdrawal(1, 50.0, 2);
-        client_funds.load_dispute(1, 1);
+    /// Adds the counts of `other` to this report.
+    pub fn merge(&mut self, other: LoadReport) {
+        self.applied += other.applied;
+        for (error, count) in other.rejected {
+            *self.rejected.entry(error).or_default() += count;
+        }
+    }
+}
+
+/// State changed by a batch, saved before the change so the batch can be rolled back.
+/// Entries are restored in reverse order, so the oldest one of a client or transaction wins.
+#[derive(Debug, Default)]
+struct Journal {
+    clients: Vec<(ClientID, Option<ClientAccounts>)>,
+    transactions: Vec<(TransactionID, Option<Transaction>)>,
+    audit_log_len: usize,
+    fees_len: usize,
+    /// Events of the batch, published once it commits.
+    events: Vec<Event>,
+}
+
+/// Storage an engine was opened on, and what changed since the last commit.
+#[derive(Debug)]
+struct Persistence {
+    storage: SharedStorage,
+    /// Clients whose accounts may have changed.
+    dirty_clients: BTreeSet<ClientID>,
+    /// Number of audit entries and fee records already stored.
+    audit_log_len: usize,
+    fees_len: usize,
+}
 
-        assert_eq!(client_funds.available, 50.0);
-        assert_eq!(client_funds.held, 0.0);
-        assert_eq!(client_funds.locked, false);
+impl Persistence {
+    /// The first `len` values of `table`, each read back from the storage as it is reached.
+    fn stored(&self, table: Table, len: usize) -> impl Iterator<Item = Vec<u8>> + '_ {
+        (0..len).map(move |index| self.storage.lock().get(table, index as u32).expect("committed entries are stored"))
     }
+}
 
-    #[test]
-    fn test_dispute_invalid_transaction() {
-        let mut client_funds = ClientFunds::default();
-        client_funds.load_deposit(100.0, 1);
-        client_funds.load_dispute(1, 2);
-        
-        assert_eq!(client_funds.available, 100.0);
-        assert_eq!(client_funds.held, 0.0);
-        assert_eq!(client_funds.locked, false);
+/// The clients whose accounts `transaction` may change: its client and destination, the house
+/// account, and the parties of the `stored` transaction it refers to.
+fn parties(transaction: &TransactionRaw, stored: Option<Transaction>, house: Option<ClientID>) -> impl Iterator<Item = ClientID> {
+    let owners = stored.map(|stored| [Some(stored.client), stored.counterparty]).unwrap_or_default();
+    [Some(transaction.client), transaction.to, house].into_iter().chain(owners).flatten()
+}
+
+/// The transaction engine, responsible for processing transactions
+/// and maintaining client states and balances.
+///
+/// Transaction ids are unique across all clients: once a deposit or withdrawal
+/// has been applied, its id cannot be used again by any client.
+///
+/// Each client holds independent balances and lock status per asset. Rows without
+/// a currency use the default asset, and disputes always act on the asset of the
+/// transaction they refer to.
+///
+/// With a [`FeeSchedule`] configured, fees are moved to the house account as each transaction
+/// is applied and recorded separately from the transactions they were charged on.
+///
+/// With a dispute window configured, the ids of expired transactions are still remembered, as
+/// ranges, so they keep being rejected as duplicates.
+///
+/// An engine built by [`TransactionEngine::recover`] logs every row to a [`WriteAheadLog`] before
+/// applying it, rejected rows included: they still register their client and move the dispute
+/// window, and replaying them rejects them again.
+///
+/// An [`EventSink`] set with [`TransactionEngine::set_event_sink`] receives the changes made by
+/// every row passed to [`TransactionEngine::apply`], [`TransactionEngine::load_transactions`] or
+/// [`TransactionEngine::apply_batch`]. A rolled back batch only publishes the rejection of the
+/// transaction that failed it, at the position of the first row of the batch. Each fee, recorded in
+/// [`TransactionEngine::fees`], is also published as a charge to the payer and a collection by the
+/// house account, after the events of the row it was charged on.
+/// Rows replayed from a write-ahead log are published again, so a sink set before
+/// [`TransactionEngine::attach_write_ahead_log`], such as an event log resumed at the position the
+/// engine was restored to, receives the events of every row once.
+///
+/// An engine opened with [`TransactionEngine::open`] keeps its transactions in a
+/// [`StorageBackend`], and writes the accounts it changed, its new audit entries and fees and its
+/// dispute window back to it on [`TransactionEngine::commit`], after which it only keeps the
+/// accounts in memory.
+#[derive(Debug)]
+pub struct TransactionEngine {
+    config: EngineConfig,
+    clients: ClientTable<ClientAccounts>,
+    transactions: Box<dyn TransactionStore>,
+    window: DisputeWindow,
+    audit_log: Vec<AuditEntry>,
+    fees: Vec<FeeRecord>,
+    /// Set while a batch is being applied.
+    journal: Option<Journal>,
+    log: Option<WriteAheadLog>,
+    events: Option<Box<dyn EventSink>>,
+    persistence: Option<Persistence>,
+}
+
+impl Default for TransactionEngine {
+    fn default() -> Self {
+        TransactionEngine::new(EngineConfig::default())
     }
+}
 
-    #[test]
-    fn test_dispute_invalid_state() {
-        let mut client_funds = ClientFunds::default();
-        client_funds.load_deposit(100.0, 1);
-        client_funds.load_dispute(1, 1);
-        client_funds.load_dispute(1, 1);
-        
-        assert_eq!(client_funds.available, 0.0);
-        assert_eq!(client_funds.held, 100.0);
-        assert_eq!(client_funds.locked, false);
+impl TransactionEngine {
+
+    /// An engine keeping its transactions in memory.
+    pub fn new(config: EngineConfig) -> Self {
+        TransactionEngine::with_store(config, Box::new(MemoryStore::default()))
     }
 
-    #[test]
-    fn test_resolve_valid() {
-        let mut client_funds = ClientFunds::default();
-        client_funds.load_deposit(100.0, 1);
-        client_funds.load_dispute(1, 1);
-        client_funds.load_resolve(1, 1);
+    /// An engine keeping its transactions in `store`.
+    pub fn with_store(config: EngineConfig, transactions: Box<dyn TransactionStore>) -> Self {
+        TransactionEngine {
+            config,
+            clients: ClientTable::default(),
+            transactions,
+            window: DisputeWindow::default(),
+            audit_log: Vec::new(),
+            fees: Vec::new(),
+            journal: None,
+            log: None,
+            events: None,
+            persistence: None,
+        }
+    }
 
-        assert_eq!(client_funds.available, 100.0);
-        assert_eq!(client_funds.held, 0.0);
-        assert_eq!(client_funds.locked, false);
+    /// Applies every transaction in order, logging and counting the rejected ones.
+    pub fn load_transactions(&mut self, transactions: impl Iterator<Item = TransactionRaw>) -> LoadReport {
+        let mut report = LoadReport::default();
+        for transaction in transactions {
+            let result = self.apply(&transaction);
+            report.record(&transaction, &result);
+        }
+        self.flush();
+        report
     }
 
-    #[test]
-    fn test_resolve_invalid_transaction() {
-        let mut client_funds = ClientFunds::default();
-        client_funds.load_deposit(100.0, 1);
-        client_funds.load_dispute(1, 1);
-        client_funds.load_resolve(1, 2);
-        
-        assert_eq!(client_funds.available, 0.0);
-        assert_eq!(client_funds.held, 100.0);
-        assert_eq!(client_funds.locked, false);
+    /// Makes everything applied so far durable: syncs the write-ahead log, writes out the events
+    /// and commits to the storage, whichever the engine has. [`Self::load_transactions`] flushes
+    /// once it is done, engines fed one row at a time flush when they see fit.
+    pub fn flush(&mut self) {
+        self.sync_log();
+        if let Some(events) = &mut self.events {
+            events.flush();
+        }
+        self.commit();
     }
 
-    #[test]
-    fn test_resolve_invalid_state() {
-        let mut client_funds = ClientFunds::default();
-        client_funds.load_deposit(100.0, 1);
-        client_funds.load_resolve(1, 1);
+    /// Applies a group of transactions as a whole, under a strict policy: if any of them is
+    /// rejected, every change made by the batch is rolled back, including the transactions
+    /// applied before the rejected one, and the engine is left as it was before the call.
+    /// Returns the outcome of each transaction, in order.
+    pub fn apply_batch(&mut self, transactions: &[TransactionRaw]) -> Result<Vec<Outcome>, BatchError> {
+        // A rolled back batch is replayed as a whole and rolled back again, so it is logged as a whole
+        if let Some(log) = &mut self.log {
+            log.append_batch(self.window.position + 1, transactions).expect("failed to write to the write-ahead log");
+        }
+        // Under group commit, a batch is still durable before it is applied
+        self.sync_log();
+        self.apply_journaled(transactions)
+    }
 
-        assert_eq!(client_funds.available, 100.0);
-        assert_eq!(client_funds.held, 0.0);
-        assert_eq!(client_funds.locked, false);
+    fn apply_journaled(&mut self, transactions: &[TransactionRaw]) -> Result<Vec<Outcome>, BatchError> {
+        self.journal = Some(Journal {
+            audit_log_len: self.audit_log.len(),
+            fees_len: self.fees.len(),
+            ..Default::default()
+        });
+        self.window.begin();
+        let mut outcomes = Vec::with_capacity(transactions.len());
+        for (index, transaction) in transactions.iter().enumerate() {
+            self.save_for_rollback(transaction);
+            match self.apply(transaction) {
+                Ok(outcome) => outcomes.push(outcome),
+                Err(error) => {
+                    self.rollback();
+                    // Reported at the position of the first row of the batch, which the next row takes over
+                    self.publish(self.window.position + 1, transaction, None, &Err(error), self.fees.len());
+                    warn!("Rolled back a batch of {} transactions, transaction {} was rejected: {}.", transactions.len(), index, error);
+                    return Err(BatchError { index, error });
+                }
+            }
+        }
+        let journal = self.journal.take().expect("the batch is still open");
+        self.window.commit();
+        if let Some(events) = &mut self.events {
+            journal.events.into_iter().for_each(|event| events.publish(event));
+        }
+        Ok(outcomes)
     }
 
-    #[test]
-    fn test_chargeback_valid() {
-        let mut client_funds = ClientFunds::default();
-        client_funds.load_deposit(100.0, 1);
-        client_funds.load_dispute(1, 1);
-        client_funds.load_chargeback(1, 1);
-        
-        assert_eq!(client_funds.available, 0.0);
-        assert_eq!(client_funds.held, 0.0);
-        assert_eq!(client_funds.locked, true);
+    /// Saves the accounts and the stored transaction `transaction` may change, while a batch is applied.
+    fn save_for_rollback(&mut self, transaction: &TransactionRaw) {
+        let Some(journal) = &mut self.journal else {
+            return;
+        };
+        let stored = self.transactions.get(transaction.tx);
+        let house = self.config.fees.as_ref().map(|fees| fees.house_account);
+        for client_id in parties(transaction, stored, house) {
+            journal.clients.push((client_id, self.clients.get(client_id).cloned()));
+        }
+        journal.transactions.push((transaction.tx, stored));
     }
 
-    #[test]
-    fn test_chargeback_invalid_transaction() {
-        let mut client_funds = ClientFunds::default();
-        client_funds.load_deposit(100.0, 1);
-        client_funds.load_dispute(1, 1);
-        client_funds.load_chargeback(1, 2);
+    /// Restores the state saved since the batch started.
+    fn rollback(&mut self) {
+        let journal = self.journal.take().expect("a rollback happens during a batch");
+        for (client_id, accounts) in journal.clients.into_iter().rev() {
+            match accounts {
+                Some(accounts) => { self.clients.insert(client_id, accounts); },
+                None => { self.clients.remove(client_id); },
+            }
+        }
+        for (transaction_id, transaction) in journal.transactions.into_iter().rev() {
+            match transaction {
+                Some(transaction) => self.transactions.put(transaction_id, transaction),
+                None => self.transactions.remove(transaction_id),
+            }
+        }
+        self.audit_log.truncate(journal.audit_log_len);
+        self.fees.truncate(journal.fees_len);
+        self.window.rollback();
+    }
 
-        assert_eq!(client_funds.available, 0.0);
-        assert_eq!(client_funds.held, 100.0);
-        assert_eq!(client_funds.locked, false);
+    /// Applies a single transaction and reports what it did, or why it was rejected.
+    pub fn apply(&mut self, transaction: &TransactionRaw) -> Result<Outcome, TransactionError> {
+        let position = self.window.position + 1;
+        if self.journal.is_none()
+            && let Some(log) = &mut self.log {
+            // A row the log does not hold must not be applied
+            log.append_row(position, transaction).expect("failed to write to the write-ahead log");
+        }
+        self.apply_unlogged(position, transaction)
     }
 
-    #[test]
-    fn test_chargeback_invalid_state() {
-        let mut client_funds = ClientFunds::default();
-        client_funds.load_deposit(100.0, 1);
-        client_funds.load_chargeback(1, 1);
+    /// Applies `transaction` at `position` like [`Self::apply`], without logging it.
+    fn apply_unlogged(&mut self, position: u64, transaction: &TransactionRaw) -> Result<Outcome, TransactionError> {
+        if self.events.is_none() && self.persistence.is_none() {
+            return self.apply_at(position, transaction);
+        }
+        let referenced = matches!(transaction.transaction_type, TransactionTypeRaw::Dispute | TransactionTypeRaw::Resolve | TransactionTypeRaw::Chargeback)
+            .then(|| self.transactions.get(transaction.tx))
+            .flatten();
+        if let Some(persistence) = &mut self.persistence {
+            let house = self.config.fees.as_ref().map(|fees| fees.house_account);
+            persistence.dirty_clients.extend(parties(transaction, referenced, house));
+        }
+        let fees_len = self.fees.len();
+        let result = self.apply_at(position, transaction);
+        self.publish(position, transaction, referenced, &result, fees_len);
+        result
+    }
 
-        assert_eq!(client_funds.available, 100.0);
-        assert_eq!(client_funds.held, 0.0);
-        assert_eq!(client_funds.locked, false);
+    /// Publishes the events of applying `transaction` at `position`, which refers to the `referenced`
+    /// transaction as it was before, followed by those of the fees it recorded from `fees_len` on.
+    /// During a batch the events wait in the journal until the batch commits.
+    fn publish(&mut self, position: u64, transaction: &TransactionRaw, referenced: Option<Transaction>, result: &Result<Outcome, TransactionError>, fees_len: usize) {
+        let event = |client_id, currency, kind| Event { position, client_id, currency, tx: transaction.tx, transaction_type: transaction.transaction_type, kind };
+        let (client_id, currency) = (transaction.client, transaction.currency.unwrap_or_default());
+        // Disputes, resolves and chargebacks act on the owner of the transaction, in its asset
+        let (owner, owner_currency) = referenced.map_or((client_id, currency), |referenced| (referenced.client, referenced.currency));
+        let reason = || transaction.reason.clone();
+        let events = match *result {
+            Err(error) => [Some(event(client_id, currency, EventKind::TransactionRejected(error))), None, None],
+            Ok(Outcome::Deposited(amount)) => [Some(event(client_id, currency, EventKind::FundsDeposited(amount))), None, None],
+            Ok(Outcome::Withdrawn(amount)) => [Some(event(client_id, currency, EventKind::FundsWithdrawn(amount))), None, None],
+            Ok(Outcome::Transferred(amount)) => [
+                Some(event(client_id, currency, EventKind::FundsWithdrawn(amount))),
+                transaction.to.map(|destination| event(destination, currency, EventKind::FundsDeposited(amount))),
+                None,
+            ],
+            Ok(Outcome::Held(amount)) => [Some(event(owner, owner_currency, EventKind::FundsHeld(amount))), None, None],
+            Ok(Outcome::Released(amount)) => [Some(event(owner, owner_currency, EventKind::FundsReleased(amount))), None, None],
+            Ok(Outcome::ChargedBack(amount)) => [
+                Some(event(owner, owner_currency, EventKind::ChargedBack(amount))),
+                // The source of a charged back transfer is credited back
+                referenced.and_then(|referenced| referenced.counterparty)
+                    .map(|source| event(source, owner_currency, EventKind::FundsDeposited(amount))),
+                Some(event(owner, owner_currency, EventKind::AccountLocked { reason: None })),
+            ],
+            Ok(Outcome::Frozen) => [Some(event(client_id, currency, EventKind::AccountLocked { reason: reason() })), None, None],
+            Ok(Outcome::Unlocked) => [Some(event(client_id, currency, EventKind::AccountUnlocked { reason: reason() })), None, None],
+            Ok(Outcome::Closed) => [Some(event(client_id, currency, EventKind::AccountClosed { reason: reason() })), None, None],
+            Ok(Outcome::Replayed) => [None, None, None],
+        };
+        let house = self.config.fees.as_ref().map(|fees| fees.house_account);
+        let fees = self.fees[fees_len..].iter().flat_map(|record| {
+            let house = house.expect("fees are only recorded with a fee schedule");
+            [event(record.client_id, record.currency, EventKind::FeeCharged(record.amount)), event(house, record.currency, EventKind::FeeCollected(record.amount))]
+        });
+        let events: Vec<_> = events.into_iter().flatten().chain(fees).collect();
+        for event in events {
+            match (&mut self.journal, &mut self.events) {
+                (Some(journal), _) => journal.events.push(event),
+                (None, Some(events)) => events.publish(event),
+                (None, None) => {},
+            }
+        }
     }
 
-    #[test]
-    fn test_locked_account_blocks_transactions() {
-        let mut engine = TransactionEngine::default();
-        
-        // Create transactions for client 1
-        let transactions = vec![
-            TransactionRaw {
-                transaction_type: TransactionTypeRaw::Deposit,
-                client: 1,
-                tx: 1,
-                amount: Some(100.0),
+    /// Applies `transaction` as the row at `position` of the input, the first row being 1.
+    /// Positions only move forward, they decide which transactions are still in the dispute window.
+    pub(crate) fn apply_at(&mut self, position: u64, transaction: &TransactionRaw) -> Result<Outcome, TransactionError> {
+        self.window.position = position;
+        self.expire_transactions();
+        if let Some(fees) = &self.config.fees
+            && (transaction.client == fees.house_account || transaction.to == Some(fees.house_account)) {
+            return Err(TransactionError::ReservedAccount);
+        }
+        // Every row makes its client known, even when it is rejected
+        self.clients.get_or_default(transaction.client);
+        let currency = transaction.currency.unwrap_or_default();
+        match transaction.transaction_type {
+            TransactionTypeRaw::Deposit => {
+                let client_id = transaction.client;
+                account_mut(&mut self.clients, client_id, currency).ensure_active()?;
+                let amount = transaction.amount.ok_or(TransactionError::MissingAmount)?;
+                self.apply_new_with_fee(FeeKind::Deposit, transaction.tx, Transaction::new(client_id, currency, amount), |clients| {
+                    account_mut(clients, client_id, currency).load_deposit(amount)
+                })
             },
-            TransactionRaw {
-                transaction_type: TransactionTypeRaw::Dispute,
-                client: 1,
-                tx: 1,
-                amount: None,
+            TransactionTypeRaw::Withdrawal => {
+                let client_id = transaction.client;
+                account_mut(&mut self.clients, client_id, currency).ensure_active()?;
+                let amount = transaction.amount.ok_or(TransactionError::MissingAmount)?;
+                let signed = amount.checked_neg().ok_or(TransactionError::Overflow)?;
+                self.apply_new_with_fee(FeeKind::Withdrawal, transaction.tx, Transaction::new(client_id, currency, signed), |clients| {
+                    account_mut(clients, client_id, currency).load_withdrawal(amount)
+                })
             },
-            TransactionRaw {
-                transaction_type: TransactionTypeRaw::Chargeback,
-                client: 1,
-                tx: 1,
-                amount: None,
+            TransactionTypeRaw::Transfer => {
+                account_mut(&mut self.clients, transaction.client, currency).ensure_active()?;
+                let amount = transaction.amount.ok_or(TransactionError::MissingAmount)?;
+                let destination = transaction.to.ok_or(TransactionError::MissingDestination)?;
+                self.apply_transfer(transaction.client, destination, currency, transaction.tx, amount)
             },
-            // These should be blocked because account is locked
-            TransactionRaw {
-                transaction_type: TransactionTypeRaw::Deposit,
-                client: 1,
-                tx: 2,
-                amount: Some(50.0),
+            TransactionTypeRaw::Dispute => {
+                let amount = transaction.amount;
+                // A transaction kept for its open dispute can still be resolved or charged back, but not disputed again
+                if self.window.is_expired(transaction.tx) {
+                    return Err(self.missing_reference(transaction.client, transaction.tx));
+                }
+                self.apply_reference(transaction.client, transaction.tx, |funds, _, transaction, config| funds.load_dispute(transaction, amount, config))
             },
-            TransactionRaw {
-                transaction_type: TransactionTypeRaw::Withdrawal,
-                client: 1,
-                tx: 3,
-                amount: Some(25.0),
+            TransactionTypeRaw::Resolve => {
+                let amount = transaction.amount;
+                self.apply_reference(transaction.client, transaction.tx, |funds, _, transaction, _| funds.load_resolve(transaction, amount))
             },
-        ];
-        
-        engine.load_transactions(transactions.into_iter());
-        
-        // Get client info
-        let client_info: Vec<_> = engine.clients().collect();
-        assert_eq!(client_info.len(), 1);
-        
-        let client = &client_info[0];
-        assert_eq!(client.client_id, 1);
-        assert_eq!(client.available, 0.0); // Should remain 0 after chargeback
-        assert_eq!(client.held, 0.0);
-        assert_eq!(client.total, 0.0);
-        assert_eq!(client.locked, true);
+            TransactionTypeRaw::Chargeback => {
+                let amount = transaction.amount;
+                let charged = self.transactions.get(transaction.tx);
+                let outcome = self.apply_reference(transaction.client, transaction.tx, |funds, source, transaction, _| funds.load_chargeback(transaction, amount, source))?;
+                if let (Outcome::ChargedBack(charged_back), Some(charged)) = (outcome, charged) {
+                    self.charge_penalty(transaction.tx, charged.client, charged.currency, charged_back);
+                }
+                Ok(outcome)
+            },
+            TransactionTypeRaw::Freeze => self.apply_administrative(transaction, currency, AdminAction::Freeze),
+            TransactionTypeRaw::Unlock => self.apply_administrative(transaction, currency, AdminAction::Unlock),
+            TransactionTypeRaw::Close => self.apply_administrative(transaction, currency, AdminAction::Close),
+        }
+    }
+
+    /// Applies an operator action on a client account and records it in the audit log.
+    /// The `tx` of an administrative row is only used as the audit reference, it does not claim a transaction id.
+    fn apply_administrative(&mut self, transaction: &TransactionRaw, currency: Currency, action: AdminAction) -> Result<Outcome, TransactionError> {
+        let client_funds = account_mut(&mut self.clients, transaction.client, currency);
+        if client_funds.status == AccountStatus::Closed {
+            return Err(TransactionError::AccountClosed);
+        }
+        let reason = transaction.reason.as_deref()
+            .filter(|reason| !reason.is_empty())
+            .ok_or(TransactionError::MissingReason)?;
+        let outcome = match action {
+            AdminAction::Freeze => client_funds.load_freeze(),
+            AdminAction::Unlock => client_funds.load_unlock(),
+            AdminAction::Close => client_funds.load_close(),
+        }?;
+        info!("Operator {:?} of client {} in {:?} (tx {}): {}", action, transaction.client, currency, transaction.tx, reason);
+        self.audit_log.push(AuditEntry {
+            client_id: transaction.client,
+            currency,
+            tx: transaction.tx,
+            action,
+            reason: reason.to_string(),
+        });
+        Ok(outcome)
+    }
+
+    /// Looks up a stored deposit or withdrawal, including how much of it is disputed.
+    pub fn transaction(&self, transaction_id: TransactionID) -> Option<TransactionInfo> {
+        self.transactions.get(transaction_id).map(|transaction| TransactionInfo {
+            client_id: transaction.client,
+            counterparty: transaction.counterparty,
+            currency: transaction.currency,
+            amount: transaction.amount,
+            state: transaction.state(),
+            disputed: transaction.disputed,
+            charged_back: transaction.charged_back,
+            undisputed: transaction.undisputed(),
+        })
+    }
+
+    /// Publishes the changes made by every row applied from now on to `events`, replacing any previous sink.
+    pub fn set_event_sink(&mut self, events: Box<dyn EventSink>) {
+        self.events = Some(events);
+    }
+
+    /// Stops publishing events and hands back the sink.
+    pub fn take_event_sink(&mut self) -> Option<Box<dyn EventSink>> {
+        self.events.take()
+    }
+
+    /// The store holding the transactions kept for disputes.
+    pub fn transaction_store(&self) -> &dyn TransactionStore {
+        self.transactions.as_ref()
+    }
+
+    /// Administrative actions applied so far, in order. An engine opened on storage reads those
+    /// it committed back from the storage.
+    pub fn audit_log(&self) -> impl Iterator<Item = AuditEntry> + '_ {
+        let stored = self.persistence.iter()
+            .flat_map(|persistence| persistence.stored(Table::AuditLog, persistence.audit_log_len))
+            .map(|value| storage::decode(&value, read_audit_entry).expect("committed audit entries are valid"));
+        stored.chain(self.audit_log.iter().cloned())
+    }
+
+    /// Number of administrative actions applied so far.
+    pub fn audit_log_len(&self) -> usize {
+        self.persistence.as_ref().map_or(0, |persistence| persistence.audit_log_len) + self.audit_log.len()
+    }
+
+    /// Fees collected so far, in order. An engine opened on storage reads those it committed back
+    /// from the storage.
+    pub fn fees(&self) -> impl Iterator<Item = FeeRecord> + '_ {
+        let stored = self.persistence.iter()
+            .flat_map(|persistence| persistence.stored(Table::Fees, persistence.fees_len))
+            .map(|value| storage::decode(&value, read_fee_record).expect("committed fee records are valid"));
+        stored.chain(self.fees.iter().cloned())
+    }
+
+    /// Number of fees collected so far.
+    pub fn fees_len(&self) -> usize {
+        self.persistence.as_ref().map_or(0, |persistence| persistence.fees_len) + self.fees.len()
+    }
+
+    /// True if a fee schedule is configured.
+    pub fn charges_fees(&self) -> bool {
+        self.config.fees.is_some()
+    }
+
+    /// The house account and the fee of `kind` on a transaction of `value`, if any fee is due.
+    fn fee_for(&self, kind: FeeKind, value: Amount) -> Result<Option<(ClientID, Amount)>, TransactionError> {
+        let Some(schedule) = &self.config.fees else {
+            return Ok(None);
+        };
+        let fee = schedule.rule(kind).fee(value).ok_or(TransactionError::Overflow)?;
+        Ok((fee > Amount::ZERO).then_some((schedule.house_account, fee)))
     }
-}
\ No newline at end of file
+
+    /// Applies a transaction through [`Self::apply_new`] and charges the fee of `kind` to its payer,
+    /// the source of a transfer or the client otherwise. If the payer cannot cover the fee, the
+    /// balances changed by `operation` are restored and the transaction is rejected.
+    fn apply_new_with_fee(
+        &mut self,
+        kind: FeeKind,
+        transaction_id: TransactionID,
+        record: Transaction,
+        operation: impl FnOnce(&mut ClientTable<ClientAccounts>) -> Result<Outcome, TransactionError>,
+    ) -> Result<Outcome, TransactionError> {
+        let Some((house, fee)) = self.fee_for(kind, record.value())? else {
+            return self.apply_new(transaction_id, record, operation);
+        };
+        let currency = record.currency;
+        let payer = record.counterparty.unwrap_or(record.client);
+        let parties = [payer, record.client];
+        let outcome = self.apply_new(transaction_id, record, |clients| {
+            let snapshot = parties.map(|client_id| account_mut(clients, client_id, currency).clone());
+            let outcome = operation(clients)?;
+            if let Err(error) = collect_fee(clients, payer, house, currency, fee, false) {
+                for (client_id, funds) in parties.into_iter().zip(snapshot) {
+                    *account_mut(clients, client_id, currency) = funds;
+                }
+                return Err(error);
+            }
+            Ok(outcome)
+        })?;
+        if outcome != Outcome::Replayed {
+            self.fees.push(FeeRecord { tx: transaction_id, client_id: payer, currency, kind, amount: fee });
+        }
+        Ok(outcome)
+    }
+
+    /// Charges the chargeback penalty on `amount` to the `owner` of the charged back transaction.
+    /// The chargeback itself has already been applied, so the penalty may leave the owner in debt
+    /// when negative balances are allowed. Otherwise only what the available funds cover is collected.
+    fn charge_penalty(&mut self, transaction_id: TransactionID, owner: ClientID, currency: Currency, amount: Amount) {
+        let allow_debt = self.config.allow_negative_balance;
+        let collected = self.fee_for(FeeKind::Chargeback, amount).and_then(|charge| match charge {
+            Some((house, fee)) => {
+                let fee = if allow_debt { fee } else { fee.min(account_mut(&mut self.clients, owner, currency).available.max(Amount::ZERO)) };
+                if fee == Amount::ZERO {
+                    return Ok(None);
+                }
+                collect_fee(&mut self.clients, owner, house, currency, fee, allow_debt).map(|_| Some(fee))
+            },
+            None => Ok(None),
+        });
+        match collected {
+            Ok(Some(fee)) => self.fees.push(FeeRecord { tx: transaction_id, client_id: owner, currency, kind: FeeKind::Chargeback, amount: fee }),
+            Ok(None) => {},
+            Err(error) => warn!("Failed to charge the chargeback penalty on transaction {} to client {}: {}.", transaction_id, owner, error),
+        }
+    }
+
+    /// Applies a transaction that creates a new `record`, enforcing global id uniqueness.
+    fn apply_new(
+        &mut self,
+        transaction_id: TransactionID,
+        record: Transaction,
+        operation: impl FnOnce(&mut ClientTable<ClientAccounts>) -> Result<Outcome, TransactionError>,
+    ) -> Result<Outcome, TransactionError> {
+        if let Some(existing) = self.transactions.get(transaction_id) {
+            return if existing.is_replay_of(&record) && self.config.idempotent_replays {
+                Ok(Outcome::Replayed)
+            } else {
+                Err(TransactionError::DuplicateTransaction)
+            };
+        }
+        // An expired transaction can no longer be compared, even an exact replay is a duplicate
+        if self.window.is_expired(transaction_id) {
+            return Err(TransactionError::DuplicateTransaction);
+        }
+
+        let outcome = operation(&mut self.clients)?;
+        if self.config.dispute_window.is_some() {
+            self.window.record(transaction_id, [record.client, record.counterparty.unwrap_or(record.client)]);
+        }
+        self.transactions.put(transaction_id, record);
+        Ok(outcome)
+    }
+
+    /// Drops the transactions that fell out of the dispute window. A transaction with an open
+    /// dispute is kept until the dispute is resolved or charged back.
+    fn expire_transactions(&mut self) {
+        let Some(window) = self.config.dispute_window else {
+            return;
+        };
+        while let Some(transaction_id) = self.window.pop_expired(window) {
+            if let Some(transaction) = self.transactions.get(transaction_id)
+                && transaction.disputed == Amount::ZERO {
+                if let Some(journal) = &mut self.journal {
+                    journal.transactions.push((transaction_id, Some(transaction)));
+                }
+                self.transactions.remove(transaction_id);
+            }
+        }
+    }
+
+    /// True if `transaction_id` was used by a deposit, withdrawal or transfer, even an expired one.
+    pub(crate) fn is_claimed(&self, transaction_id: TransactionID) -> bool {
+        self.transactions.get(transaction_id).is_some() || self.window.is_expired(transaction_id)
+    }
+
+    /// Moves `amount` from `source` to `destination`. Either both balances change or neither does.
+    fn apply_transfer(&mut self, source: ClientID, destination: ClientID, currency: Currency, transaction_id: TransactionID, amount: Amount) -> Result<Outcome, TransactionError> {
+        if source == destination {
+            return Err(TransactionError::InvalidDestination);
+        }
+        account_mut(&mut self.clients, destination, currency).ensure_active()?;
+        self.apply_new_with_fee(FeeKind::Transfer, transaction_id, Transaction::transfer(source, destination, currency, amount), |clients| {
+            let Some((from, to)) = clients.get_pair_mut(source, destination) else {
+                unreachable!("both clients were registered before the transfer");
+            };
+            let (from, to) = (from.funds_mut(currency), to.funds_mut(currency));
+            let available_before = from.available;
+            from.load_withdrawal(amount)?;
+            if let Err(error) = to.load_deposit(amount) {
+                from.available = available_before;
+                return Err(error);
+            }
+            Ok(Outcome::Transferred(amount))
+        })
+    }
+
+    /// Applies a transaction that refers to an earlier one the client is a party to. The operation
+    /// acts on the funds of the transaction owner in the asset of the transaction and, for transfers,
+    /// may also use the source funds.
+    fn apply_reference(
+        &mut self,
+        client_id: ClientID,
+        ref_transaction_id: TransactionID,
+        operation: impl FnOnce(&mut ClientFunds, Option<&mut ClientFunds>, &mut Transaction, &EngineConfig) -> Result<Outcome, TransactionError>,
+    ) -> Result<Outcome, TransactionError> {
+        let Some(mut transaction) = self.transactions.get(ref_transaction_id)
+            .filter(|transaction| transaction.involves(client_id)) else {
+            return Err(self.missing_reference(client_id, ref_transaction_id));
+        };
+        let currency = transaction.currency;
+        account_mut(&mut self.clients, client_id, currency).ensure_active()?;
+        let outcome = match transaction.counterparty {
+            None => operation(account_mut(&mut self.clients, transaction.client, currency), None, &mut transaction, &self.config)?,
+            Some(source) => {
+                let Some((owner, source)) = self.clients.get_pair_mut(transaction.client, source) else {
+                    unreachable!("both parties of a transfer exist");
+                };
+                let owner = owner.funds_mut(currency);
+                owner.ensure_active()?;
+                operation(owner, Some(source.funds_mut(currency)), &mut transaction, &self.config)?
+            }
+        };
+        if self.window.is_expired(ref_transaction_id) && transaction.disputed == Amount::ZERO {
+            // The last open dispute on an expired transaction is settled
+            self.transactions.remove(ref_transaction_id);
+        } else {
+            self.transactions.put(ref_transaction_id, transaction);
+        }
+        Ok(outcome)
+    }
+
+    /// Why `client_id` cannot refer to `transaction_id`: the transaction expired if the client was
+    /// known to be a party to it, and is unknown otherwise, so that clients learn nothing about
+    /// the transaction ids of others.
+    fn missing_reference(&self, client_id: ClientID, transaction_id: TransactionID) -> TransactionError {
+        if self.window.is_expired_for(transaction_id, client_id) {
+            TransactionError::ExpiredTransaction
+        } else {
+            TransactionError::UnknownTransaction
+        }
+    }
+
+    /// Removes a client and all its asset accounts, so another engine can act on them.
+    pub(crate) fn take_client(&mut self, client_id: ClientID) -> Option<ClientAccounts> {
+        self.clients.remove(client_id)
+    }
+
+    /// Puts back the accounts of a client taken with [`Self::take_client`].
+    pub(crate) fn put_client(&mut self, client_id: ClientID, accounts: ClientAccounts) {
+        self.clients.insert(client_id, accounts);
+    }
+
+    /// Combines engines that processed disjoint sets of clients and transaction ids into one.
+    /// The house account is the only client several engines may know, its balances are added up.
+    /// Each engine comes with the sequence numbers of its audit entries and fee records, which
+    /// restore their global order.
+    /// The transactions are moved to the store of the first engine, and those that fell out of
+    /// the dispute window after the last row of their engine are dropped.
+    pub(crate) fn merge(config: EngineConfig, engines: Vec<(TransactionEngine, Vec<u64>, Vec<u64>)>) -> TransactionEngine {
+        let mut engines = engines.into_iter();
+        let (first, first_audit_sequence, first_fee_sequence) = engines.next().expect("at least one engine to merge");
+        let mut merged = TransactionEngine::with_store(config, first.transactions);
+        merged.clients = first.clients;
+        let mut windows = vec![first.window];
+        let mut audit_log: Vec<_> = first_audit_sequence.into_iter().zip(first.audit_log).collect();
+        let mut fees: Vec<_> = first_fee_sequence.into_iter().zip(first.fees).collect();
+        for (engine, audit_sequence, fee_sequence) in engines {
+            for (client_id, accounts) in engine.clients {
+                match merged.clients.get_mut(client_id) {
+                    Some(merged_accounts) => merged_accounts.absorb(accounts),
+                    None => { merged.clients.insert(client_id, accounts); },
+                }
+            }
+            for (transaction_id, transaction) in engine.transactions.into_entries() {
+                merged.transactions.put(transaction_id, transaction);
+            }
+            windows.push(engine.window);
+            audit_log.extend(audit_sequence.into_iter().zip(engine.audit_log));
+            fees.extend(fee_sequence.into_iter().zip(engine.fees));
+        }
+        audit_log.sort_by_key(|(sequence, _)| *sequence);
+        fees.sort_by_key(|(sequence, _)| *sequence);
+        merged.audit_log = audit_log.into_iter().map(|(_, entry)| entry).collect();
+        merged.fees = fees.into_iter().map(|(_, record)| record).collect();
+        merged.window = DisputeWindow::merge(windows.into_iter());
+        merged.expire_transactions();
+        merged
+    }
+
+    /// Writes the full state of the engine as a snapshot: the balances and status of every client
+    /// account, the stored transactions with their dispute state, the dispute window, the audit log
+    /// and the fees collected. The configuration is not part of the snapshot.
+    pub fn write_snapshot(&self, writer: impl Write) -> io::Result<()> {
+        let mut snapshot = SnapshotWriter::new(writer)?;
+        snapshot.count(self.clients.len())?;
+        for (client_id, accounts) in self.clients.iter() {
+            snapshot.u16(client_id)?;
+            accounts.write_snapshot(&mut snapshot)?;
+        }
+        snapshot.count(self.transactions.len())?;
+        for (transaction_id, transaction) in self.transactions.entries() {
+            snapshot.u32(transaction_id)?;
+            snapshot.bytes(&transaction.encode())?;
+        }
+        self.window.write_snapshot(&mut snapshot)?;
+        snapshot.count(self.audit_log_len())?;
+        for entry in self.audit_log() {
+            write_audit_entry(&mut snapshot, &entry)?;
+        }
+        snapshot.count(self.fees_len())?;
+        for record in self.fees() {
+            write_fee_record(&mut snapshot, &record)?;
+        }
+        snapshot.finish().map(|_| ())
+    }
+
+    /// Writes a snapshot to the file at `path`, replacing it only once the snapshot is complete.
+    pub fn save_snapshot(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        snapshot::write_file(path.as_ref(), |writer| self.write_snapshot(writer))
+    }
+
+    /// An engine in the state saved by [`Self::write_snapshot`], keeping its transactions in the
+    /// empty store `transactions`. Transactions applied next continue where the snapshot left off;
+    /// `config` should match the configuration of the engine that wrote the snapshot.
+    pub fn restore(config: EngineConfig, transactions: Box<dyn TransactionStore>, reader: impl Read) -> Result<Self, SnapshotError> {
+        let mut snapshot = SnapshotReader::new(reader)?;
+        let mut engine = TransactionEngine::with_store(config, transactions);
+        for _ in 0..snapshot.count()? {
+            let client_id = snapshot.u16()?;
+            let accounts = ClientAccounts::read_snapshot(&mut snapshot)?;
+            if engine.clients.insert(client_id, accounts).is_some() {
+                return Err(SnapshotError::Corrupt("duplicate client"));
+            }
+        }
+        for _ in 0..snapshot.count()? {
+            let transaction_id = snapshot.u32()?;
+            let transaction = Transaction::decode(&snapshot.array()?).ok_or(SnapshotError::Corrupt("invalid transaction"))?;
+            engine.transactions.put(transaction_id, transaction);
+        }
+        engine.window = DisputeWindow::read_snapshot(&mut snapshot)?;
+        for _ in 0..snapshot.count()? {
+            engine.audit_log.push(read_audit_entry(&mut snapshot)?);
+        }
+        for _ in 0..snapshot.count()? {
+            engine.fees.push(read_fee_record(&mut snapshot)?);
+        }
+        snapshot.finish()?;
+        Ok(engine)
+    }
+
+    /// Restores an engine from the snapshot file at `path`, see [`Self::restore`].
+    pub fn restore_file(config: EngineConfig, transactions: Box<dyn TransactionStore>, path: impl AsRef<Path>) -> Result<Self, SnapshotError> {
+        TransactionEngine::restore(config, transactions, snapshot::open_file(path.as_ref())?)
+    }
+
+    /// An engine working on `storage`, in the state of its last [`Self::commit`], or empty if
+    /// nothing was ever committed to it. Its transactions, audit log and fees stay in the storage,
+    /// only read when needed, while the accounts of every client are loaded. `config` should match the
+    /// configuration of the engine that committed the state.
+    pub fn open(config: EngineConfig, storage: Box<dyn StorageBackend>) -> Result<Self, StorageError> {
+        let storage = SharedStorage::new(storage);
+        let mut engine = TransactionEngine::with_store(config, Box::new(StoredTransactions::new(storage.clone())));
+        {
+            let backend = storage.lock();
+            let client_ids: Vec<_> = backend.keys(Table::Clients).collect();
+            for key in client_ids {
+                let client_id = ClientID::try_from(key).map_err(|_| StorageError::Corrupt("client id out of range"))?;
+                let value = backend.get(Table::Clients, key).expect("listed keys have a value");
+                engine.clients.insert(client_id, storage::decode(&value, ClientAccounts::read_snapshot)?);
+            }
+            if let Some(value) = backend.get(Table::Engine, 0) {
+                engine.window = storage::decode(&value, DisputeWindow::read_snapshot)?;
+            }
+            engine.persistence = Some(Persistence {
+                storage: storage.clone(),
+                dirty_clients: BTreeSet::new(),
+                audit_log_len: backend.len(Table::AuditLog),
+                fees_len: backend.len(Table::Fees),
+            });
+        }
+        Ok(engine)
+    }
+
+    /// Writes the changes made since the last commit to the storage the engine was opened on, and
+    /// makes them durable all at once. [`Self::load_transactions`] commits once it is done, and
+    /// an engine without storage has nothing to commit.
+    pub fn commit(&mut self) {
+        let Some(persistence) = &mut self.persistence else {
+            return;
+        };
+        assert!(self.journal.is_none(), "a batch is committed as a whole");
+        let mut backend = persistence.storage.lock();
+        for client_id in std::mem::take(&mut persistence.dirty_clients) {
+            match self.clients.get(client_id) {
+                Some(accounts) => backend.put(Table::Clients, client_id as u32, &storage::encode(|value| accounts.write_snapshot(value))),
+                // Rolled back batches drop the clients they registered
+                None => backend.remove(Table::Clients, client_id as u32),
+            }
+        }
+        // Committed entries are only kept in the storage
+        for (index, entry) in self.audit_log.drain(..).enumerate() {
+            let index = persistence.audit_log_len + index;
+            backend.put(Table::AuditLog, index as u32, &storage::encode(|value| write_audit_entry(value, &entry)));
+        }
+        for (index, record) in self.fees.drain(..).enumerate() {
+            let index = persistence.fees_len + index;
+            backend.put(Table::Fees, index as u32, &storage::encode(|value| write_fee_record(value, &record)));
+        }
+        backend.put(Table::Engine, 0, &storage::encode(|value| self.window.write_snapshot(value)));
+        backend.commit();
+        persistence.audit_log_len = backend.len(Table::AuditLog);
+        persistence.fees_len = backend.len(Table::Fees);
+    }
+
+    /// Rebuilds an engine after a crash: restores the `snapshot` if one is given, then replays
+    /// the rows of the write-ahead log at `log` that come after it. A new log is started when there
+    /// is no file at `log`. The engine keeps logging to it, see [`Self::checkpoint`] to trim it.
+    pub fn recover(config: EngineConfig, transactions: Box<dyn TransactionStore>, snapshot: Option<&Path>, log: &Path) -> Result<Self, LogError> {
+        let mut engine = match snapshot {
+            Some(path) => TransactionEngine::restore_file(config, transactions, path).map_err(LogError::Snapshot)?,
+            None => TransactionEngine::with_store(config, transactions),
+        };
+        engine.attach_write_ahead_log(log)?;
+        Ok(engine)
+    }
+
+    /// Replays the rows of the write-ahead log at `log` that come after the current state of the
+    /// engine, e.g. one resumed from a checkpoint, and keeps logging to it. A new log is started
+    /// when there is no file at `log`. The event sink, if set, receives the events of the replayed rows.
+    pub fn attach_write_ahead_log(&mut self, log: &Path) -> Result<(), LogError> {
+        let log = if log.exists() {
+            WriteAheadLog::open(log, |entry| self.replay(entry))?
+        } else {
+            WriteAheadLog::create(log, self.window.position)?
+        };
+        if log.base() > self.window.position {
+            return Err(LogError::Gap { expected: self.window.position + 1, found: log.base() + 1 });
+        }
+        self.log = Some(log);
+        Ok(())
+    }
+
+    /// Applies a logged entry again, unless the state it was restored from already covers it.
+    fn replay(&mut self, entry: LogEntry) -> Result<(), LogError> {
+        let start = match &entry {
+            LogEntry::Row { position, .. } => *position,
+            LogEntry::Batch { start, .. } => *start,
+        };
+        if start <= self.window.position {
+            return Ok(());
+        }
+        if start != self.window.position + 1 {
+            return Err(LogError::Gap { expected: self.window.position + 1, found: start });
+        }
+        // Rejected rows were logged too, they are rejected again, and the events are published again
+        match entry {
+            LogEntry::Row { position, transaction } => { let _ = self.apply_unlogged(position, &transaction); },
+            LogEntry::Batch { transactions, .. } => { let _ = self.apply_journaled(&transactions); },
+        }
+        Ok(())
+    }
+
+    /// Saves a snapshot to `path` and starts the write-ahead log over after it, so recovering from
+    /// that snapshot only replays the rows applied since.
+    pub fn checkpoint(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.save_snapshot(path)?;
+        self.trim_write_ahead_log()
+    }
+
+    /// Starts the write-ahead log over after the current row, once the state of the engine is
+    /// saved somewhere recovery can start from, such as a snapshot or a checkpoint.
+    pub fn trim_write_ahead_log(&mut self) -> io::Result<()> {
+        if let Some(log) = &mut self.log {
+            log.reset(self.window.position)?;
+        }
+        Ok(())
+    }
+
+    /// Makes the rows logged so far durable, if the engine has a write-ahead log.
+    pub fn sync_log(&mut self) {
+        if let Some(log) = &mut self.log {
+            log.sync().expect("failed to sync the write-ahead log");
+        }
+    }
+
+    /// The write-ahead log of the engine, if it has one.
+    pub fn write_ahead_log(&self) -> Option<&WriteAheadLog> {
+        self.log.as_ref()
+    }
+
+    /// The write-ahead log of the engine, e.g. to turn on group commit.
+    pub fn write_ahead_log_mut(&mut self) -> Option<&mut WriteAheadLog> {
+        self.log.as_mut()
+    }
+
+    /// Detaches the write-ahead log, e.g. to remove it once the results it protects are published.
+    pub fn take_write_ahead_log(&mut self) -> Option<WriteAheadLog> {
+        self.log.take()
+    }
+
+    /// Number of rows applied so far, counting those of the runs a restored snapshot carries on from.
+    pub fn position(&self) -> u64 {
+        self.window.position
+    }
+
+    /// Balances of every client, one entry per asset the client has used.
+    pub fn clients(&self) -> impl Iterator<Item = ClientInfo> + '_ {
+        self.clients.iter().flat_map(|(client_id, accounts)| {
+            // A client whose transactions were all rejected still shows up, with empty default balances
+            let empty = accounts.assets.is_empty().then(ClientFunds::default);
+            accounts.assets.iter()
+                .map(move |(currency, funds)| ClientInfo::new(client_id, *currency, funds))
+                .chain(empty.map(move |funds| ClientInfo::new(client_id, Currency::DEFAULT, &funds)))
+        })
+    }
+
+    /// True once any client holds an asset other than the default one.
+    pub fn is_multi_asset(&self) -> bool {
+        self.clients.values()
+            .flat_map(|accounts| &accounts.assets)
+            .any(|(currency, _)| *currency != Currency::DEFAULT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fees::FeeRule;
+    use crate::test_util::row;
+
+    fn amount(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    fn in_currency(transaction: TransactionRaw, currency: &str) -> TransactionRaw {
+        TransactionRaw {
+            currency: Some(currency.parse().unwrap()),
+            ..transaction
+        }
+    }
+
+    fn funds(engine: &TransactionEngine, client_id: ClientID) -> &ClientFunds {
+        funds_in(engine, client_id, Currency::DEFAULT)
+    }
+
+    fn funds_in(engine: &TransactionEngine, client_id: ClientID, currency: Currency) -> &ClientFunds {
+        engine.clients.get(client_id).unwrap().assets.iter()
+            .find(|(asset, _)| *asset == currency)
+            .map(|(_, funds)| funds)
+            .unwrap()
+    }
+
+    fn transfer(client: ClientID, to: ClientID, tx: TransactionID, value: &str) -> TransactionRaw {
+        TransactionRaw {
+            to: Some(to),
+            ..row(TransactionTypeRaw::Transfer, client, tx, Some(value))
+        }
+    }
+
+    fn admin(transaction_type: TransactionTypeRaw, client: ClientID, tx: TransactionID, reason: &str) -> TransactionRaw {
+        TransactionRaw {
+            reason: Some(reason.to_string()),
+            ..row(transaction_type, client, tx, None)
+        }
+    }
+
+    fn deposit(client: ClientID, tx: TransactionID, value: &str) -> TransactionRaw {
+        row(TransactionTypeRaw::Deposit, client, tx, Some(value))
+    }
+
+    fn withdrawal(client: ClientID, tx: TransactionID, value: &str) -> TransactionRaw {
+        row(TransactionTypeRaw::Withdrawal, client, tx, Some(value))
+    }
+
+    fn partial(transaction_type: TransactionTypeRaw, client: ClientID, tx: TransactionID, value: &str) -> TransactionRaw {
+        row(transaction_type, client, tx, Some(value))
+    }
+
+    fn dispute(client: ClientID, tx: TransactionID) -> TransactionRaw {
+        row(TransactionTypeRaw::Dispute, client, tx, None)
+    }
+
+    fn resolve(client: ClientID, tx: TransactionID) -> TransactionRaw {
+        row(TransactionTypeRaw::Resolve, client, tx, None)
+    }
+
+    fn chargeback(client: ClientID, tx: TransactionID) -> TransactionRaw {
+        row(TransactionTypeRaw::Chargeback, client, tx, None)
+    }
+
+    #[test]
+    fn test_dispute_valid() {
+        let mut engine = TransactionEngine::default();
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        assert_eq!(engine.apply(&dispute(1, 1)), Ok(Outcome::Held(amount("100.0"))));
+
+        let client_funds = funds(&engine, 1);
+        assert_eq!(client_funds.available, amount("0.0"));
+        assert_eq!(client_funds.held, amount("100.0"));
+        assert_eq!(client_funds.status, AccountStatus::Active);
+    }
+
+    #[test]
+    fn test_dispute_invalid_after_withdrawal() {
+        let mut engine = TransactionEngine::default();
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        engine.apply(&withdrawal(1, 2, "50.0")).unwrap();
+        assert_eq!(engine.apply(&dispute(1, 1)), Err(TransactionError::InsufficientFunds));
+
+        let client_funds = funds(&engine, 1);
+        assert_eq!(client_funds.available, amount("50.0"));
+        assert_eq!(client_funds.held, amount("0.0"));
+        assert_eq!(client_funds.status, AccountStatus::Active);
+    }
+
+    #[test]
+    fn test_dispute_into_debt() {
+        let mut engine = TransactionEngine::new(EngineConfig { allow_negative_balance: true, ..Default::default() });
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        engine.apply(&withdrawal(1, 2, "70.0")).unwrap();
+        assert_eq!(engine.apply(&dispute(1, 1)), Ok(Outcome::Held(amount("100.0"))));
+
+        let client_funds = funds(&engine, 1);
+        assert_eq!(client_funds.available, amount("-70.0"));
+        assert_eq!(client_funds.held, amount("100.0"));
+        assert_eq!(client_funds.debt(), amount("70.0"));
+        // The client cannot spend while in debt
+        assert_eq!(engine.apply(&withdrawal(1, 3, "1.0")), Err(TransactionError::InsufficientFunds));
+
+        engine.apply(&chargeback(1, 1)).unwrap();
+        let client = engine.clients().next().unwrap();
+        assert_eq!(client.available, amount("-70.0"));
+        assert_eq!(client.held, amount("0.0"));
+        assert_eq!(client.total, amount("-70.0"));
+        assert_eq!(client.debt, amount("70.0"));
+        assert!(client.locked);
+    }
+
+    #[test]
+    fn test_deposit_repays_debt() {
+        let mut engine = TransactionEngine::new(EngineConfig { allow_negative_balance: true, ..Default::default() });
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        engine.apply(&withdrawal(1, 2, "100.0")).unwrap();
+        engine.apply(&dispute(1, 1)).unwrap();
+        engine.apply(&deposit(1, 3, "60.0")).unwrap();
+
+        let client_funds = funds(&engine, 1);
+        assert_eq!(client_funds.available, amount("-40.0"));
+        assert_eq!(client_funds.debt(), amount("40.0"));
+
+        engine.apply(&resolve(1, 1)).unwrap();
+        let client_funds = funds(&engine, 1);
+        assert_eq!(client_funds.available, amount("60.0"));
+        assert_eq!(client_funds.debt(), amount("0.0"));
+    }
+
+    #[test]
+    fn test_dispute_invalid_transaction() {
+        let mut engine = TransactionEngine::default();
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        assert_eq!(engine.apply(&dispute(1, 2)), Err(TransactionError::UnknownTransaction));
+
+        let client_funds = funds(&engine, 1);
+        assert_eq!(client_funds.available, amount("100.0"));
+        assert_eq!(client_funds.held, amount("0.0"));
+        assert_eq!(client_funds.status, AccountStatus::Active);
+    }
+
+    #[test]
+    fn test_dispute_other_client_transaction() {
+        let mut engine = TransactionEngine::default();
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        engine.apply(&deposit(2, 2, "100.0")).unwrap();
+        assert_eq!(engine.apply(&dispute(2, 1)), Err(TransactionError::UnknownTransaction));
+
+        assert_eq!(funds(&engine, 1).held, amount("0.0"));
+        assert_eq!(funds(&engine, 2).held, amount("0.0"));
+    }
+
+    #[test]
+    fn test_dispute_invalid_state() {
+        let mut engine = TransactionEngine::default();
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        engine.apply(&dispute(1, 1)).unwrap();
+        assert_eq!(engine.apply(&dispute(1, 1)), Err(TransactionError::InvalidState));
+
+        let client_funds = funds(&engine, 1);
+        assert_eq!(client_funds.available, amount("0.0"));
+        assert_eq!(client_funds.held, amount("100.0"));
+        assert_eq!(client_funds.status, AccountStatus::Active);
+    }
+
+    #[test]
+    fn test_dispute_withdrawal() {
+        let mut engine = TransactionEngine::default();
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        engine.apply(&withdrawal(1, 2, "40.0")).unwrap();
+        assert_eq!(engine.apply(&dispute(1, 2)), Err(TransactionError::WithdrawalNotDisputable));
+
+        let client_funds = funds(&engine, 1);
+        assert_eq!(client_funds.available, amount("60.0"));
+        assert_eq!(client_funds.held, amount("0.0"));
+    }
+
+    #[test]
+    fn test_dispute_withdrawal_enabled() {
+        let mut engine = TransactionEngine::new(EngineConfig { dispute_withdrawals: true, ..Default::default() });
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        engine.apply(&withdrawal(1, 2, "40.0")).unwrap();
+        engine.apply(&withdrawal(1, 3, "10.0")).unwrap();
+        assert_eq!(engine.apply(&dispute(1, 2)), Ok(Outcome::Held(amount("40.0"))));
+        assert_eq!(engine.apply(&dispute(1, 3)), Ok(Outcome::Held(amount("10.0"))));
+
+        let client_funds = funds(&engine, 1);
+        assert_eq!(client_funds.available, amount("50.0"));
+        assert_eq!(client_funds.held, amount("50.0"));
+
+        // Resolving drops the hold, the withdrawal stands
+        assert_eq!(engine.apply(&resolve(1, 3)), Ok(Outcome::Released(amount("10.0"))));
+        let client_funds = funds(&engine, 1);
+        assert_eq!(client_funds.available, amount("50.0"));
+        assert_eq!(client_funds.held, amount("40.0"));
+
+        // Charging back returns the withdrawn funds
+        assert_eq!(engine.apply(&chargeback(1, 2)), Ok(Outcome::ChargedBack(amount("40.0"))));
+        let client_funds = funds(&engine, 1);
+        assert_eq!(client_funds.available, amount("90.0"));
+        assert_eq!(client_funds.held, amount("0.0"));
+        assert_eq!(client_funds.status, AccountStatus::Locked);
+    }
+
+    #[test]
+    fn test_withdrawal_insufficient_funds() {
+        let mut engine = TransactionEngine::default();
+        engine.apply(&deposit(1, 1, "10.0")).unwrap();
+        assert_eq!(engine.apply(&withdrawal(1, 2, "10.0001")), Err(TransactionError::InsufficientFunds));
+        assert_eq!(engine.apply(&withdrawal(1, 3, "-1.0")), Err(TransactionError::NonPositiveAmount));
+
+        assert_eq!(funds(&engine, 1).available, amount("10.0"));
+        assert!(engine.transactions.get(2).is_none());
+    }
+
+    #[test]
+    fn test_resolve_valid() {
+        let mut engine = TransactionEngine::default();
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        engine.apply(&dispute(1, 1)).unwrap();
+        assert_eq!(engine.apply(&resolve(1, 1)), Ok(Outcome::Released(amount("100.0"))));
+
+        let client_funds = funds(&engine, 1);
+        assert_eq!(client_funds.available, amount("100.0"));
+        assert_eq!(client_funds.held, amount("0.0"));
+        assert_eq!(client_funds.status, AccountStatus::Active);
+    }
+
+    #[test]
+    fn test_resolve_invalid_transaction() {
+        let mut engine = TransactionEngine::default();
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        engine.apply(&dispute(1, 1)).unwrap();
+        assert_eq!(engine.apply(&resolve(1, 2)), Err(TransactionError::UnknownTransaction));
+
+        let client_funds = funds(&engine, 1);
+        assert_eq!(client_funds.available, amount("0.0"));
+        assert_eq!(client_funds.held, amount("100.0"));
+        assert_eq!(client_funds.status, AccountStatus::Active);
+    }
+
+    #[test]
+    fn test_resolve_invalid_state() {
+        let mut engine = TransactionEngine::default();
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        assert_eq!(engine.apply(&resolve(1, 1)), Err(TransactionError::InvalidState));
+
+        let client_funds = funds(&engine, 1);
+        assert_eq!(client_funds.available, amount("100.0"));
+        assert_eq!(client_funds.held, amount("0.0"));
+        assert_eq!(client_funds.status, AccountStatus::Active);
+    }
+
+    #[test]
+    fn test_chargeback_valid() {
+        let mut engine = TransactionEngine::default();
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        engine.apply(&dispute(1, 1)).unwrap();
+        assert_eq!(engine.apply(&chargeback(1, 1)), Ok(Outcome::ChargedBack(amount("100.0"))));
+
+        let client_funds = funds(&engine, 1);
+        assert_eq!(client_funds.available, amount("0.0"));
+        assert_eq!(client_funds.held, amount("0.0"));
+        assert_eq!(client_funds.status, AccountStatus::Locked);
+    }
+
+    #[test]
+    fn test_chargeback_invalid_transaction() {
+        let mut engine = TransactionEngine::default();
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        engine.apply(&dispute(1, 1)).unwrap();
+        assert_eq!(engine.apply(&chargeback(1, 2)), Err(TransactionError::UnknownTransaction));
+
+        let client_funds = funds(&engine, 1);
+        assert_eq!(client_funds.available, amount("0.0"));
+        assert_eq!(client_funds.held, amount("100.0"));
+        assert_eq!(client_funds.status, AccountStatus::Active);
+    }
+
+    #[test]
+    fn test_chargeback_invalid_state() {
+        let mut engine = TransactionEngine::default();
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        assert_eq!(engine.apply(&chargeback(1, 1)), Err(TransactionError::InvalidState));
+
+        let client_funds = funds(&engine, 1);
+        assert_eq!(client_funds.available, amount("100.0"));
+        assert_eq!(client_funds.held, amount("0.0"));
+        assert_eq!(client_funds.status, AccountStatus::Active);
+    }
+
+    #[test]
+    fn test_partial_disputes() {
+        let mut engine = TransactionEngine::default();
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        assert_eq!(engine.apply(&partial(TransactionTypeRaw::Dispute, 1, 1, "30.0")), Ok(Outcome::Held(amount("30.0"))));
+        assert_eq!(engine.apply(&partial(TransactionTypeRaw::Dispute, 1, 1, "50.0")), Ok(Outcome::Held(amount("50.0"))));
+        assert_eq!(engine.apply(&partial(TransactionTypeRaw::Dispute, 1, 1, "20.0001")), Err(TransactionError::ExceedsUndisputedAmount));
+        assert_eq!(engine.apply(&partial(TransactionTypeRaw::Dispute, 1, 1, "0")), Err(TransactionError::NonPositiveAmount));
+
+        let info = engine.transaction(1).unwrap();
+        assert_eq!(info.state, State::Disputed);
+        assert_eq!(info.disputed, amount("80.0"));
+        assert_eq!(info.undisputed, amount("20.0"));
+        assert_eq!(funds(&engine, 1).available, amount("20.0"));
+        assert_eq!(funds(&engine, 1).held, amount("80.0"));
+
+        assert_eq!(engine.apply(&partial(TransactionTypeRaw::Resolve, 1, 1, "80.0001")), Err(TransactionError::ExceedsDisputedAmount));
+        assert_eq!(engine.apply(&partial(TransactionTypeRaw::Resolve, 1, 1, "10.0")), Ok(Outcome::Released(amount("10.0"))));
+        // A full dispute takes whatever is still undisputed
+        assert_eq!(engine.apply(&dispute(1, 1)), Ok(Outcome::Held(amount("30.0"))));
+        assert_eq!(engine.apply(&dispute(1, 1)), Err(TransactionError::InvalidState));
+
+        assert_eq!(engine.apply(&partial(TransactionTypeRaw::Chargeback, 1, 1, "40.0")), Ok(Outcome::ChargedBack(amount("40.0"))));
+        let info = engine.transaction(1).unwrap();
+        assert_eq!(info.disputed, amount("60.0"));
+        assert_eq!(info.charged_back, amount("40.0"));
+        assert_eq!(info.undisputed, amount("0.0"));
+
+        let client_funds = funds(&engine, 1);
+        assert_eq!(client_funds.available, amount("0.0"));
+        assert_eq!(client_funds.held, amount("60.0"));
+        assert_eq!(client_funds.status, AccountStatus::Locked);
+    }
+
+    #[test]
+    fn test_partial_resolve_then_chargeback() {
+        let mut engine = TransactionEngine::default();
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        engine.apply(&dispute(1, 1)).unwrap();
+        engine.apply(&partial(TransactionTypeRaw::Resolve, 1, 1, "25.0")).unwrap();
+        assert_eq!(engine.apply(&chargeback(1, 1)), Ok(Outcome::ChargedBack(amount("75.0"))));
+
+        let info = engine.transaction(1).unwrap();
+        assert_eq!(info.state, State::ChargedBack);
+        assert_eq!(info.undisputed, amount("25.0"));
+        let client_funds = funds(&engine, 1);
+        assert_eq!(client_funds.available, amount("25.0"));
+        assert_eq!(client_funds.held, amount("0.0"));
+    }
+
+    #[test]
+    fn test_transfer() {
+        let mut engine = TransactionEngine::default();
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        assert_eq!(engine.apply(&transfer(1, 2, 2, "60.0")), Ok(Outcome::Transferred(amount("60.0"))));
+        assert_eq!(engine.apply(&transfer(1, 2, 3, "40.0001")), Err(TransactionError::InsufficientFunds));
+        assert_eq!(engine.apply(&transfer(1, 1, 4, "1.0")), Err(TransactionError::InvalidDestination));
+        assert_eq!(engine.apply(&row(TransactionTypeRaw::Transfer, 1, 5, Some("1.0"))), Err(TransactionError::MissingDestination));
+        assert_eq!(engine.apply(&transfer(3, 1, 2, "1.0")), Err(TransactionError::DuplicateTransaction));
+
+        assert_eq!(funds(&engine, 1).available, amount("40.0"));
+        assert_eq!(funds(&engine, 2).available, amount("60.0"));
+    }
+
+    #[test]
+    fn test_transfer_to_locked_account() {
+        let mut engine = TransactionEngine::default();
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        engine.apply(&admin(TransactionTypeRaw::Freeze, 2, 2, "review")).unwrap();
+        assert_eq!(engine.apply(&transfer(1, 2, 3, "10.0")), Err(TransactionError::AccountLocked));
+
+        assert_eq!(funds(&engine, 1).available, amount("100.0"));
+        assert_eq!(funds(&engine, 2).available, amount("0.0"));
+        assert!(engine.transaction(3).is_none());
+    }
+
+    #[test]
+    fn test_transfer_chargeback_reverses() {
+        let mut engine = TransactionEngine::default();
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        engine.apply(&transfer(1, 2, 2, "60.0")).unwrap();
+        // The payer disputes the transfer, the amount is held at the destination
+        assert_eq!(engine.apply(&dispute(1, 2)), Ok(Outcome::Held(amount("60.0"))));
+        assert_eq!(funds(&engine, 2).available, amount("0.0"));
+        assert_eq!(funds(&engine, 2).held, amount("60.0"));
+        assert_eq!(engine.apply(&dispute(3, 2)), Err(TransactionError::UnknownTransaction));
+
+        assert_eq!(engine.apply(&chargeback(1, 2)), Ok(Outcome::ChargedBack(amount("60.0"))));
+        let source = funds(&engine, 1);
+        assert_eq!(source.available, amount("100.0"));
+        assert_eq!(source.status, AccountStatus::Active);
+        let destination = funds(&engine, 2);
+        assert_eq!(destination.available, amount("0.0"));
+        assert_eq!(destination.held, amount("0.0"));
+        assert_eq!(destination.status, AccountStatus::Locked);
+    }
+
+    #[test]
+    fn test_assets_are_independent() {
+        let mut engine = TransactionEngine::default();
+        let eur: Currency = "EUR".parse().unwrap();
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        engine.apply(&in_currency(deposit(1, 2, "50.0"), "eur")).unwrap();
+        assert_eq!(engine.apply(&in_currency(withdrawal(1, 3, "60.0"), "EUR")), Err(TransactionError::InsufficientFunds));
+        assert_eq!(engine.apply(&in_currency(transfer(1, 2, 4, "20.0"), "EUR")), Ok(Outcome::Transferred(amount("20.0"))));
+
+        // The dispute row has no currency but acts on the asset of the disputed deposit
+        assert_eq!(engine.apply(&partial(TransactionTypeRaw::Dispute, 1, 2, "30.0")), Ok(Outcome::Held(amount("30.0"))));
+        assert_eq!(funds_in(&engine, 1, eur).held, amount("30.0"));
+        assert_eq!(funds(&engine, 1).held, amount("0.0"));
+        engine.apply(&chargeback(1, 2)).unwrap();
+        assert_eq!(funds_in(&engine, 1, eur).status, AccountStatus::Locked);
+        assert_eq!(funds(&engine, 1).status, AccountStatus::Active);
+        assert_eq!(engine.apply(&withdrawal(1, 5, "100.0")), Ok(Outcome::Withdrawn(amount("100.0"))));
+        assert_eq!(engine.apply(&in_currency(deposit(1, 6, "1.0"), "EUR")), Err(TransactionError::AccountLocked));
+
+        let mut balances: Vec<_> = engine.clients().map(|info| (info.client_id, info.currency.to_string(), info.available)).collect();
+        balances.sort();
+        assert_eq!(balances, vec![
+            (1, "".to_string(), amount("0.0")),
+            (1, "EUR".to_string(), amount("0.0")),
+            (2, "EUR".to_string(), amount("20.0")),
+        ]);
+        assert!(engine.is_multi_asset());
+    }
+
+    #[test]
+    fn test_duplicate_transaction_id() {
+        let mut engine = TransactionEngine::default();
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        engine.apply(&dispute(1, 1)).unwrap();
+        // Same client: must neither credit again nor reset the dispute
+        assert_eq!(engine.apply(&deposit(1, 1, "100.0")), Err(TransactionError::DuplicateTransaction));
+        // Another client reusing the id
+        assert_eq!(engine.apply(&withdrawal(2, 1, "5.0")), Err(TransactionError::DuplicateTransaction));
+
+        let client_funds = funds(&engine, 1);
+        assert_eq!(client_funds.available, amount("0.0"));
+        assert_eq!(client_funds.held, amount("100.0"));
+        assert_eq!(engine.transactions.get(1).unwrap().state(), State::Disputed);
+    }
+
+    #[test]
+    fn test_rejected_transaction_does_not_claim_id() {
+        let mut engine = TransactionEngine::default();
+        assert_eq!(engine.apply(&withdrawal(1, 1, "5.0")), Err(TransactionError::InsufficientFunds));
+        assert_eq!(engine.apply(&deposit(2, 1, "5.0")), Ok(Outcome::Deposited(amount("5.0"))));
+    }
+
+    #[test]
+    fn test_idempotent_replays() {
+        let mut engine = TransactionEngine::new(EngineConfig { idempotent_replays: true, ..Default::default() });
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        engine.apply(&withdrawal(1, 2, "30.0")).unwrap();
+        assert_eq!(engine.apply(&deposit(1, 1, "100.0")), Ok(Outcome::Replayed));
+        assert_eq!(engine.apply(&withdrawal(1, 2, "30.0")), Ok(Outcome::Replayed));
+        // Anything that differs from the original is still a duplicate
+        assert_eq!(engine.apply(&deposit(1, 1, "100.5")), Err(TransactionError::DuplicateTransaction));
+        assert_eq!(engine.apply(&deposit(1, 2, "30.0")), Err(TransactionError::DuplicateTransaction));
+        assert_eq!(engine.apply(&deposit(2, 1, "100.0")), Err(TransactionError::DuplicateTransaction));
+
+        assert_eq!(funds(&engine, 1).available, amount("70.0"));
+    }
+
+    #[test]
+    fn test_freeze_and_unlock() {
+        let mut engine = TransactionEngine::default();
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        assert_eq!(engine.apply(&admin(TransactionTypeRaw::Freeze, 1, 2, "suspicious activity")), Ok(Outcome::Frozen));
+        assert_eq!(engine.apply(&withdrawal(1, 3, "10.0")), Err(TransactionError::AccountLocked));
+        assert_eq!(engine.apply(&admin(TransactionTypeRaw::Freeze, 1, 4, "again")), Err(TransactionError::InvalidAccountState));
+        assert_eq!(engine.apply(&row(TransactionTypeRaw::Unlock, 1, 5, None)), Err(TransactionError::MissingReason));
+        assert_eq!(engine.apply(&admin(TransactionTypeRaw::Unlock, 1, 6, "reviewed")), Ok(Outcome::Unlocked));
+        assert_eq!(engine.apply(&withdrawal(1, 7, "10.0")), Ok(Outcome::Withdrawn(amount("10.0"))));
+
+        assert_eq!(funds(&engine, 1).status, AccountStatus::Active);
+        assert_eq!(engine.audit_log().collect::<Vec<_>>(), [
+            AuditEntry { client_id: 1, currency: Currency::DEFAULT, tx: 2, action: AdminAction::Freeze, reason: "suspicious activity".to_string() },
+            AuditEntry { client_id: 1, currency: Currency::DEFAULT, tx: 6, action: AdminAction::Unlock, reason: "reviewed".to_string() },
+        ]);
+    }
+
+    #[test]
+    fn test_unlock_after_chargeback() {
+        let mut engine = TransactionEngine::default();
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        engine.apply(&deposit(1, 2, "50.0")).unwrap();
+        engine.apply(&dispute(1, 1)).unwrap();
+        engine.apply(&chargeback(1, 1)).unwrap();
+        assert_eq!(engine.apply(&admin(TransactionTypeRaw::Unlock, 1, 3, "chargeback reviewed")), Ok(Outcome::Unlocked));
+        assert_eq!(engine.apply(&withdrawal(1, 4, "50.0")), Ok(Outcome::Withdrawn(amount("50.0"))));
+    }
+
+    #[test]
+    fn test_close_account() {
+        let mut engine = TransactionEngine::default();
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        engine.apply(&dispute(1, 1)).unwrap();
+        assert_eq!(engine.apply(&admin(TransactionTypeRaw::Close, 1, 2, "customer request")), Err(TransactionError::InvalidAccountState));
+        engine.apply(&resolve(1, 1)).unwrap();
+        engine.apply(&withdrawal(1, 3, "100.0")).unwrap();
+        assert_eq!(engine.apply(&admin(TransactionTypeRaw::Close, 1, 4, "customer request")), Ok(Outcome::Closed));
+
+        assert_eq!(engine.apply(&deposit(1, 5, "1.0")), Err(TransactionError::AccountClosed));
+        assert_eq!(engine.apply(&admin(TransactionTypeRaw::Unlock, 1, 6, "reopen")), Err(TransactionError::AccountClosed));
+        let client = engine.clients().next().unwrap();
+        assert!(client.locked);
+        assert_eq!(client.status, AccountStatus::Closed);
+    }
+
+    #[test]
+    fn test_close_requires_empty_active_account() {
+        let mut engine = TransactionEngine::new(EngineConfig { allow_negative_balance: true, ..Default::default() });
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        engine.apply(&withdrawal(1, 2, "60.0")).unwrap();
+        // Remaining funds would be stranded
+        assert_eq!(engine.apply(&admin(TransactionTypeRaw::Close, 1, 3, "customer request")), Err(TransactionError::InvalidAccountState));
+        engine.apply(&withdrawal(1, 4, "40.0")).unwrap();
+        // Debt would be written off
+        engine.apply(&dispute(1, 1)).unwrap();
+        engine.apply(&resolve(1, 1)).unwrap();
+        engine.apply(&dispute(1, 1)).unwrap();
+        engine.apply(&chargeback(1, 1)).unwrap();
+        engine.apply(&admin(TransactionTypeRaw::Unlock, 1, 5, "chargeback reviewed")).unwrap();
+        assert_eq!(engine.clients().next().unwrap().available, amount("-100.0"));
+        assert_eq!(engine.apply(&admin(TransactionTypeRaw::Close, 1, 6, "customer request")), Err(TransactionError::InvalidAccountState));
+        engine.apply(&deposit(1, 7, "100.0")).unwrap();
+        // Locked accounts are unlocked first
+        engine.apply(&admin(TransactionTypeRaw::Freeze, 1, 8, "review")).unwrap();
+        assert_eq!(engine.apply(&admin(TransactionTypeRaw::Close, 1, 9, "customer request")), Err(TransactionError::InvalidAccountState));
+        engine.apply(&admin(TransactionTypeRaw::Unlock, 1, 10, "reviewed")).unwrap();
+        assert_eq!(engine.apply(&admin(TransactionTypeRaw::Close, 1, 11, "customer request")), Ok(Outcome::Closed));
+        assert_eq!(engine.audit_log_len(), 4);
+    }
+
+    #[test]
+    fn test_locked_account_blocks_transactions() {
+        let mut engine = TransactionEngine::default();
+
+        // Create transactions for client 1
+        let transactions = vec![
+            deposit(1, 1, "100.0"),
+            dispute(1, 1),
+            chargeback(1, 1),
+            // These should be blocked because account is locked
+            deposit(1, 2, "50.0"),
+            withdrawal(1, 3, "25.0"),
+        ];
+
+        let report = engine.load_transactions(transactions.into_iter());
+        assert_eq!(report.applied, 3);
+        assert_eq!(report.rejected.get(&TransactionError::AccountLocked), Some(&2));
+
+        // Get client info
+        let client_info: Vec<_> = engine.clients().collect();
+        assert_eq!(client_info.len(), 1);
+
+        let client = &client_info[0];
+        assert_eq!(client.client_id, 1);
+        assert_eq!(client.available, amount("0.0")); // Should remain 0 after chargeback
+        assert_eq!(client.held, amount("0.0"));
+        assert_eq!(client.total, amount("0.0"));
+        assert!(client.locked);
+    }
+
+    fn with_fees(configure: impl FnOnce(&mut FeeSchedule)) -> TransactionEngine {
+        let mut schedule = FeeSchedule::new(99);
+        configure(&mut schedule);
+        TransactionEngine::new(EngineConfig { fees: Some(schedule), ..Default::default() })
+    }
+
+    #[test]
+    fn test_withdrawal_fee() {
+        let mut engine = with_fees(|fees| fees.withdrawal = FeeRule { flat: amount("0.5"), percent: amount("1") });
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        assert_eq!(engine.apply(&withdrawal(1, 2, "50.0")), Ok(Outcome::Withdrawn(amount("50.0"))));
+        assert_eq!(funds(&engine, 1).available, amount("49.0"));
+        assert_eq!(funds(&engine, 1).fees, amount("1.0"));
+        assert_eq!(funds(&engine, 99).available, amount("1.0"));
+        assert_eq!(engine.fees().collect::<Vec<_>>(), [FeeRecord { tx: 2, client_id: 1, currency: Currency::DEFAULT, kind: FeeKind::Withdrawal, amount: amount("1.0") }]);
+
+        // The fee must be covered on top of the withdrawn amount
+        assert_eq!(engine.apply(&withdrawal(1, 3, "49.0")), Err(TransactionError::InsufficientFunds));
+        assert_eq!(funds(&engine, 1).available, amount("49.0"));
+        assert_eq!(funds(&engine, 99).available, amount("1.0"));
+        assert!(engine.transaction(3).is_none());
+        assert_eq!(engine.fees_len(), 1);
+    }
+
+    #[test]
+    fn test_deposit_and_transfer_fees() {
+        let mut engine = with_fees(|fees| {
+            fees.deposit.flat = amount("1.0");
+            fees.transfer.percent = amount("10");
+        });
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        assert_eq!(engine.apply(&deposit(2, 2, "0.5")), Err(TransactionError::InsufficientFunds));
+        assert_eq!(funds(&engine, 2).available, amount("0.0"));
+
+        assert_eq!(engine.apply(&transfer(1, 2, 3, "91.0")), Err(TransactionError::InsufficientFunds));
+        assert_eq!(funds(&engine, 1).available, amount("99.0"));
+        assert_eq!(funds(&engine, 2).available, amount("0.0"));
+        assert_eq!(engine.apply(&transfer(1, 2, 3, "50.0")), Ok(Outcome::Transferred(amount("50.0"))));
+        assert_eq!(funds(&engine, 1).available, amount("44.0"));
+        assert_eq!(funds(&engine, 2).available, amount("50.0"));
+        assert_eq!(funds(&engine, 99).available, amount("6.0"));
+        assert_eq!(engine.fees().map(|fee| (fee.tx, fee.client_id)).collect::<Vec<_>>(), [(1, 1), (3, 1)]);
+    }
+
+    #[test]
+    fn test_chargeback_penalty() {
+        let mut engine = with_fees(|fees| fees.chargeback.flat = amount("15.0"));
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        engine.apply(&deposit(1, 2, "10.0")).unwrap();
+        engine.apply(&dispute(1, 1)).unwrap();
+        assert_eq!(engine.apply(&chargeback(1, 1)), Ok(Outcome::ChargedBack(amount("100.0"))));
+
+        // Without negative balances, only what the available funds cover is collected
+        let client_funds = funds(&engine, 1);
+        assert_eq!(client_funds.available, amount("0.0"));
+        assert_eq!(client_funds.fees, amount("10.0"));
+        assert_eq!(funds(&engine, 99).available, amount("10.0"));
+        assert_eq!(engine.fees().collect::<Vec<_>>(), [FeeRecord { tx: 1, client_id: 1, currency: Currency::DEFAULT, kind: FeeKind::Chargeback, amount: amount("10.0") }]);
+
+        // With nothing left, no penalty is recorded
+        engine.apply(&deposit(2, 3, "30.0")).unwrap();
+        engine.apply(&dispute(2, 3)).unwrap();
+        engine.apply(&chargeback(2, 3)).unwrap();
+        assert_eq!(funds(&engine, 2).available, amount("0.0"));
+        assert_eq!(engine.fees_len(), 1);
+    }
+
+    #[test]
+    fn test_chargeback_penalty_with_negative_balance() {
+        let mut engine = with_fees(|fees| fees.chargeback.flat = amount("15.0"));
+        engine.config.allow_negative_balance = true;
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        engine.apply(&deposit(1, 2, "10.0")).unwrap();
+        engine.apply(&dispute(1, 1)).unwrap();
+        assert_eq!(engine.apply(&chargeback(1, 1)), Ok(Outcome::ChargedBack(amount("100.0"))));
+
+        // The penalty is collected even when the client cannot cover it
+        let client_funds = funds(&engine, 1);
+        assert_eq!(client_funds.available, amount("-5.0"));
+        assert_eq!(client_funds.debt(), amount("5.0"));
+        assert_eq!(client_funds.fees, amount("15.0"));
+        assert_eq!(funds(&engine, 99).available, amount("15.0"));
+        assert_eq!(engine.fees().next().unwrap().kind, FeeKind::Chargeback);
+    }
+
+    #[test]
+    fn test_house_account_is_reserved() {
+        let mut engine = with_fees(|_| {});
+        assert_eq!(engine.apply(&deposit(99, 1, "10.0")), Err(TransactionError::ReservedAccount));
+        engine.apply(&deposit(1, 2, "10.0")).unwrap();
+        assert_eq!(engine.apply(&transfer(1, 99, 3, "5.0")), Err(TransactionError::ReservedAccount));
+        // Without any fee due, nothing is recorded and the house account stays unknown
+        assert_eq!(engine.fees_len(), 0);
+        assert!(!engine.clients.contains(99));
+    }
+
+    #[test]
+    fn test_dispute_window() {
+        let mut engine = TransactionEngine::new(EngineConfig { dispute_window: Some(3), ..Default::default() });
+        engine.apply(&deposit(1, 1, "10.0")).unwrap();
+        engine.apply(&deposit(1, 2, "20.0")).unwrap();
+        engine.apply(&deposit(1, 3, "30.0")).unwrap();
+        // Transaction 1 is three rows behind this dispute
+        assert_eq!(engine.apply(&dispute(1, 1)), Err(TransactionError::ExpiredTransaction));
+        assert_eq!(engine.apply(&dispute(1, 4)), Err(TransactionError::UnknownTransaction));
+        assert!(engine.transaction(1).is_none());
+        // An expired id cannot be used again
+        assert_eq!(engine.apply(&deposit(2, 1, "10.0")), Err(TransactionError::DuplicateTransaction));
+        assert_eq!(engine.apply(&dispute(1, 2)), Err(TransactionError::ExpiredTransaction));
+        assert_eq!(funds(&engine, 1).available, amount("60.0"));
+        assert_eq!(engine.transaction_store().len(), 0);
+    }
+
+    #[test]
+    fn test_dispute_window_hides_ids_of_others() {
+        let mut engine = TransactionEngine::new(EngineConfig { dispute_window: Some(3), ..Default::default() });
+        engine.apply(&deposit(1, 1, "10.0")).unwrap();
+        engine.apply(&transfer(1, 2, 2, "5.0")).unwrap();
+        engine.apply(&row(TransactionTypeRaw::Dispute, 1, 1, Some("4.0"))).unwrap();
+        engine.apply(&deposit(3, 3, "1.0")).unwrap();
+
+        // Only the parties learn that an expired id was used
+        assert_eq!(engine.apply(&dispute(3, 1)), Err(TransactionError::UnknownTransaction));
+        assert_eq!(engine.apply(&dispute(3, 2)), Err(TransactionError::UnknownTransaction));
+        assert_eq!(engine.apply(&resolve(3, 1)), Err(TransactionError::UnknownTransaction));
+        assert_eq!(engine.apply(&dispute(1, 1)), Err(TransactionError::ExpiredTransaction));
+        assert_eq!(engine.apply(&dispute(1, 2)), Err(TransactionError::ExpiredTransaction));
+        assert_eq!(engine.apply(&dispute(2, 2)), Err(TransactionError::ExpiredTransaction));
+    }
+
+    #[test]
+    fn test_dispute_window_keeps_open_disputes() {
+        let mut engine = TransactionEngine::new(EngineConfig { dispute_window: Some(2), ..Default::default() });
+        engine.apply(&deposit(1, 1, "10.0")).unwrap();
+        engine.apply(&row(TransactionTypeRaw::Dispute, 1, 1, Some("4.0"))).unwrap();
+        engine.apply(&deposit(1, 2, "20.0")).unwrap();
+        engine.apply(&deposit(1, 3, "30.0")).unwrap();
+
+        // The open dispute keeps the transaction, but its undisputed part can no longer be disputed
+        assert_eq!(engine.transaction(1).unwrap().disputed, amount("4.0"));
+        assert_eq!(engine.apply(&dispute(1, 1)), Err(TransactionError::ExpiredTransaction));
+        assert_eq!(engine.apply(&resolve(1, 1)), Ok(Outcome::Released(amount("4.0"))));
+        assert!(engine.transaction(1).is_none());
+        assert_eq!(engine.apply(&resolve(1, 1)), Err(TransactionError::ExpiredTransaction));
+        assert_eq!(funds(&engine, 1).available, amount("60.0"));
+        assert_eq!(funds(&engine, 1).held, Amount::ZERO);
+    }
+
+    #[test]
+    fn test_apply_batch() {
+        let mut engine = TransactionEngine::default();
+        let batch = [deposit(1, 1, "100.0"), transfer(1, 2, 2, "40.0"), dispute(2, 2)];
+        assert_eq!(engine.apply_batch(&batch), Ok(vec![
+            Outcome::Deposited(amount("100.0")),
+            Outcome::Transferred(amount("40.0")),
+            Outcome::Held(amount("40.0")),
+        ]));
+        assert_eq!(funds(&engine, 2).held, amount("40.0"));
+    }
+
+    #[test]
+    fn test_apply_batch_rolls_back() {
+        let mut engine = with_fees(|fees| fees.withdrawal.flat = amount("1.0"));
+        engine.apply(&deposit(1, 1, "100.0")).unwrap();
+        engine.apply(&withdrawal(1, 2, "10.0")).unwrap();
+        let batch = [
+            deposit(2, 3, "50.0"),
+            transfer(2, 1, 4, "20.0"),
+            withdrawal(1, 5, "5.0"),
+            dispute(1, 1),
+            admin(TransactionTypeRaw::Freeze, 2, 6, "review"),
+            withdrawal(2, 7, "1000.0"),
+        ];
+        assert_eq!(engine.apply_batch(&batch), Err(BatchError { index: 5, error: TransactionError::AccountLocked }));
+
+        assert_eq!(funds(&engine, 1).available, amount("89.0"));
+        assert_eq!(funds(&engine, 1).held, Amount::ZERO);
+        assert_eq!(funds(&engine, 99).available, amount("1.0"));
+        assert!(!engine.clients.contains(2));
+        assert_eq!(engine.transaction(1).unwrap().state, State::Normal);
+        assert!(engine.transaction(3).is_none() && engine.transaction(4).is_none());
+        assert_eq!(engine.audit_log_len(), 0);
+        assert_eq!(engine.fees_len(), 1);
+        // The ids of the rejected batch can be used again
+        assert_eq!(engine.apply_batch(&batch[..4]).map(|outcomes| outcomes.len()), Ok(4));
+        assert_eq!(funds(&engine, 1).held, amount("100.0"));
+    }
+
+    /// An engine publishing its events to the returned receiver.
+    fn with_events() -> (TransactionEngine, std::sync::mpsc::Receiver<Event>) {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut engine = TransactionEngine::default();
+        engine.set_event_sink(Box::new(sender));
+        (engine, receiver)
+    }
+
+    fn event(position: u64, client_id: ClientID, tx: TransactionID, transaction_type: TransactionTypeRaw, kind: EventKind) -> Event {
+        Event { position, client_id, currency: Currency::DEFAULT, tx, transaction_type, kind }
+    }
+
+    #[test]
+    fn test_events() {
+        use TransactionTypeRaw::*;
+        let (mut engine, events) = with_events();
+        engine.load_transactions([
+            deposit(1, 1, "100.0"),
+            in_currency(transfer(1, 2, 2, "30.0"), "eur"),
+            transfer(1, 2, 3, "30.0"),
+            dispute(1, 3),
+            resolve(1, 3),
+            withdrawal(2, 4, "50.0"),
+            dispute(2, 3),
+            chargeback(1, 3),
+            admin(Freeze, 1, 5, "kyc review"),
+            admin(Unlock, 1, 6, "cleared"),
+        ].into_iter());
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), vec![
+            event(1, 1, 1, Deposit, EventKind::FundsDeposited(amount("100.0"))),
+            Event { currency: "EUR".parse().unwrap(), ..event(2, 1, 2, Transfer, EventKind::TransactionRejected(TransactionError::InsufficientFunds)) },
+            event(3, 1, 3, Transfer, EventKind::FundsWithdrawn(amount("30.0"))),
+            event(3, 2, 3, Transfer, EventKind::FundsDeposited(amount("30.0"))),
+            // Disputes of a transfer act on its destination
+            event(4, 2, 3, Dispute, EventKind::FundsHeld(amount("30.0"))),
+            event(5, 2, 3, Resolve, EventKind::FundsReleased(amount("30.0"))),
+            event(6, 2, 4, Withdrawal, EventKind::TransactionRejected(TransactionError::InsufficientFunds)),
+            event(7, 2, 3, Dispute, EventKind::FundsHeld(amount("30.0"))),
+            event(8, 2, 3, Chargeback, EventKind::ChargedBack(amount("30.0"))),
+            // The source of the transfer gets the amount back
+            event(8, 1, 3, Chargeback, EventKind::FundsDeposited(amount("30.0"))),
+            event(8, 2, 3, Chargeback, EventKind::AccountLocked { reason: None }),
+            event(9, 1, 5, Freeze, EventKind::AccountLocked { reason: Some("kyc review".to_string()) }),
+            event(10, 1, 6, Unlock, EventKind::AccountUnlocked { reason: Some("cleared".to_string()) }),
+        ]);
+
+        engine.take_event_sink();
+        engine.apply(&deposit(3, 7, "1.0")).unwrap();
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_fee_events() {
+        use TransactionTypeRaw::*;
+        let mut engine = with_fees(|fees| {
+            fees.deposit.flat = amount("1.0");
+            fees.chargeback.flat = amount("5.0");
+        });
+        let (sender, events) = std::sync::mpsc::channel();
+        engine.set_event_sink(Box::new(sender));
+        engine.load_transactions([
+            deposit(1, 1, "100.0"),
+            deposit(2, 2, "1.0"),
+            admin(Close, 2, 3, "left"),
+            deposit(1, 4, "3.0"),
+            dispute(1, 1),
+            chargeback(1, 1),
+        ].into_iter());
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), vec![
+            event(1, 1, 1, Deposit, EventKind::FundsDeposited(amount("100.0"))),
+            event(1, 1, 1, Deposit, EventKind::FeeCharged(amount("1.0"))),
+            event(1, 99, 1, Deposit, EventKind::FeeCollected(amount("1.0"))),
+            event(2, 2, 2, Deposit, EventKind::FundsDeposited(amount("1.0"))),
+            event(2, 2, 2, Deposit, EventKind::FeeCharged(amount("1.0"))),
+            event(2, 99, 2, Deposit, EventKind::FeeCollected(amount("1.0"))),
+            event(3, 2, 3, Close, EventKind::AccountClosed { reason: Some("left".to_string()) }),
+            event(4, 1, 4, Deposit, EventKind::FundsDeposited(amount("3.0"))),
+            event(4, 1, 4, Deposit, EventKind::FeeCharged(amount("1.0"))),
+            event(4, 99, 4, Deposit, EventKind::FeeCollected(amount("1.0"))),
+            event(5, 1, 1, Dispute, EventKind::FundsHeld(amount("100.0"))),
+            event(6, 1, 1, Chargeback, EventKind::ChargedBack(amount("100.0"))),
+            event(6, 1, 1, Chargeback, EventKind::AccountLocked { reason: None }),
+            // The penalty is capped to the available funds
+            event(6, 1, 1, Chargeback, EventKind::FeeCharged(amount("1.0"))),
+            event(6, 99, 1, Chargeback, EventKind::FeeCollected(amount("1.0"))),
+        ]);
+    }
+
+    #[test]
+    fn test_batch_events() {
+        let (mut engine, events) = with_events();
+        engine.apply_batch(&[deposit(1, 1, "100.0"), withdrawal(1, 2, "10.0")]).unwrap();
+        assert_eq!(events.try_iter().count(), 2);
+        // A rolled back batch only reports the rejected transaction, at the position of its first row
+        let batch = [deposit(1, 3, "10.0"), withdrawal(1, 4, "1000.0")];
+        assert!(engine.apply_batch(&batch).is_err());
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), vec![
+            event(3, 1, 4, TransactionTypeRaw::Withdrawal, EventKind::TransactionRejected(TransactionError::InsufficientFunds)),
+        ]);
+    }
+
+    #[test]
+    fn test_apply_batch_rolls_back_dispute_window() {
+        let mut engine = TransactionEngine::new(EngineConfig { dispute_window: Some(2), ..Default::default() });
+        engine.apply(&deposit(1, 1, "10.0")).unwrap();
+        let batch = [deposit(1, 2, "10.0"), deposit(1, 3, "10.0"), dispute(1, 1)];
+        assert_eq!(engine.apply_batch(&batch), Err(BatchError { index: 2, error: TransactionError::ExpiredTransaction }));
+        // Transaction 1 is back within the window
+        assert_eq!(engine.apply(&dispute(1, 1)), Ok(Outcome::Held(amount("10.0"))));
+    }
+
+    #[test]
+    fn test_pack_round_trip() {
+        let mut disputed = Transaction::new(65535, Currency::DEFAULT, amount("-3518437208.8831"));
+        disputed.disputed = disputed.value();
+        let mut charged_back = Transaction::new(7, Currency::DEFAULT, amount("0.0001"));
+        charged_back.charged_back = charged_back.value();
+        for transaction in [Transaction::new(1, Currency::DEFAULT, amount("12.5")), disputed, charged_back] {
+            assert_eq!(Transaction::unpack(transaction.pack().unwrap()), transaction);
+        }
+
+        let mut partial = Transaction::new(1, Currency::DEFAULT, amount("12.5"));
+        partial.disputed = amount("2.0");
+        assert_eq!(partial.pack(), None);
+        assert_eq!(Transaction::new(1, Currency::DEFAULT, amount("3518437208.8832")).pack(), None);
+        assert_eq!(Transaction::new(1, "EUR".parse().unwrap(), amount("1.0")).pack(), None);
+        assert_eq!(Transaction::transfer(1, 2, Currency::DEFAULT, amount("1.0")).pack(), None);
+    }
+}
@@ -0,0 +1,368 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use crate::transaction_engine::{Transaction, TransactionID};
+
+/// Storage of the transactions the engine keeps for later disputes.
+///
+/// The engine reads a transaction, changes its dispute state and puts it back, so a store
+/// only needs to return the latest version put under an id.
+pub trait TransactionStore: fmt::Debug + Send {
+    /// The latest version of the transaction stored under `transaction_id`.
+    fn get(&self, transaction_id: TransactionID) -> Option<Transaction>;
+
+    /// Stores a new transaction, or a new version of a stored one.
+    fn put(&mut self, transaction_id: TransactionID, transaction: Transaction);
+
+    /// Drops the transaction stored under `transaction_id`, once it can no longer be disputed.
+    fn remove(&mut self, transaction_id: TransactionID);
+
+    /// Number of distinct transactions stored.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Approximate number of bytes of memory the store uses.
+    fn heap_bytes(&self) -> usize;
+
+    /// Approximate memory used per stored transaction.
+    fn bytes_per_transaction(&self) -> f64 {
+        self.heap_bytes() as f64 / self.len().max(1) as f64
+    }
+
+    /// Every stored transaction, in no particular order.
+    fn entries(&self) -> Box<dyn Iterator<Item = (TransactionID, Transaction)> + '_>;
+
+    /// Moves every transaction out of the store, in no particular order.
+    fn into_entries(self: Box<Self>) -> Box<dyn Iterator<Item = (TransactionID, Transaction)>>;
+}
+
+/// Approximate number of bytes allocated by a hash map: its buckets, each with a control byte.
+pub(crate) fn hash_map_bytes<K, V>(map: &HashMap<K, V>) -> usize {
+    // Buckets are only filled up to 7/8
+    map.capacity() * 8 / 7 * (size_of::<(K, V)>() + 1)
+}
+
+/// Keeps every transaction in a hash map.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    transactions: HashMap<TransactionID, Transaction>,
+}
+
+impl TransactionStore for MemoryStore {
+    fn get(&self, transaction_id: TransactionID) -> Option<Transaction> {
+        self.transactions.get(&transaction_id).copied()
+    }
+
+    fn put(&mut self, transaction_id: TransactionID, transaction: Transaction) {
+        self.transactions.insert(transaction_id, transaction);
+    }
+
+    fn remove(&mut self, transaction_id: TransactionID) {
+        self.transactions.remove(&transaction_id);
+    }
+
+    fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    fn heap_bytes(&self) -> usize {
+        hash_map_bytes(&self.transactions)
+    }
+
+    fn entries(&self) -> Box<dyn Iterator<Item = (TransactionID, Transaction)> + '_> {
+        Box::new(self.transactions.iter().map(|(&transaction_id, &transaction)| (transaction_id, transaction)))
+    }
+
+    fn into_entries(self: Box<Self>) -> Box<dyn Iterator<Item = (TransactionID, Transaction)>> {
+        Box::new(self.transactions.into_iter())
+    }
+}
+
+/// Keeps most transactions packed in a single word next to their id.
+///
+/// Deposits and withdrawals of the default asset that are untouched, fully disputed or fully
+/// charged back are stored packed (see [`Transaction::pack`]), which takes 17 to 35 bytes per
+/// transaction depending on how full the hash table is, against 57 to 114 bytes for the
+/// [`MemoryStore`]. Transfers, other assets and partial disputes are kept whole on the side.
+#[derive(Debug, Default)]
+pub struct CompactStore {
+    packed: HashMap<TransactionID, u64>,
+    others: HashMap<TransactionID, Transaction>,
+}
+
+impl TransactionStore for CompactStore {
+    fn get(&self, transaction_id: TransactionID) -> Option<Transaction> {
+        match self.packed.get(&transaction_id) {
+            Some(&word) => Some(Transaction::unpack(word)),
+            None => self.others.get(&transaction_id).copied(),
+        }
+    }
+
+    fn put(&mut self, transaction_id: TransactionID, transaction: Transaction) {
+        match transaction.pack() {
+            Some(word) => {
+                self.packed.insert(transaction_id, word);
+                self.others.remove(&transaction_id);
+            },
+            None => {
+                self.others.insert(transaction_id, transaction);
+                self.packed.remove(&transaction_id);
+            },
+        }
+    }
+
+    fn remove(&mut self, transaction_id: TransactionID) {
+        self.packed.remove(&transaction_id);
+        self.others.remove(&transaction_id);
+    }
+
+    fn len(&self) -> usize {
+        self.packed.len() + self.others.len()
+    }
+
+    fn heap_bytes(&self) -> usize {
+        hash_map_bytes(&self.packed) + hash_map_bytes(&self.others)
+    }
+
+    fn entries(&self) -> Box<dyn Iterator<Item = (TransactionID, Transaction)> + '_> {
+        let packed = self.packed.iter().map(|(&transaction_id, &word)| (transaction_id, Transaction::unpack(word)));
+        Box::new(packed.chain(self.others.iter().map(|(&transaction_id, &transaction)| (transaction_id, transaction))))
+    }
+
+    fn into_entries(self: Box<Self>) -> Box<dyn Iterator<Item = (TransactionID, Transaction)>> {
+        let packed = self.packed.into_iter().map(|(transaction_id, word)| (transaction_id, Transaction::unpack(word)));
+        Box::new(packed.chain(self.others))
+    }
+}
+
+/// Number of pages a [`DiskStore`] keeps in memory unless told otherwise.
+pub const DEFAULT_CACHE_PAGES: usize = 1024;
+
+const RECORD_LEN: usize = 4 + Transaction::ENCODED_LEN;
+const RECORDS_PER_PAGE: u64 = 100;
+const PAGE_LEN: usize = RECORDS_PER_PAGE as usize * RECORD_LEN;
+
+/// Least recently used pages of a file that does not change once written, such as a [`DiskStore`].
+#[derive(Debug)]
+pub(crate) struct PageCache {
+    capacity: usize,
+    /// Content and last use of each cached page.
+    pages: HashMap<u64, (Box<[u8]>, u64)>,
+    /// Cached pages by last use.
+    recency: BTreeMap<u64, u64>,
+    clock: u64,
+}
+
+impl PageCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        PageCache {
+            capacity: capacity.max(1),
+            pages: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// The content of `page`, loading it and evicting the least recently used page if it is not cached.
+    pub(crate) fn get(&mut self, page: u64, load: impl FnOnce() -> Box<[u8]>) -> &[u8] {
+        match self.try_get(page, || Ok::<_, ()>(load())) {
+            Ok(content) => content,
+            Err(()) => unreachable!("the page was loaded"),
+        }
+    }
+
+    /// Like [`Self::get`], with a `load` that may fail, in which case nothing is cached.
+    pub(crate) fn try_get<E>(&mut self, page: u64, load: impl FnOnce() -> Result<Box<[u8]>, E>) -> Result<&[u8], E> {
+        self.clock += 1;
+        match self.pages.get_mut(&page) {
+            Some((_, used)) => {
+                self.recency.remove(used);
+                *used = self.clock;
+            },
+            None => {
+                let content = load()?;
+                if self.pages.len() >= self.capacity {
+                    let (_, evicted) = self.recency.pop_first().expect("a full cache has pages");
+                    self.pages.remove(&evicted);
+                }
+                self.pages.insert(page, (content, self.clock));
+            },
+        }
+        self.recency.insert(self.clock, page);
+        Ok(&self.pages[&page].0)
+    }
+
+    /// Number of cached pages.
+    pub(crate) fn len(&self) -> usize {
+        self.pages.len()
+    }
+}
+
+/// Keeps transactions in an append-only file, with an in-memory index and a bounded page cache.
+///
+/// Every version of a transaction is appended as a fixed-size record, and the index points to
+/// the latest one. Records are written a page at a time; written pages never change, so cached
+/// pages never go stale. Memory use is the index, a few bytes per transaction, plus the cache.
+///
+/// The file only lives as long as the store. I/O errors are fatal: the engine state would
+/// no longer match its stored transactions, so the store panics.
+#[derive(Debug)]
+pub struct DiskStore {
+    file: RefCell<File>,
+    /// Record number of the latest version of each transaction.
+    index: HashMap<TransactionID, u64>,
+    records: u64,
+    /// Records of the last page, written to the file once the page is full.
+    tail: Vec<u8>,
+    cache: RefCell<PageCache>,
+}
+
+impl DiskStore {
+    /// Creates the store file at `path`, replacing any existing file, caching up to `cache_pages` pages.
+    pub fn create(path: impl AsRef<Path>, cache_pages: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        Ok(DiskStore {
+            file: RefCell::new(file),
+            index: HashMap::new(),
+            records: 0,
+            tail: Vec::with_capacity(PAGE_LEN),
+            cache: RefCell::new(PageCache::new(cache_pages)),
+        })
+    }
+
+    fn read_page(&self, page: u64) -> Box<[u8]> {
+        let mut content = vec![0; PAGE_LEN].into_boxed_slice();
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(page * PAGE_LEN as u64))
+            .and_then(|_| file.read_exact(&mut content))
+            .expect("failed to read from the transaction store");
+        content
+    }
+
+    fn read(&self, record: u64) -> Transaction {
+        let page = record / RECORDS_PER_PAGE;
+        let offset = (record % RECORDS_PER_PAGE) as usize * RECORD_LEN;
+        let mut bytes = [0; Transaction::ENCODED_LEN];
+        if page == self.records / RECORDS_PER_PAGE {
+            bytes.copy_from_slice(&self.tail[offset + 4..offset + RECORD_LEN]);
+        } else {
+            let mut cache = self.cache.borrow_mut();
+            let content = cache.get(page, || self.read_page(page));
+            bytes.copy_from_slice(&content[offset + 4..offset + RECORD_LEN]);
+        }
+        Transaction::decode(&bytes).expect("the transaction store file is corrupt")
+    }
+}
+
+impl TransactionStore for DiskStore {
+    fn get(&self, transaction_id: TransactionID) -> Option<Transaction> {
+        self.index.get(&transaction_id).map(|&record| self.read(record))
+    }
+
+    fn put(&mut self, transaction_id: TransactionID, transaction: Transaction) {
+        self.tail.extend_from_slice(&transaction_id.to_le_bytes());
+        self.tail.extend_from_slice(&transaction.encode());
+        self.index.insert(transaction_id, self.records);
+        self.records += 1;
+        if self.tail.len() == PAGE_LEN {
+            let page = self.records / RECORDS_PER_PAGE - 1;
+            let file = self.file.get_mut();
+            file.seek(SeekFrom::Start(page * PAGE_LEN as u64))
+                .and_then(|_| file.write_all(&self.tail))
+                .expect("failed to write to the transaction store");
+            self.tail.clear();
+        }
+    }
+
+    /// Only forgets the record, the file keeps growing.
+    fn remove(&mut self, transaction_id: TransactionID) {
+        self.index.remove(&transaction_id);
+    }
+
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    fn heap_bytes(&self) -> usize {
+        hash_map_bytes(&self.index) + self.tail.capacity() + self.cache.borrow().len() * PAGE_LEN
+    }
+
+    fn entries(&self) -> Box<dyn Iterator<Item = (TransactionID, Transaction)> + '_> {
+        Box::new(self.index.iter().map(|(&transaction_id, &record)| (transaction_id, self.read(record))))
+    }
+
+    fn into_entries(self: Box<Self>) -> Box<dyn Iterator<Item = (TransactionID, Transaction)>> {
+        let mut store = *self;
+        let index = std::mem::take(&mut store.index);
+        Box::new(index.into_iter().map(move |(transaction_id, record)| (transaction_id, store.read(record))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv_handler::{TransactionRaw, TransactionTypeRaw};
+    use crate::test_util::{row, workload};
+    use crate::transaction_engine::{EngineConfig, TransactionEngine};
+
+    #[test]
+    fn test_disk_store_matches_memory_store() {
+        let directory = tempfile::tempdir().unwrap();
+        let store = DiskStore::create(directory.path().join("transactions.dat"), 2).unwrap();
+        let mut on_disk = TransactionEngine::with_store(EngineConfig::default(), Box::new(store));
+        let mut in_memory = TransactionEngine::default();
+        // Many pages, with disputes and chargebacks on early and recent transactions
+        assert_eq!(on_disk.load_transactions(workload(3000).into_iter()), in_memory.load_transactions(workload(3000).into_iter()));
+
+        for tx in 1..=3000 {
+            assert_eq!(on_disk.transaction(tx), in_memory.transaction(tx));
+        }
+        assert!(on_disk.transaction(3001).is_none());
+    }
+
+    #[test]
+    fn test_compact_store_matches_memory_store() {
+        let mut rows = workload(1000);
+        // Partial disputes, other assets and amounts too large to pack are kept whole
+        rows.push(row(TransactionTypeRaw::Dispute, 1, 1, Some("2.5")));
+        rows.push(TransactionRaw { currency: Some("EUR".parse().unwrap()), ..row(TransactionTypeRaw::Deposit, 3, 2000, Some("10.0")) });
+        rows.push(row(TransactionTypeRaw::Deposit, 3, 2001, Some("4000000000.0")));
+        rows.push(row(TransactionTypeRaw::Withdrawal, 3, 2002, Some("3999999999.0")));
+
+        let mut compact = TransactionEngine::with_store(EngineConfig::default(), Box::new(CompactStore::default()));
+        let mut in_memory = TransactionEngine::default();
+        let report = compact.load_transactions(rows.clone().into_iter());
+        assert_eq!(report, in_memory.load_transactions(rows.into_iter()));
+        for tx in 1..=2002 {
+            assert_eq!(compact.transaction(tx), in_memory.transaction(tx));
+        }
+
+        // Deposits of the default asset are packed
+        let deposits = || workload(1000).into_iter()
+            .filter(|row| matches!(row.transaction_type, TransactionTypeRaw::Deposit) && row.currency.is_none());
+        let mut compact = TransactionEngine::with_store(EngineConfig::default(), Box::new(CompactStore::default()));
+        let mut in_memory = TransactionEngine::default();
+        compact.load_transactions(deposits());
+        in_memory.load_transactions(deposits());
+        assert!(compact.transaction_store().bytes_per_transaction() < in_memory.transaction_store().bytes_per_transaction() / 2.0);
+    }
+
+    #[test]
+    fn test_page_cache_evicts_least_recently_used() {
+        let mut cache = PageCache::new(2);
+        let page = |byte: u8| vec![byte; 4].into_boxed_slice();
+        assert_eq!(cache.get(1, || page(1))[0], 1);
+        assert_eq!(cache.get(2, || page(2))[0], 2);
+        assert_eq!(cache.get(1, || unreachable!())[0], 1);
+        assert_eq!(cache.get(3, || page(3))[0], 3);
+        // Page 2 was the least recently used and had to go
+        assert!(!cache.pages.contains_key(&2));
+        assert_eq!(cache.get(1, || unreachable!())[0], 1);
+    }
+}
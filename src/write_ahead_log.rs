@@ -0,0 +1,434 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use crate::csv_handler::{TransactionRaw, TransactionTypeRaw};
+use crate::snapshot::{self, SnapshotError, SnapshotReader, SnapshotWriter};
+
+/// Version of the log format written by this build.
+pub const LOG_VERSION: u32 = 1;
+
+/// First bytes of every log.
+const MAGIC: [u8; 8] = *b"TXENGWAL";
+
+/// Size of the log header: magic, version and base position.
+const HEADER_LEN: u64 = 8 + 4 + 8;
+
+/// Records larger than this can only be the garbage of a torn write.
+const MAX_RECORD_LEN: usize = 1 << 30;
+
+const ROW: u8 = 0;
+const BATCH: u8 = 1;
+
+/// Reasons why the engine cannot be recovered.
+#[derive(Debug)]
+pub enum LogError {
+    Io(io::Error),
+    /// The snapshot to start from cannot be restored.
+    Snapshot(SnapshotError),
+    /// The file does not start like a write-ahead log.
+    NotALog,
+    /// The log was written in a format this build does not read.
+    UnsupportedVersion(u32),
+    /// A complete record of the log holds an invalid value.
+    Corrupt(&'static str),
+    /// The log does not continue the state it is replayed on: its next row is not the engine's next row.
+    Gap { expected: u64, found: u64 },
+}
+
+impl fmt::Display for LogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogError::Io(error) => write!(f, "failed to read write-ahead log: {}", error),
+            LogError::Snapshot(error) => error.fmt(f),
+            LogError::NotALog => f.write_str("not a write-ahead log"),
+            LogError::UnsupportedVersion(version) => write!(f, "unsupported write-ahead log version {} (expected {})", version, LOG_VERSION),
+            LogError::Corrupt(reason) => write!(f, "corrupt write-ahead log: {}", reason),
+            LogError::Gap { expected, found } => write!(f, "write-ahead log continues at row {} but the engine is at row {}", found, expected),
+        }
+    }
+}
+
+impl std::error::Error for LogError {}
+
+impl From<io::Error> for LogError {
+    fn from(error: io::Error) -> Self {
+        LogError::Io(error)
+    }
+}
+
+impl From<SnapshotError> for LogError {
+    fn from(error: SnapshotError) -> Self {
+        match error {
+            SnapshotError::Io(error) => LogError::Io(error),
+            SnapshotError::Corrupt(reason) => LogError::Corrupt(reason),
+            SnapshotError::NotASnapshot | SnapshotError::UnsupportedVersion(_) => unreachable!("log records have no snapshot header"),
+        }
+    }
+}
+
+/// A logged write to the engine, read back on recovery.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum LogEntry {
+    /// A row applied at `position`.
+    Row { position: u64, transaction: TransactionRaw },
+    /// A batch applied as a whole, its first row at `start`.
+    Batch { start: u64, transactions: Vec<TransactionRaw> },
+}
+
+/// Append-only journal of the rows given to a [`TransactionEngine`](crate::transaction_engine::TransactionEngine),
+/// each written before it is applied, so an engine lost in a crash can be rebuilt by replaying them.
+///
+/// The log starts after the row at its base position, usually that of the snapshot it follows.
+/// Each record carries its length and a checksum; a record cut short by a crash ends the log.
+/// Every record is synced before the engine applies its rows, unless group commit is turned on
+/// with [`WriteAheadLog::set_group_commit`]: the log then syncs itself once a group of rows was
+/// appended since the last sync, and the engine syncs it once it is done loading transactions and
+/// before it applies a batch. A crash then loses at most the rows of the last group, along with
+/// their effects, so group commit suits inputs that can be read again, such as a file.
+#[derive(Debug)]
+pub struct WriteAheadLog {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    base: u64,
+    /// Rows appended since the last sync.
+    unsynced: usize,
+    /// Rows appended before the log syncs itself.
+    group_rows: usize,
+}
+
+impl WriteAheadLog {
+    /// Creates an empty log at `path`, replacing any existing file, starting after the row at `base`.
+    pub fn create(path: impl AsRef<Path>, base: u64) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path.as_ref())?;
+        let mut log = WriteAheadLog { path: path.as_ref().to_path_buf(), writer: BufWriter::new(file), base, unsynced: 0, group_rows: 1 };
+        log.reset(base)?;
+        Ok(log)
+    }
+
+    /// Opens the log at `path`, passing every complete record to `replay` in order. A torn record
+    /// at the end is cut off, and later records are appended after the last complete one.
+    pub(crate) fn open(path: impl AsRef<Path>, mut replay: impl FnMut(LogEntry) -> Result<(), LogError>) -> Result<Self, LogError> {
+        let path = path.as_ref();
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut header = [0; HEADER_LEN as usize];
+        reader.read_exact(&mut header).map_err(|_| LogError::NotALog)?;
+        if header[..8] != MAGIC {
+            return Err(LogError::NotALog);
+        }
+        let version = u32::from_le_bytes(header[8..12].try_into().expect("four bytes"));
+        if version != LOG_VERSION {
+            return Err(LogError::UnsupportedVersion(version));
+        }
+        let base = u64::from_le_bytes(header[12..20].try_into().expect("eight bytes"));
+
+        let mut end = HEADER_LEN;
+        while let Some((len, payload)) = read_record(&mut reader)? {
+            replay(decode_entry(&payload)?)?;
+            end += len;
+        }
+        drop(reader);
+
+        let file = OpenOptions::new().append(true).open(path)?;
+        if file.metadata()?.len() > end {
+            file.set_len(end)?;
+            file.sync_all()?;
+        }
+        Ok(WriteAheadLog { path: path.to_path_buf(), writer: BufWriter::new(file), base, unsynced: 0, group_rows: 1 })
+    }
+
+    /// Commits records in groups of `rows` rows instead of syncing each one, trading the rows a
+    /// crash can lose for fewer syncs.
+    pub fn set_group_commit(&mut self, rows: usize) {
+        self.group_rows = rows.max(1);
+    }
+
+    /// Position of the row the log starts after.
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    /// Logs the row about to be applied at `position`.
+    pub(crate) fn append_row(&mut self, position: u64, transaction: &TransactionRaw) -> io::Result<()> {
+        self.append(ROW, position, std::slice::from_ref(transaction))
+    }
+
+    /// Logs a batch about to be applied, its first row at `start`.
+    pub(crate) fn append_batch(&mut self, start: u64, transactions: &[TransactionRaw]) -> io::Result<()> {
+        self.append(BATCH, start, transactions)
+    }
+
+    fn append(&mut self, kind: u8, position: u64, transactions: &[TransactionRaw]) -> io::Result<()> {
+        let mut fields = SnapshotWriter::fields(Vec::new());
+        encode_entry(&mut fields, kind, position, transactions)?;
+        let payload = fields.finish()?;
+        // The payload ends with its checksum
+        self.writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&payload)?;
+        self.unsynced += transactions.len();
+        if self.unsynced >= self.group_rows {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Makes the records logged so far durable.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Drops every record and starts the log over after the row at `base`.
+    pub(crate) fn reset(&mut self, base: u64) -> io::Result<()> {
+        self.writer.flush()?;
+        let file = self.writer.get_mut();
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        let mut header = Vec::with_capacity(HEADER_LEN as usize);
+        header.extend_from_slice(&MAGIC);
+        header.extend_from_slice(&LOG_VERSION.to_le_bytes());
+        header.extend_from_slice(&base.to_le_bytes());
+        file.write_all(&header)?;
+        file.sync_all()?;
+        self.base = base;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Closes the log and deletes its file, once its rows no longer need to be recovered.
+    pub fn remove(self) -> io::Result<()> {
+        let WriteAheadLog { path, writer, .. } = self;
+        drop(writer.into_inner().map_err(|error| error.into_error())?);
+        fs::remove_file(path)
+    }
+}
+
+/// Reads the next record, `None` at the end of the log or at a torn record.
+/// Returns the length of the record in the file with its payload.
+fn read_record(reader: &mut impl Read) -> io::Result<Option<(u64, Vec<u8>)>> {
+    let mut len = [0; 4];
+    if !read_complete(reader, &mut len)? {
+        return Ok(None);
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if !(8..=MAX_RECORD_LEN).contains(&len) {
+        return Ok(None);
+    }
+    let mut payload = vec![0; len];
+    if !read_complete(reader, &mut payload)? {
+        return Ok(None);
+    }
+    let (fields, hash) = payload.split_at(len - 8);
+    if snapshot::fnv1a(snapshot::FNV_OFFSET, fields).to_le_bytes() != hash {
+        return Ok(None);
+    }
+    payload.truncate(len - 8);
+    Ok(Some((4 + len as u64, payload)))
+}
+
+/// Fills `buffer`, false if the input ends first.
+fn read_complete(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(buffer) {
+        Ok(()) => Ok(true),
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(error) => Err(error),
+    }
+}
+
+fn encode_entry(fields: &mut SnapshotWriter<Vec<u8>>, kind: u8, position: u64, transactions: &[TransactionRaw]) -> io::Result<()> {
+    fields.u8(kind)?;
+    fields.u64(position)?;
+    if kind == BATCH {
+        fields.count(transactions.len())?;
+    }
+    for transaction in transactions {
+        fields.u8(transaction.transaction_type as u8)?;
+        fields.u16(transaction.client)?;
+        fields.u32(transaction.tx)?;
+        fields.u8(transaction.amount.is_some() as u8)?;
+        if let Some(amount) = transaction.amount {
+            fields.amount(amount)?;
+        }
+        fields.u8(transaction.reason.is_some() as u8)?;
+        if let Some(reason) = &transaction.reason {
+            fields.string(reason)?;
+        }
+        fields.u8(transaction.to.is_some() as u8)?;
+        if let Some(to) = transaction.to {
+            fields.u16(to)?;
+        }
+        fields.u8(transaction.currency.is_some() as u8)?;
+        if let Some(currency) = transaction.currency {
+            fields.currency(currency)?;
+        }
+    }
+    Ok(())
+}
+
+fn decode_entry(payload: &[u8]) -> Result<LogEntry, LogError> {
+    let mut fields = SnapshotReader::fields(payload);
+    let kind = fields.u8()?;
+    let position = fields.u64()?;
+    let entry = match kind {
+        ROW => LogEntry::Row { position, transaction: decode_row(&mut fields)? },
+        BATCH => {
+            let count = fields.count()?;
+            let transactions = (0..count).map(|_| decode_row(&mut fields)).collect::<Result<_, _>>()?;
+            LogEntry::Batch { start: position, transactions }
+        },
+        _ => return Err(LogError::Corrupt("invalid record kind")),
+    };
+    Ok(entry)
+}
+
+fn decode_row(fields: &mut SnapshotReader<&[u8]>) -> Result<TransactionRaw, LogError> {
+    use TransactionTypeRaw::*;
+    let transaction_type = match fields.u8()? {
+        0 => Deposit,
+        1 => Withdrawal,
+        2 => Transfer,
+        3 => Dispute,
+        4 => Resolve,
+        5 => Chargeback,
+        6 => Freeze,
+        7 => Unlock,
+        8 => Close,
+        _ => return Err(LogError::Corrupt("invalid transaction type")),
+    };
+    let client = fields.u16()?;
+    let tx = fields.u32()?;
+    let amount = if fields.u8()? != 0 { Some(fields.amount()?) } else { None };
+    let reason = if fields.u8()? != 0 { Some(fields.string()?) } else { None };
+    let to = if fields.u8()? != 0 { Some(fields.u16()?) } else { None };
+    let currency = if fields.u8()? != 0 { Some(fields.currency()?) } else { None };
+    Ok(TransactionRaw { transaction_type, client, tx, amount, reason, to, currency })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction_engine::{EngineConfig, TransactionEngine};
+    use crate::test_util::{row, workload};
+    use crate::transaction_store::MemoryStore;
+
+    /// The rows of the [`workload`] at positions `range`, counted from 1.
+    fn rows(range: std::ops::Range<u32>) -> Vec<TransactionRaw> {
+        workload(range.end - 1).split_off(range.start as usize - 1)
+    }
+
+    fn config() -> EngineConfig {
+        EngineConfig { dispute_window: Some(20), ..Default::default() }
+    }
+
+    fn recover(snapshot: Option<&Path>, log: &Path) -> TransactionEngine {
+        TransactionEngine::recover(config(), Box::new(MemoryStore::default()), snapshot, log).unwrap()
+    }
+
+    fn assert_same_state(recovered: &TransactionEngine, expected: &TransactionEngine) {
+        assert_eq!(recovered.clients().collect::<Vec<_>>(), expected.clients().collect::<Vec<_>>());
+        assert_eq!(recovered.position(), expected.position());
+        for tx in 0..200 {
+            assert_eq!(recovered.transaction(tx), expected.transaction(tx));
+        }
+    }
+
+    /// Drops an engine without flushing its log, as a crash would.
+    fn crash(engine: TransactionEngine) {
+        std::mem::forget(engine);
+    }
+
+    #[test]
+    fn test_recover_from_log() {
+        let directory = tempfile::tempdir().unwrap();
+        let log = directory.path().join("engine.wal");
+        let mut engine = recover(None, &log);
+        engine.load_transactions(rows(1..60).into_iter());
+        let batch = [row(TransactionTypeRaw::Deposit, 1, 100, Some("5")), row(TransactionTypeRaw::Deposit, 2, 101, Some("5"))];
+        assert!(engine.apply_batch(&batch).is_ok());
+        assert!(engine.apply_batch(&[row(TransactionTypeRaw::Deposit, 1, 102, Some("1")), row(TransactionTypeRaw::Withdrawal, 1, 103, Some("1000"))]).is_err());
+        // A row is durable once it is applied
+        let _ = engine.apply(&rows(63..64)[0]);
+        crash(engine);
+        // A record torn by the crash ends the log
+        let mut file = OpenOptions::new().append(true).open(&log).unwrap();
+        file.write_all(&[40, 0, 0, 0, ROW, 1, 2]).unwrap();
+        drop(file);
+
+        let mut expected = TransactionEngine::new(config());
+        expected.load_transactions(rows(1..60).into_iter());
+        expected.apply_batch(&batch).unwrap();
+        let _ = expected.apply(&rows(63..64)[0]);
+        let mut recovered = recover(None, &log);
+        assert_same_state(&recovered, &expected);
+
+        // The torn record was cut off and the log carries on after the last complete one
+        recovered.load_transactions(rows(64..80).into_iter());
+        expected.load_transactions(rows(64..80).into_iter());
+        drop(recovered);
+        assert_same_state(&recover(None, &log), &expected);
+    }
+
+    #[test]
+    fn test_recover_from_checkpoint() {
+        let directory = tempfile::tempdir().unwrap();
+        let (snapshot, log) = (directory.path().join("engine.snapshot"), directory.path().join("engine.wal"));
+        let mut engine = recover(None, &log);
+        engine.load_transactions(rows(1..50).into_iter());
+        engine.checkpoint(&snapshot).unwrap();
+        assert_eq!(engine.write_ahead_log().unwrap().base(), 49);
+        engine.load_transactions(rows(50..90).into_iter());
+        crash(engine);
+
+        let mut expected = TransactionEngine::new(config());
+        expected.load_transactions(rows(1..90).into_iter());
+        assert_same_state(&recover(Some(&snapshot), &log), &expected);
+
+        // A log that starts after the snapshot cannot be replayed on it
+        let mut engine = recover(Some(&snapshot), &log);
+        engine.load_transactions(rows(90..100).into_iter());
+        engine.checkpoint(directory.path().join("later.snapshot")).unwrap();
+        drop(engine);
+        let error = TransactionEngine::recover(config(), Box::new(MemoryStore::default()), Some(&snapshot), &log).unwrap_err();
+        assert!(matches!(error, LogError::Gap { expected: 50, found: 100 }));
+    }
+
+    #[test]
+    fn test_group_commit() {
+        let directory = tempfile::tempdir().unwrap();
+        let log = directory.path().join("engine.wal");
+        let mut engine = recover(None, &log);
+        engine.write_ahead_log_mut().unwrap().set_group_commit(100);
+        // Rows applied one at a time are only synced once a group is full
+        for transaction in rows(1..260) {
+            let _ = engine.apply(&transaction);
+        }
+        crash(engine);
+        let mut engine = recover(None, &log);
+        assert_eq!(engine.position(), 200);
+
+        // A batch is synced before it is applied
+        engine.write_ahead_log_mut().unwrap().set_group_commit(100);
+        let _ = engine.apply(&rows(201..202)[0]);
+        engine.apply_batch(&[row(TransactionTypeRaw::Deposit, 1, 300, Some("5"))]).unwrap();
+        crash(engine);
+        assert_eq!(recover(None, &log).position(), 202);
+    }
+
+    #[test]
+    fn test_entries_round_trip() {
+        let mut transactions = rows(1..8);
+        transactions[3].reason = Some("kyc review".to_string());
+        for (kind, transactions) in [(ROW, &transactions[..1]), (BATCH, &transactions[..])] {
+            let mut fields = SnapshotWriter::fields(Vec::new());
+            encode_entry(&mut fields, kind, 42, transactions).unwrap();
+            let entry = decode_entry(&fields.finish().unwrap()).unwrap();
+            let expected = match kind {
+                ROW => LogEntry::Row { position: 42, transaction: transactions[0].clone() },
+                _ => LogEntry::Batch { start: 42, transactions: transactions.to_vec() },
+            };
+            assert_eq!(entry, expected);
+        }
+    }
+}
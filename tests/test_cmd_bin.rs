@@ -1,6 +1,10 @@
 
+use std::path::Path;
 use std::process::Command;
 use std::io::Write;
+use transaction_engine::checkpoint::{self, InputPosition};
+use transaction_engine::csv_handler::{self, TransactionRaw};
+use transaction_engine::transaction_engine::{EngineConfig, TransactionEngine};
 
 const EXPECTED_OUTPUT: &str = r"
 client, available, held, total, locked
@@ -35,8 +39,8 @@ fn normalize_csv(content: &str) -> Vec<String> {
         .collect()
 }
 
-#[test]
-fn test_transaction_engine_binary() {
+/// Runs the binary with `args` on `input` and returns its standard output.
+fn run_binary(args: &[&str], input: &str) -> String {
     // Get the path to the binary using the CARGO_BIN_EXE environment variable
     let bin_path = env!("CARGO_BIN_EXE_transaction_engine");
     
@@ -44,13 +48,14 @@ fn test_transaction_engine_binary() {
     let mut temp_file = tempfile::NamedTempFile::new()
         .expect("Failed to create temporary file");
     
-    temp_file.write_all(INPUT.as_bytes())
+    temp_file.write_all(input.as_bytes())
         .expect("Failed to write to temporary file");
     
     let input_path = temp_file.path();
     
     // Run the binary with the test input file
     let output = Command::new(bin_path)
+        .args(args)
         .arg(input_path)
         .output()
         .expect("Failed to execute binary");
@@ -59,12 +64,17 @@ fn test_transaction_engine_binary() {
         "Binary failed with stderr: {}", 
         String::from_utf8_lossy(&output.stderr));
     
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+/// Runs the binary with `args` on `input` and compares its output with `expected`, ignoring row order.
+fn assert_binary_output(args: &[&str], input: &str, expected: &str) {
     // Get the actual output
-    let actual_output = String::from_utf8_lossy(&output.stdout);
+    let actual_output = run_binary(args, input);
     
     // Normalize both outputs (trim whitespace, normalize line endings)
     let mut actual_lines = normalize_csv(&actual_output);
-    let mut expected_lines = normalize_csv(EXPECTED_OUTPUT);
+    let mut expected_lines = normalize_csv(expected);
 
     actual_lines.sort();
     expected_lines.sort();
@@ -74,7 +84,7 @@ fn test_transaction_engine_binary() {
         actual_lines.len(),
         expected_lines.len(),
         "Output has different number of lines.\nExpected:\n{}\n\nActual:\n{}",
-        EXPECTED_OUTPUT,
+        expected,
         actual_output
     );
     
@@ -88,6 +98,491 @@ fn test_transaction_engine_binary() {
             actual
         );
     }
-    
+}
+
+#[test]
+fn test_transaction_engine_binary() {
+    assert_binary_output(&[], INPUT, EXPECTED_OUTPUT);
     println!("Test passed! Output matches expected output.");
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_fixed_point_precision() {
+    // With binary floating point 0.3 - 0.1 < 0.2, which used to reject the last withdrawal
+    let input = r"
+type, client, tx, amount
+deposit, 1, 1, 0.3
+withdrawal, 1, 2, 0.1
+withdrawal, 1, 3, 0.2
+deposit, 2, 4, 0.1234
+deposit, 2, 5, 0.0001
+";
+    let expected = r"
+client, available, held, total, locked
+1, 0.0000, 0.0000, 0.0000, false
+2, 0.1235, 0.0000, 0.1235, false
+";
+    assert_binary_output(&[], input, expected);
+}
+
+#[test]
+fn test_duplicate_transaction_ids() {
+    let input = r"
+type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 1, 1, 10.0
+deposit, 2, 1, 5.0
+deposit, 2, 2, 5.0
+";
+    let strict = r"
+client, available, held, total, locked
+1, 10.0000, 0.0000, 10.0000, false
+2, 5.0000, 0.0000, 5.0000, false
+";
+    assert_binary_output(&[], input, strict);
+    assert_binary_output(&["--idempotent-replays"], input, strict);
+}
+
+#[test]
+fn test_dispute_withdrawals() {
+    let input = r"
+type, client, tx, amount
+deposit, 1, 1, 100.0
+withdrawal, 1, 2, 60.0
+dispute, 1, 2,
+deposit, 2, 3, 10.0
+withdrawal, 2, 4, 10.0
+dispute, 2, 4,
+chargeback, 2, 4,
+";
+    assert_binary_output(&[], input, r"
+client, available, held, total, locked
+1, 40.0000, 0.0000, 40.0000, false
+2, 0.0000, 0.0000, 0.0000, false
+");
+    assert_binary_output(&["--dispute-withdrawals"], input, r"
+client, available, held, total, locked
+1, 40.0000, 60.0000, 100.0000, false
+2, 10.0000, 0.0000, 10.0000, true
+");
+}
+
+#[test]
+fn test_dispute_window() {
+    let input = r"
+type, client, tx, amount
+deposit, 1, 1, 100.0
+deposit, 2, 2, 50.0
+dispute, 2, 2,
+deposit, 1, 3, 10.0
+deposit, 1, 4, 10.0
+dispute, 1, 1,
+chargeback, 2, 2,
+";
+    assert_binary_output(&[], input, r"
+client, available, held, total, locked
+1, 20.0000, 100.0000, 120.0000, false
+2, 0.0000, 0.0000, 0.0000, true
+");
+    // Transaction 1 has expired, transaction 2 is kept for its open dispute
+    assert_binary_output(&["--dispute-window", "5"], input, r"
+client, available, held, total, locked
+1, 120.0000, 0.0000, 120.0000, false
+2, 0.0000, 0.0000, 0.0000, true
+");
+}
+
+#[test]
+fn test_allow_negative_balance() {
+    let input = r"
+type, client, tx, amount
+deposit, 1, 1, 100.0
+withdrawal, 1, 2, 80.0
+dispute, 1, 1,
+chargeback, 1, 1,
+";
+    assert_binary_output(&[], input, r"
+client, available, held, total, locked
+1, 20.0000, 0.0000, 20.0000, false
+");
+    assert_binary_output(&["--allow-negative-balance"], input, r"
+client, available, held, total, locked
+1, -80.0000, 0.0000, -80.0000, true
+");
+}
+
+#[test]
+fn test_administrative_transactions() {
+    let input = r"
+type, client, tx, amount, reason
+deposit, 1, 1, 100.0,
+dispute, 1, 1,
+chargeback, 1, 1,
+deposit, 1, 2, 5.0
+unlock, 1, 3, , chargeback reviewed by support
+deposit, 1, 4, 5.0
+deposit, 2, 5, 10.0
+freeze, 2, 6, , suspicious activity
+withdrawal, 2, 7, 10.0
+close, 3, 8,
+close, 4, 9, , customer request
+deposit, 4, 10, 10.0
+";
+    assert_binary_output(&[], input, r"
+client, available, held, total, locked
+1, 5.0000, 0.0000, 5.0000, false
+2, 10.0000, 0.0000, 10.0000, true
+3, 0.0000, 0.0000, 0.0000, false
+4, 0.0000, 0.0000, 0.0000, true
+");
+}
+
+#[test]
+fn test_partial_disputes() {
+    let input = r"
+type, client, tx, amount
+deposit, 1, 1, 100.0
+dispute, 1, 1, 40.0
+dispute, 1, 1, 10.0
+resolve, 1, 1, 10.0
+deposit, 2, 2, 100.0
+dispute, 2, 2, 30.0
+chargeback, 2, 2, 20.0
+";
+    assert_binary_output(&[], input, r"
+client, available, held, total, locked
+1, 60.0000, 40.0000, 100.0000, false
+2, 70.0000, 10.0000, 80.0000, true
+");
+}
+
+#[test]
+fn test_transfers() {
+    let input = r"
+type, client, tx, amount, to
+deposit, 1, 1, 100.0,
+transfer, 1, 2, 30.0, 2
+transfer, 1, 3, 80.0, 3
+transfer, 2, 4, 10.0, 3
+dispute, 2, 4,
+chargeback, 2, 4,
+transfer, 1, 5, 10.0, 3
+";
+    assert_binary_output(&[], input, r"
+client, available, held, total, locked
+1, 70.0000, 0.0000, 70.0000, false
+2, 30.0000, 0.0000, 30.0000, false
+3, 0.0000, 0.0000, 0.0000, true
+");
+}
+
+#[test]
+fn test_multi_asset() {
+    let input = r"
+type, client, tx, amount, currency
+deposit, 1, 1, 100.0,
+deposit, 1, 2, 2.5, btc
+withdrawal, 1, 3, 3.0, BTC
+deposit, 2, 4, 10.0, EUR
+dispute, 2, 4,
+chargeback, 2, 4,
+deposit, 2, 5, 7.0,
+";
+    assert_binary_output(&[], input, r"
+client, currency, available, held, total, locked
+1, , 100.0000, 0.0000, 100.0000, false
+1, BTC, 2.5000, 0.0000, 2.5000, false
+2, EUR, 0.0000, 0.0000, 0.0000, true
+2, , 7.0000, 0.0000, 7.0000, false
+");
+}
+
+#[test]
+fn test_fee_schedule() {
+    let mut fees_file = tempfile::NamedTempFile::new().expect("Failed to create temporary file");
+    fees_file.write_all(b"type, flat, percent\nwithdrawal, 0.5, 1\nchargeback, 15,\n")
+        .expect("Failed to write to temporary file");
+    let fees_path = fees_file.path().to_str().unwrap();
+
+    let input = r"
+type, client, tx, amount
+deposit, 1, 1, 100.0
+withdrawal, 1, 2, 50.0
+deposit, 2, 3, 30.0
+deposit, 2, 5, 10.0
+dispute, 2, 3,
+chargeback, 2, 3,
+deposit, 9, 4, 10.0
+";
+    assert_binary_output(&["--fees", fees_path, "--house-account", "9"], input, r"
+client, available, held, total, locked, fees
+1, 49.0000, 0.0000, 49.0000, false, 1.0000
+2, 0.0000, 0.0000, 0.0000, true, 10.0000
+9, 11.0000, 0.0000, 11.0000, false, 0.0000
+");
+    assert_binary_output(&["--fees", fees_path, "--house-account", "9", "--allow-negative-balance"], input, r"
+client, available, held, total, locked, fees
+1, 49.0000, 0.0000, 49.0000, false, 1.0000
+2, -5.0000, 0.0000, -5.0000, true, 15.0000
+9, 16.0000, 0.0000, 16.0000, false, 0.0000
+");
+}
+
+/// A few thousand rows over a dozen clients mixing every kind of transaction.
+fn generated_input() -> String {
+    let mut input = String::from("type, client, tx, amount, reason, to\n");
+    for tx in 1..=2000u32 {
+        let client = tx * 7 % 13;
+        let row = match tx % 9 {
+            0 => format!("dispute, {}, {}, ,", client, tx / 2),
+            1 => format!("chargeback, {}, {}, ,", client, (tx - 1) / 2),
+            2 | 3 => format!("withdrawal, {}, {}, {}.5", client, tx, tx % 50),
+            4 => format!("transfer, {}, {}, {}, , {}", client, tx, tx % 30, (client + tx) % 13),
+            5 => format!("unlock, {}, {}, , reviewed", client, tx),
+            _ => format!("deposit, {}, {}, {}.25", client, tx, tx % 100),
+        };
+        input.push_str(&row);
+        input.push('\n');
+    }
+    input
+}
+
+/// The rows of `input` split in two halves, each with the header.
+fn halves(input: &str) -> [String; 2] {
+    let (header, rows) = input.split_once('\n').unwrap();
+    let lines: Vec<&str> = rows.lines().collect();
+    let (first, second) = lines.split_at(lines.len() / 2);
+    [first, second].map(|rows| format!("{}\n{}\n", header, rows.join("\n")))
+}
+
+/// Runs the binary with `first` on the first half of `input`, then with `second` on the second
+/// half, and returns the output of both runs.
+fn run_in_two_halves(first: &[&str], second: &[&str], input: &str) -> [String; 2] {
+    let [today, tomorrow] = halves(input);
+    [run_binary(first, &today), run_binary(second, &tomorrow)]
+}
+
+/// Applies the first rows of `input` to `engine` the way a run that gets interrupted would: the
+/// first `checkpointed` rows are saved to `engine.checkpoint` in `directory`, trimming the
+/// write-ahead log, and the next `logged` ones are applied after that. The input is read from
+/// `transactions.csv` in `directory`, and the rows that were not applied are returned.
+fn interrupted_run(engine: &mut TransactionEngine, input: &str, directory: &Path, checkpointed: usize, logged: usize) -> Vec<TransactionRaw> {
+    let input_path = directory.join("transactions.csv");
+    std::fs::write(&input_path, input).unwrap();
+    let mut rows = csv_handler::resume_csv_file(std::fs::File::open(&input_path).unwrap(), None, false).unwrap();
+    let mut next = None;
+    engine.load_transactions(rows.by_ref().take(checkpointed).map(|(position, transaction)| {
+        next = Some(position);
+        transaction
+    }));
+    if let Some(next) = next {
+        let position = InputPosition { next, input_len: input.len() as u64 };
+        checkpoint::save_checkpoint(directory.join("engine.checkpoint"), engine, &position).unwrap();
+        engine.trim_write_ahead_log().unwrap();
+    }
+    engine.load_transactions(rows.by_ref().take(logged).map(|(_, transaction)| transaction));
+    rows.map(|(_, transaction)| transaction).collect()
+}
+
+#[test]
+fn test_sharded_output_is_identical() {
+    let input = generated_input();
+    let sequential = run_binary(&[], &input);
+    assert_eq!(run_binary(&["--shards", "4"], &input), sequential);
+    assert_eq!(run_binary(&["--shards", "13", "--dispute-withdrawals"], &input), run_binary(&["--dispute-withdrawals"], &input));
+}
+
+#[test]
+fn test_fast_csv_output_is_identical() {
+    let input = generated_input();
+    assert_eq!(run_binary(&["--fast-csv"], &input), run_binary(&[], &input));
+    assert_eq!(run_binary(&["--fast-csv"], INPUT), run_binary(&[], INPUT));
+    assert_eq!(run_binary(&["--parallel-csv", "4"], &input), run_binary(&[], &input));
+    assert_eq!(run_binary(&["--parallel-csv", "2"], INPUT), run_binary(&[], INPUT));
+}
+
+#[test]
+fn test_generated_workload() {
+    let directory = tempfile::tempdir().expect("Failed to create temporary directory");
+    let transactions = directory.path().join("transactions.csv");
+    let expected = directory.path().join("expected.csv");
+    let status = Command::new(env!("CARGO_BIN_EXE_generate_workload"))
+        .args(["--clients", "40", "--rows", "5000", "--mix", "45,20,15,9,1,8,1,1", "--invalid-rate", "0.02", "--amounts", "exponential", "--seed", "3"])
+        .args([&transactions, &expected])
+        .status()
+        .expect("Failed to execute generator");
+    assert!(status.success());
+    let input = std::fs::read_to_string(&transactions).unwrap();
+    let expected = std::fs::read_to_string(&expected).unwrap();
+    assert_binary_output(&[], &input, &expected);
+    assert_binary_output(&["--shards", "3", "--fast-csv"], &input, &expected);
+    assert_binary_output(&["--parallel-csv", "3"], &input, &expected);
+}
+
+#[test]
+fn test_transaction_stores() {
+    let input = generated_input();
+    let directory = tempfile::tempdir().expect("Failed to create temporary directory");
+    let store = directory.path().to_str().unwrap();
+    let in_memory = run_binary(&[], &input);
+    assert_eq!(run_binary(&["--transaction-store", store], &input), in_memory);
+    assert_eq!(run_binary(&["--transaction-store", store, "--shards", "3"], &input), in_memory);
+    assert_eq!(run_binary(&["--compact-transactions"], &input), in_memory);
+}
+
+#[test]
+fn test_snapshot_and_restore() {
+    let input = generated_input();
+    let directory = tempfile::tempdir().expect("Failed to create temporary directory");
+    let snapshot = directory.path().join("engine.snapshot");
+    let snapshot = snapshot.to_str().unwrap();
+    for options in [&[][..], &["--dispute-window", "300"][..]] {
+        let first = [options, &["--snapshot", snapshot]].concat();
+        let second = [options, &["--restore", snapshot, "--compact-transactions"]].concat();
+        let [_, resumed] = run_in_two_halves(&first, &second, &input);
+        assert_eq!(resumed, run_binary(options, &input));
+    }
+}
+
+#[test]
+fn test_recovers_from_write_ahead_log() {
+    use transaction_engine::transaction_store::MemoryStore;
+
+    let input = generated_input();
+    let uninterrupted = run_binary(&[], &input);
+    let directory = tempfile::tempdir().expect("Failed to create temporary directory");
+    let log = directory.path().join("engine.wal");
+
+    for options in [&[][..], &["--group-commit", "64"][..]] {
+        // A run that crashed after logging part of the input
+        let mut engine = TransactionEngine::recover(EngineConfig::default(), Box::new(MemoryStore::default()), None, &log).unwrap();
+        interrupted_run(&mut engine, &input, directory.path(), 0, 700);
+        std::mem::forget(engine);
+
+        let recovered = run_binary(&[options, &["--write-ahead-log", log.to_str().unwrap()]].concat(), &input);
+        assert_eq!(recovered, uninterrupted);
+        assert!(!log.exists());
+    }
+}
+
+#[test]
+fn test_resumes_from_checkpoint() {
+    let input = generated_input();
+    let uninterrupted = run_binary(&[], &input);
+    let directory = tempfile::tempdir().expect("Failed to create temporary directory");
+    let checkpoint = directory.path().join("engine.checkpoint");
+    let path = checkpoint.to_str().unwrap();
+    assert_eq!(run_binary(&["--checkpoint", path, "--checkpoint-every", "300"], &input), uninterrupted);
+    assert!(!checkpoint.exists());
+
+    for options in [&[][..], &["--fast-csv"][..]] {
+        // A run that stopped after checkpointing part of the input
+        interrupted_run(&mut TransactionEngine::new(EngineConfig::default()), &input, directory.path(), 1234, 0);
+
+        let resumed = run_binary(&[options, &["--checkpoint", path, "--resume", "--checkpoint-every", "500"]].concat(), &input);
+        assert_eq!(resumed, uninterrupted);
+        assert!(!checkpoint.exists());
+    }
+}
+
+#[test]
+fn test_checkpoints_with_write_ahead_log() {
+    let input = generated_input();
+    let uninterrupted = run_binary(&[], &input);
+    let directory = tempfile::tempdir().expect("Failed to create temporary directory");
+    let (checkpoint, log) = (directory.path().join("engine.checkpoint"), directory.path().join("engine.wal"));
+    let args = ["--checkpoint", checkpoint.to_str().unwrap(), "--write-ahead-log", log.to_str().unwrap(), "--checkpoint-every", "300"];
+    assert_eq!(run_binary(&args, &input), uninterrupted);
+    assert!(!checkpoint.exists() && !log.exists());
+
+    // A run that crashed after checkpointing 1000 rows and logging 400 more
+    let mut engine = TransactionEngine::new(EngineConfig::default());
+    engine.attach_write_ahead_log(&log).unwrap();
+    interrupted_run(&mut engine, &input, directory.path(), 1000, 400);
+    std::mem::forget(engine);
+
+    assert_eq!(run_binary(&[&args[..], &["--resume"]].concat(), &input), uninterrupted);
+    assert!(!checkpoint.exists() && !log.exists());
+}
+
+#[test]
+fn test_event_log() {
+    let directory = tempfile::tempdir().expect("Failed to create temporary directory");
+    let csv_log = directory.path().join("events.csv");
+    let json_log = directory.path().join("events.jsonl");
+    assert_binary_output(&["--event-log", csv_log.to_str().unwrap()], INPUT, EXPECTED_OUTPUT);
+    assert_eq!(std::fs::read_to_string(&csv_log).unwrap(), "event,row,client,currency,tx,type,amount,reason
+funds_deposited,1,1,,1,deposit,100.0000,
+funds_deposited,2,2,,2,deposit,200.0000,
+funds_deposited,3,1,,3,deposit,50.0000,
+funds_withdrawn,4,1,,4,withdrawal,25.0000,
+funds_held,5,1,,1,dispute,100.0000,
+funds_released,6,1,,1,resolve,100.0000,
+funds_deposited,7,3,,5,deposit,75.0000,
+transaction_rejected,8,3,,100,dispute,,referenced transaction not found
+transaction_rejected,9,3,,100,resolve,,referenced transaction not found
+transaction_rejected,10,3,,100,chargeback,,referenced transaction not found
+funds_held,11,1,,3,dispute,50.0000,
+charged_back,12,1,,3,chargeback,50.0000,
+account_locked,12,1,,3,chargeback,,
+");
+
+    let input = generated_input();
+    run_binary(&["--event-log", json_log.to_str().unwrap()], &input);
+    run_binary(&["--event-log", csv_log.to_str().unwrap(), "--fast-csv"], &input);
+    let json_lines = std::fs::read_to_string(&json_log).unwrap();
+    assert!(json_lines.lines().all(|line| line.starts_with(r#"{"event":""#) && line.ends_with('}')));
+    assert_eq!(json_lines.lines().count(), std::fs::read_to_string(&csv_log).unwrap().lines().count() - 1);
+}
+
+#[test]
+fn test_event_log_resumes() {
+    use transaction_engine::events::{EventFormat, EventLogWriter};
+
+    let input = generated_input();
+    let directory = tempfile::tempdir().expect("Failed to create temporary directory");
+    let (checkpoint, log) = (directory.path().join("engine.checkpoint"), directory.path().join("engine.wal"));
+    for name in ["events.csv", "events.jsonl"] {
+        let events = directory.path().join(name);
+        run_binary(&["--event-log", events.to_str().unwrap()], &input);
+        let uninterrupted = std::fs::read_to_string(&events).unwrap();
+
+        for checkpointed in [0, 1000] {
+            // A run that crashed after checkpointing some rows, logging 700 more and writing the
+            // events of part of the next ones
+            let mut engine = TransactionEngine::new(EngineConfig::default());
+            engine.set_event_sink(Box::new(EventLogWriter::create(&events, EventFormat::from_path(&events)).unwrap()));
+            engine.attach_write_ahead_log(&log).unwrap();
+            for transaction in interrupted_run(&mut engine, &input, directory.path(), checkpointed, 700).into_iter().take(40) {
+                let _ = engine.apply(&transaction);
+            }
+            let mut torn = engine.take_event_sink().unwrap();
+            torn.flush();
+            std::mem::forget(torn);
+            std::mem::forget(engine);
+            let mut file = std::fs::OpenOptions::new().append(true).open(&events).unwrap();
+            file.write_all(b"funds_dep").unwrap();
+            drop(file);
+
+            let mut args = vec!["--event-log", events.to_str().unwrap(), "--write-ahead-log", log.to_str().unwrap()];
+            if checkpointed > 0 {
+                args.extend(["--checkpoint", checkpoint.to_str().unwrap(), "--resume"]);
+            }
+            run_binary(&args, &input);
+            assert_eq!(std::fs::read_to_string(&events).unwrap(), uninterrupted, "{} resumed after {} rows", name, checkpointed);
+        }
+    }
+}
+
+#[test]
+fn test_persistent_storage() {
+    let input = generated_input();
+    let directory = tempfile::tempdir().expect("Failed to create temporary directory");
+    for (index, options) in [&[][..], &["--dispute-window", "300"][..]].into_iter().enumerate() {
+        let storage = directory.path().join(format!("engine-{}.kv", index));
+        let args = [options, &["--storage", storage.to_str().unwrap()]].concat();
+        let [today, resumed] = run_in_two_halves(&args, &args, &input);
+        assert_eq!(today, run_binary(options, &halves(&input)[0]));
+        assert_eq!(resumed, run_binary(options, &input));
+    }
+}